@@ -0,0 +1,254 @@
+//! Moving the backup GPT when the size of the device changes.
+
+use std::collections::BTreeMap;
+use std::fs;
+
+use crate::disk::LogicalBlockSize;
+use crate::header::{self, Header, HeaderBuilder};
+use crate::mbr::ProtectiveMBR;
+use crate::partition::{self, Partition};
+use crate::{DiskDevice, GptDisk, GptError};
+
+/// The MBR partition type protecting a GPT disk.
+const PROTECTIVE_TYPE: u8 = 0xEE;
+
+impl<D> GptDisk<D>
+where
+    D: DiskDevice,
+{
+    /// Returns the last lba of the device if the device is larger than
+    /// the partition table says, for example after an image was enlarged.
+    ///
+    /// In that case the backup header is not at the end of the device,
+    /// use `relocate_backup_to_end` to move it there. `GptDisk::verify`
+    /// reports such a disk with a `WrongBackupLba` warning.
+    pub fn disk_grown(&mut self) -> Result<Option<u64>, GptError> {
+        let end = header::find_backup_lba(&mut self.device, self.config.lb_size)?;
+        let backup_lba = backup_lba(self.header()?);
+
+        Ok(Some(end).filter(|end| *end > backup_lba))
+    }
+
+    /// Returns the last lba of the device if it was larger than the
+    /// partition table said when the disk was opened, like `disk_grown`.
+    ///
+    /// This is what `sgdisk -e` would fix, it is not updated by
+    /// `relocate_backup_to_end`.
+    pub fn grown_to(&self) -> Option<u64> {
+        self.grown_to
+    }
+
+    /// Moves the backup partition array and header to the end of the
+    /// device and extends the last usable lba accordingly (like `sgdisk -e`).
+    ///
+    /// This only changes the headers in memory, the backup structures are
+    /// written at their new location by the next `write`. The old backup
+    /// header is not cleared, it ends up in the now usable space.
+    ///
+    /// If the device shrunk this fails with `GptError::PartitionsDoNotFit`
+    /// if a partition would overlap the backup structures.
+    ///
+    /// The protective MBR is not changed, use `update_protective_mbr` to
+    /// make it cover the device again.
+    ///
+    /// Returns the new lba of the backup header.
+    pub fn relocate_backup_to_end(&mut self) -> Result<u64, GptError> {
+        let end = header::find_backup_lba(&mut self.device, self.config.lb_size)?;
+        self.move_backup_to(end)?;
+
+        Ok(end)
+    }
+
+    /// Returns the size in bytes of the smallest device which can hold
+    /// all partitions plus the backup partition array and header.
+    pub fn min_device_size(&self) -> Result<u64, GptError> {
+        let backup_lba = self.min_backup_lba()?;
+        backup_lba
+            .checked_add(1)
+            .and_then(|lbs| lbs.checked_mul(self.config.lb_size.as_u64()))
+            .ok_or(GptError::Overflow("device size"))
+    }
+
+    /// Moves the backup partition array and header directly after the
+    /// last partition and returns the size in bytes the device can be
+    /// shrunk to.
+    ///
+    /// Like `relocate_backup_to_end` this only changes the headers in
+    /// memory, the device needs to be written before it can be shrunk.
+    /// Once it was shrunk `update_protective_mbr` fits the protective MBR
+    /// to the new size. For file backed disks `shrink_file_to_fit` does
+    /// all of this.
+    pub fn shrink_to_fit(&mut self) -> Result<u64, GptError> {
+        let size = self.min_device_size()?;
+        self.move_backup_to(self.min_backup_lba()?)?;
+
+        Ok(size)
+    }
+
+    /// Resizes the protective partition of the MBR on the device to
+    /// cover the whole device, for example after `relocate_backup_to_end`.
+    ///
+    /// The MBR is written right away, its other partitions and the boot
+    /// code are kept. Fails with `GptError::Mbr` if the device has no
+    /// valid MBR.
+    pub fn update_protective_mbr(&mut self) -> Result<(), GptError> {
+        if !self.config.writable {
+            return Err(GptError::ReadOnly);
+        }
+
+        let lb_size = self.config.lb_size;
+        let last_lba = header::find_backup_lba(&mut self.device, lb_size)?;
+        let mut mbr = ProtectiveMBR::from_disk(&mut self.device, lb_size)?;
+        protect_up_to(&mut mbr, last_lba);
+        mbr.overwrite_lba0(&mut self.device)?;
+        if let Some(sync_all) = self.sync_all {
+            sync_all(&mut self.device)?;
+        }
+
+        Ok(())
+    }
+
+    fn min_backup_lba(&self) -> Result<u64, GptError> {
+        let header = self.header()?;
+        // keep at least one usable lba, a header without one is invalid
+        let last_used = self
+            .partitions
+            .values()
+            .filter(|p| p.is_used())
+            .map(|p| p.last_lba)
+            .fold(header.first_usable, u64::max);
+
+        last_used
+            .checked_add(part_array_lbs(header, self.config.lb_size) + 1)
+            .ok_or(GptError::Overflow("backup lba"))
+    }
+
+    fn move_backup_to(&mut self, new_lba: u64) -> Result<(), GptError> {
+        let lb_size = self.config.lb_size;
+        let header = self.header()?;
+        let ids = not_fitting(&self.partitions, header, new_lba, lb_size);
+        if !ids.is_empty() {
+            return Err(GptError::PartitionsDoNotFit(ids));
+        }
+        debug!(
+            "moving backup header from lba {} to {}",
+            backup_lba(header),
+            new_lba
+        );
+
+        // only the primary array stays where it is
+        let primary = HeaderBuilder::from_header(header)
+            .primary_part_start(self.primary_array_start()?)
+            .backup_part_start(0)
+            .primary(true)
+            .backup_lba(new_lba)
+            .last_usable(0)
+            .build(lb_size)?;
+        if !self.config.readonly_backup {
+            let backup = HeaderBuilder::from_header(&primary)
+                .primary(false)
+                .build(lb_size)?;
+            self.backup_header = Ok(backup);
+        }
+        self.primary_header = Ok(primary);
+
+        Ok(())
+    }
+}
+
+impl GptDisk<fs::File> {
+    /// Moves the backup structures directly after the last partition,
+    /// writes the table and truncates the file to the smallest possible
+    /// size. A valid protective MBR is resized to the new size.
+    ///
+    /// This only works for regular files, not for block devices.
+    /// Returns the new size of the file in bytes.
+    pub fn shrink_file_to_fit(&mut self) -> Result<u64, GptError> {
+        let size = self.shrink_to_fit()?;
+        self.write_inplace()?;
+        self.device.set_len(size)?;
+        self.device.sync_all()?;
+        match self.update_protective_mbr() {
+            Err(GptError::Mbr(e)) => {
+                debug!("not resizing the invalid protective MBR: {}", e);
+            }
+            res => res?,
+        }
+
+        Ok(size)
+    }
+}
+
+/// Fails if the device is smaller than the table described by `header`
+/// and not all partitions fit anymore.
+///
+/// If the partition array can't be read nothing is checked, opening the
+/// disk decides what to do with it.
+pub(crate) fn check_device_size<D: DiskDevice>(
+    device: &mut D,
+    header: &Header,
+    lb_size: LogicalBlockSize,
+) -> Result<(), GptError> {
+    let end = match header::find_backup_lba(device, lb_size) {
+        Ok(end) if end < header.backup_lba => end,
+        _ => return Ok(()),
+    };
+    debug!(
+        "device ends at lba {} before the backup header at {}",
+        end, header.backup_lba
+    );
+
+    let partitions = match partition::file_read_partitions(device, header, lb_size) {
+        Ok(partitions) => partitions,
+        Err(e) => {
+            debug!("not checking the partitions of the unreadable array: {}", e);
+            return Ok(());
+        }
+    };
+    let ids = not_fitting(&partitions, header, end, lb_size);
+    if ids.is_empty() {
+        Ok(())
+    } else {
+        Err(GptError::PartitionsDoNotFit(ids))
+    }
+}
+
+/// Makes the protective partitions of the MBR end at `last_lba`.
+pub(crate) fn protect_up_to(mbr: &mut ProtectiveMBR, last_lba: u64) {
+    for i in 0..4 {
+        if let Some(mut record) = mbr.partition(i) {
+            if record.os_type == PROTECTIVE_TYPE {
+                record.lb_size = u32::try_from(last_lba).unwrap_or(u32::MAX);
+                mbr.set_partition(i, record);
+            }
+        }
+    }
+}
+
+/// Returns the ids of all partitions which would overlap the backup
+/// structures if the backup header was at `backup_lba`.
+fn not_fitting(
+    partitions: &BTreeMap<u32, Partition>,
+    header: &Header,
+    backup_lba: u64,
+    lb_size: LogicalBlockSize,
+) -> Vec<u32> {
+    let last_usable = backup_lba.saturating_sub(part_array_lbs(header, lb_size) + 1);
+    partitions
+        .iter()
+        .filter(|(_, p)| p.is_used() && p.last_lba > last_usable)
+        .map(|(id, _)| *id)
+        .collect()
+}
+
+/// The number of lbas the partition array occupies.
+pub(crate) fn part_array_lbs(header: &Header, lb_size: LogicalBlockSize) -> u64 {
+    let lb_size = lb_size.as_u64();
+    let len = u64::from(header.num_parts) * u64::from(header.part_size);
+    len / lb_size + u64::from(len % lb_size != 0)
+}
+
+/// The lba of the backup header, regardless of which header is given.
+fn backup_lba(header: &Header) -> u64 {
+    header.current_lba.max(header.backup_lba)
+}
@@ -18,6 +18,10 @@ pub struct HeaderBuilder {
     num_parts: u32,
     /// Size of a partition entry, usually 128
     part_size: u32,
+    /// First LBA of the primary partition array (0 means directly after the header)
+    primary_part_start: u64,
+    /// First LBA of the backup partition array (0 means directly before the header)
+    backup_part_start: u64,
 }
 
 impl HeaderBuilder {
@@ -32,6 +36,8 @@ impl HeaderBuilder {
             last_usable: 0,
             num_parts: super::MIN_NUM_PARTS,
             part_size: 128,
+            primary_part_start: 0,
+            backup_part_start: 0,
         }
     }
 
@@ -45,6 +51,13 @@ impl HeaderBuilder {
             (header.backup_lba, header.current_lba)
         };
 
+        // the location of the other partition array is not known
+        let (primary_part_start, backup_part_start) = if primary {
+            (header.part_start, 0)
+        } else {
+            (0, header.part_start)
+        };
+
         Self {
             primary,
             disk_guid: header.disk_guid,
@@ -54,15 +67,11 @@ impl HeaderBuilder {
             last_usable: header.last_usable,
             num_parts: header.num_parts,
             part_size: header.part_size,
+            primary_part_start,
+            backup_part_start,
         }
     }
 
-    pub(crate) fn from_maybe_header(header: Result<&Header, HeaderError>) -> Self {
-        header
-            .map(Self::from_header)
-            .unwrap_or_else(|_| Self::new())
-    }
-
     /// Set wether this header is the primary or the backup.
     pub fn primary(&mut self, primary: bool) -> &mut Self {
         self.primary = primary;
@@ -89,26 +98,49 @@ impl HeaderBuilder {
         self
     }
 
-    /// If you don't set this it will get calculated automatically
+    /// If you don't set this (or set it to 0) it will get calculated
+    /// automatically
     ///
-    /// It might still be calculated if the value is not sufficient
+    /// It gets lowered if it would overlap the backup partition array
     pub fn last_usable(&mut self, last_usable: u64) -> &mut Self {
         self.last_usable = last_usable;
         self
     }
 
-    /// this will always set 128 >=
+    /// Set the first lba of the primary partition array
     ///
-    /// If you already have partitions make sure num parts is bigger or equal
+    /// By default (or if set to 0) the array starts directly after the
+    /// primary header. The first usable lba is moved behind the array.
+    pub fn primary_part_start(&mut self, primary_part_start: u64) -> &mut Self {
+        self.primary_part_start = primary_part_start;
+        self
+    }
+
+    /// Set the first lba of the backup partition array
+    ///
+    /// By default (or if set to 0) the array ends directly before the
+    /// backup header. The last usable lba is moved in front of the array.
+    pub fn backup_part_start(&mut self, backup_part_start: u64) -> &mut Self {
+        self.backup_part_start = backup_part_start;
+        self
+    }
+
+    /// The number of entries in the partition array, by default 128
+    ///
+    /// If you already have partitions make sure num parts is bigger or
+    /// equal to the highest partition id
     ///
     /// ## Warning
     /// This might change the first usable and last usable part
     pub fn num_parts(&mut self, num_parts: u32) -> &mut Self {
-        self.num_parts = num_parts.max(super::MIN_NUM_PARTS);
+        self.num_parts = num_parts;
         self
     }
 
-    /// This should probably be 128 but can be set lower
+    /// The size of a partition entry, 128 by default
+    ///
+    /// It needs to be 128 * 2^n bytes, larger entries can hold
+    /// additional data after the standard fields.
     ///
     /// ## Warning
     /// This might change the first usable and last usable part
@@ -125,6 +157,9 @@ impl HeaderBuilder {
         if self.backup_lba < self.primary_lba {
             return Err(HeaderError::MissingBackupLba);
         }
+        if self.part_size < 128 || !self.part_size.is_power_of_two() {
+            return Err(HeaderError::InvalidPartitionEntrySize(self.part_size));
+        }
 
         let (current_lba, backup_lba) = if self.primary {
             (self.primary_lba, self.backup_lba)
@@ -132,29 +167,54 @@ impl HeaderBuilder {
             (self.backup_lba, self.primary_lba)
         };
 
-        let part_array_size = self.num_parts * self.part_size;
-        let part_array_lbs = u64_div_ceil(part_array_size as u64, lb_size.as_u64());
-
-        let first_usable = self.first_usable.max(
-            // mbr, header, part_array
-            1 + 1 + part_array_lbs,
-        );
+        let part_array_size = u64::from(self.num_parts) * u64::from(self.part_size);
+        let part_array_lbs = u64_div_ceil(part_array_size, lb_size.as_u64());
 
-        let last_usable = self.last_usable.max(
-            // last is inclusive: end of disk is (partition array) (backup header)
-            self.backup_lba
-                .checked_sub(part_array_lbs + 1)
+        let primary_part_start = match self.primary_part_start {
+            0 => self.primary_lba + 1,
+            start => start,
+        };
+        // last is inclusive: end of disk is (partition array) (backup header)
+        let backup_part_start = match self.backup_part_start {
+            0 => self
+                .backup_lba
+                .checked_sub(part_array_lbs)
                 .ok_or(HeaderError::BackupLbaToEarly)?,
-        );
+            start => start,
+        };
+
+        let primary_part_end = primary_part_start
+            .checked_add(part_array_lbs)
+            .ok_or(HeaderError::Overflow("primary partition array end"))?;
+        let backup_part_end = backup_part_start
+            .checked_add(part_array_lbs)
+            .ok_or(HeaderError::Overflow("backup partition array end"))?;
+        if primary_part_start <= self.primary_lba
+            || backup_part_end > self.backup_lba
+            || primary_part_end > backup_part_start
+        {
+            return Err(HeaderError::InvalidPartitionArrayLocation);
+        }
+
+        // mbr, header, (reserved), part_array
+        let first_usable = self.first_usable.max(primary_part_end);
+
+        let max_last_usable = backup_part_start
+            .checked_sub(1)
+            .ok_or(HeaderError::BackupLbaToEarly)?;
+        let last_usable = match self.last_usable {
+            0 => max_last_usable,
+            last_usable => last_usable.min(max_last_usable),
+        };
 
         if first_usable > last_usable {
             return Err(HeaderError::BackupLbaToEarly);
         }
 
         let part_start = if self.primary {
-            self.primary_lba + 1
+            primary_part_start
         } else {
-            last_usable + 1
+            backup_part_start
         };
 
         Ok(Header {
@@ -14,7 +14,8 @@ use crate::disk;
 
 use simple_bytes::{BytesArray, BytesRead, BytesSeek, BytesWrite};
 
-const MIN_NUM_PARTS: u32 = 128;
+/// the number of partition entries used for new tables
+pub(crate) const MIN_NUM_PARTS: u32 = 128;
 
 #[non_exhaustive]
 #[derive(Debug)]
@@ -29,7 +30,12 @@ pub enum HeaderError {
     /// Invalid CRC32 Checksum
     ///
     /// This means the header was corrupted or not fully written.
-    InvalidCRC32Checksum,
+    InvalidCRC32Checksum {
+        /// The checksum stored in the header
+        expected: u32,
+        /// The checksum computed over the header
+        actual: u32,
+    },
     // Builder errors
     /// Get's returned when you call build on a HeaderBuilder and the backup lba field
     /// was never set
@@ -45,6 +51,11 @@ pub enum HeaderError {
     Overflow(&'static str),
     /// The Disk is to small to hold a backup header
     ToSmallForBackup,
+    /// Get's returned when you call build on a HeaderBuilder and a partition array
+    /// would overlap a header
+    InvalidPartitionArrayLocation,
+    /// The partition entry size is not 128 * 2^n
+    InvalidPartitionEntrySize(u32),
 }
 
 impl HeaderError {
@@ -52,12 +63,17 @@ impl HeaderError {
         match self {
             Self::Io(e) => Self::Io(Error::from(e.kind())),
             Self::InvalidGptSignature => Self::InvalidGptSignature,
-            Self::InvalidCRC32Checksum => Self::InvalidCRC32Checksum,
+            Self::InvalidCRC32Checksum { expected, actual } => Self::InvalidCRC32Checksum {
+                expected: *expected,
+                actual: *actual,
+            },
             Self::MissingBackupLba => Self::MissingBackupLba,
             Self::BackupLbaToEarly => Self::BackupLbaToEarly,
             Self::WritingToWrongLba => Self::WritingToWrongLba,
             Self::Overflow(m) => Self::Overflow(m),
             Self::ToSmallForBackup => Self::ToSmallForBackup,
+            Self::InvalidPartitionArrayLocation => Self::InvalidPartitionArrayLocation,
+            Self::InvalidPartitionEntrySize(size) => Self::InvalidPartitionEntrySize(*size),
         }
     }
 }
@@ -68,7 +84,14 @@ impl From<Error> for HeaderError {
     }
 }
 
-impl std::error::Error for HeaderError {}
+impl std::error::Error for HeaderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl fmt::Display for HeaderError {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -78,7 +101,13 @@ impl fmt::Display for HeaderError {
                 return write!(fmt, "Header IO Error: {e}")
             },
             InvalidGptSignature => "Invalid GPT Signature, the header does not exist or is invalid",
-            InvalidCRC32Checksum => "CRC32 Checksum Mismatch, the header is corrupted",
+            InvalidCRC32Checksum { expected, actual } => {
+                return write!(
+                    fmt,
+                    "CRC32 Checksum Mismatch, the header is corrupted \
+                    (expected {expected:#010x} got {actual:#010x})"
+                )
+            }
             MissingBackupLba => "HeaderBuilder expects the field backup_lba to be set",
             BackupLbaToEarly => {
                 "HeaderBuilder: there isn't enough space between first_lba and backup_lba"
@@ -87,7 +116,13 @@ impl fmt::Display for HeaderError {
                 "you trying to write to the wrong lba (example calling write_primary instead of write_backup)"
             },
             Overflow(m) => return write!(fmt, "Header error Overflow: {m}"),
-            ToSmallForBackup => "the disk is to small to hold a backup header"
+            ToSmallForBackup => "the disk is to small to hold a backup header",
+            InvalidPartitionArrayLocation => {
+                "HeaderBuilder: a partition array would overlap a header"
+            }
+            InvalidPartitionEntrySize(size) => {
+                return write!(fmt, "the partition entry size {size} is not 128 * 2^n bytes")
+            }
         };
         write!(fmt, "{desc}")
     }
@@ -95,6 +130,7 @@ impl fmt::Display for HeaderError {
 
 /// Header describing a GPT disk.
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Header {
     /// GPT header magic signature, hardcoded to "EFI PART".
     pub signature: String, // Offset  0. "EFI PART", 45h 46h 49h 20h 50h 41h 52h 54h
@@ -170,8 +206,25 @@ impl Header {
         lba: u64,
         lb_size: disk::LogicalBlockSize,
     ) -> Result<usize, HeaderError> {
-        // Build up byte array in memory
         let parts_checksum = partentry_checksum(file, self, lb_size)?;
+        let bytes = self.seal_block(parts_checksum, lb_size.as_usize());
+
+        // Write it to disk in 1 shot
+        let start = lba
+            .checked_mul(lb_size.into())
+            .ok_or(HeaderError::Overflow("writing header: lba * lbs"))?;
+        trace!("Seeking to {}", start);
+        let _ = file.seek(SeekFrom::Start(start))?;
+        file.write_all(&bytes)?;
+        trace!("Wrote {} bytes", bytes.len());
+
+        Ok(bytes.len())
+    }
+
+    /// Serializes the header for a partition array with the given CRC32
+    /// into a block of `len` bytes, updating both CRC32 fields.
+    pub(crate) fn seal_block(&mut self, parts_checksum: u32, len: usize) -> Vec<u8> {
+        // Build up byte array in memory
         self.crc32_parts = parts_checksum;
         trace!("computed partitions CRC32: {:#x}", parts_checksum);
         let (checksum_pos, mut header_bytes) = self.to_bytes(parts_checksum);
@@ -186,26 +239,17 @@ impl Header {
         BytesSeek::seek(&mut header_bytes, checksum_pos);
         header_bytes.write_le_u32(checksum);
 
-        // Write it to disk in 1 shot
-        let start = lba
-            .checked_mul(lb_size.into())
-            .ok_or(HeaderError::Overflow("writing header: lba * lbs"))?;
-        trace!("Seeking to {}", start);
-        let _ = file.seek(SeekFrom::Start(start))?;
         // Per the spec, the rest of the logical block must be zeros...
-        let mut bytes = Vec::with_capacity(lb_size.as_usize());
+        let mut bytes = Vec::with_capacity(len);
         bytes.extend_from_slice(header_bytes.as_slice());
-        bytes.resize(lb_size.as_usize(), 0);
-        file.write_all(&bytes)?;
-        trace!("Wrote {} bytes", bytes.len());
-
-        Ok(bytes.len())
+        bytes.resize(len, 0);
+        bytes
     }
 
-    /// Returns true if the num parts changes
-    pub(crate) fn num_parts_would_change(&self, partitions_len: u32) -> bool {
-        let n_num_parts = partitions_len.max(MIN_NUM_PARTS).max(self.num_parts);
-        self.num_parts != n_num_parts
+    /// Returns true if the partition array needs to grow to hold
+    /// the partition with the given id
+    pub(crate) fn num_parts_would_change(&self, partition_id: u32) -> bool {
+        partition_id > self.num_parts
     }
 
     /// returns the position where the checksum should be written
@@ -297,22 +341,27 @@ pub(crate) fn read_primary_header<D: Read + Seek>(
     file: &mut D,
     sector_size: disk::LogicalBlockSize,
 ) -> Result<Header, HeaderError> {
-    let cur = file.stream_position().unwrap_or(0);
-    let offset: u64 = sector_size.into();
-    let res = file_read_header(file, offset);
-    let _ = file.seek(SeekFrom::Start(cur));
-    res
+    read_header_at(file, 1, sector_size)
 }
 
 pub(crate) fn read_backup_header<D: Read + Seek>(
     file: &mut D,
     sector_size: disk::LogicalBlockSize,
 ) -> Result<Header, HeaderError> {
-    let cur = file.stream_position().unwrap_or(0);
     let h2sect = find_backup_lba(file, sector_size)?;
-    let offset = h2sect
+    read_header_at(file, h2sect, sector_size)
+}
+
+/// Reads the header at the given lba, restoring the position afterwards.
+pub(crate) fn read_header_at<D: Read + Seek>(
+    file: &mut D,
+    lba: u64,
+    sector_size: disk::LogicalBlockSize,
+) -> Result<Header, HeaderError> {
+    let cur = file.stream_position().unwrap_or(0);
+    let offset = lba
         .checked_mul(sector_size.into())
-        .ok_or(HeaderError::Overflow("backup header overflow - offset"))?;
+        .ok_or(HeaderError::Overflow("header overflow - offset"))?;
     let res = file_read_header(file, offset);
     let _ = file.seek(SeekFrom::Start(cur));
     res
@@ -369,7 +418,10 @@ pub(crate) fn file_read_header<D: Read + Seek>(
     if c == h.crc32 {
         Ok(h)
     } else {
-        Err(HeaderError::InvalidCRC32Checksum)
+        Err(HeaderError::InvalidCRC32Checksum {
+            expected: h.crc32,
+            actual: c,
+        })
     }
 }
 
@@ -402,7 +454,7 @@ pub(crate) fn find_backup_lba<D: Read + Seek>(
 
 const CRC_32: Crc<u32> = Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
 
-fn calculate_crc32(b: &[u8]) -> u32 {
+pub(crate) fn calculate_crc32(b: &[u8]) -> u32 {
     let mut digest = CRC_32.digest();
     trace!("Writing buffer to digest calculator");
     digest.update(b);
@@ -431,12 +483,21 @@ pub(crate) fn partentry_checksum<D: Read + Seek>(
         .checked_mul(hdr.part_size.into())
         .ok_or(HeaderError::Overflow("partition table - size"))?;
     trace!("Reading {} bytes", pt_len);
-    let mut buf = vec![0; pt_len as usize];
-    file.read_exact(&mut buf)?;
 
-    //trace!("Buffer before checksum: {:?}", buf);
-    // Compute CRC32 over all table bits.
-    Ok(calculate_crc32(&buf))
+    // Compute CRC32 over all table bits, in chunks so that a corrupted
+    // header can't make us allocate huge buffers.
+    const CHUNK: u64 = 64 * 1024;
+    let mut buf = vec![0; CHUNK.min(pt_len) as usize];
+    let mut digest = CRC_32.digest();
+    let mut remaining = pt_len;
+    while remaining > 0 {
+        let n = CHUNK.min(remaining) as usize;
+        file.read_exact(&mut buf[..n])?;
+        digest.update(&buf[..n]);
+        remaining -= n as u64;
+    }
+
+    Ok(digest.finalize())
 }
 
 /// A helper function to create a new header and write it to disk.
@@ -476,15 +537,15 @@ mod tests {
     use std::fs;
     use std::io::Cursor;
 
-    /// whats needs to be tested
-    /// creating
-    /// reading
-    /// writing
+    // whats needs to be tested
+    // creating
+    // reading
+    // writing
 
-    /// edgecases
-    /// part_size different
-    /// num_parts different
-    /// same part start
+    // edgecases
+    // part_size different
+    // num_parts different
+    // same part start
 
     fn expected_headers() -> (Header, Header) {
         let expected_primary = Header {
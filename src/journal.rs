@@ -0,0 +1,347 @@
+//! Undo journal used for transactional writes.
+//!
+//! When a journal is enabled via `GptConfig::journal`, `GptDisk::write_inplace`
+//! first records every block it is going to touch (the protective MBR, both
+//! headers and both partition arrays) together with the bytes which are going
+//! to be written there. If any step of the write fails the original bytes are
+//! restored.
+//!
+//! If the journal is kept in a file, an interrupted write (for example
+//! because of a power loss) can be undone or finished later with
+//! `recover_from_journal`.
+
+use crc::Crc;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{DiskDevice, GptError};
+
+use simple_bytes::{Bytes, BytesRead};
+
+const MAGIC: &[u8; 8] = b"GPTJRNL\0";
+const VERSION: u32 = 1;
+/// position of the state field, the state is not part of the checksum so it
+/// can be updated without rewriting the whole journal
+const STATE_OFFSET: u64 = 12;
+
+const CRC_32: Crc<u32> = Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+/// Where the undo journal of a write gets stored.
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum JournalMode {
+    /// Writes are not journaled.
+    #[default]
+    Disabled,
+    /// The journal is only kept in memory, a failed write gets rolled back
+    /// but a crash can not be recovered from.
+    Memory,
+    /// The journal is written (and synced) to the given file before the
+    /// disk is touched. The file is overwritten on every write and is
+    /// kept afterwards.
+    File(PathBuf),
+}
+
+/// The state of a journaled write.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum JournalState {
+    /// The write was started but did not finish.
+    Pending,
+    /// The write finished, the disk contains the new bytes.
+    Committed,
+    /// The write was undone, the disk contains the old bytes.
+    RolledBack,
+}
+
+impl JournalState {
+    fn to_u32(self) -> u32 {
+        match self {
+            Self::Pending => 0,
+            Self::Committed => 1,
+            Self::RolledBack => 2,
+        }
+    }
+
+    fn from_u32(v: u32) -> Option<Self> {
+        match v {
+            0 => Some(Self::Pending),
+            1 => Some(Self::Committed),
+            2 => Some(Self::RolledBack),
+            _ => None,
+        }
+    }
+}
+
+/// What `recover_from_journal` should do with a pending write.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RecoveryAction {
+    /// Restore the bytes which were on disk before the write started.
+    Rollback,
+    /// Write the remaining new bytes, finishing the write.
+    RollForward,
+}
+
+/// A region of the disk touched by a write.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct JournalEntry {
+    /// Offset in bytes from the start of the disk.
+    pub offset: u64,
+    /// Bytes before the write.
+    pub old: Vec<u8>,
+    /// Bytes after the write, always the same length as `old`.
+    pub new: Vec<u8>,
+}
+
+/// The undo (and redo) journal of a single write.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Journal {
+    state: JournalState,
+    entries: Vec<JournalEntry>,
+}
+
+impl Journal {
+    pub(crate) fn new(entries: Vec<JournalEntry>) -> Self {
+        Self {
+            state: JournalState::Pending,
+            entries,
+        }
+    }
+
+    /// The state of the write this journal belongs to.
+    pub fn state(&self) -> JournalState {
+        self.state
+    }
+
+    /// All regions touched by the write, sorted by offset.
+    pub fn entries(&self) -> &[JournalEntry] {
+        &self.entries
+    }
+
+    /// Serialize the journal.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        buf.extend_from_slice(&self.state.to_u32().to_le_bytes());
+        buf.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        for entry in &self.entries {
+            buf.extend_from_slice(&entry.offset.to_le_bytes());
+            buf.extend_from_slice(&(entry.old.len() as u64).to_le_bytes());
+            buf.extend_from_slice(&entry.old);
+            buf.extend_from_slice(&entry.new);
+        }
+        let crc = journal_checksum(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+
+        buf
+    }
+
+    /// Parse a serialized journal.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, GptError> {
+        let invalid = GptError::InvalidJournal;
+
+        // magic, version, state, entry count and checksum
+        if buf.len() < MAGIC.len() + 4 * 4 {
+            return Err(invalid("journal too short"));
+        }
+        let crc_pos = buf.len() - 4;
+        let mut bytes = Bytes::from(buf);
+        if bytes.try_read(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(invalid("invalid journal magic"));
+        }
+        if bytes.try_read_le_u32().ok() != Some(VERSION) {
+            return Err(invalid("unsupported journal version"));
+        }
+
+        let mut crc_bytes = Bytes::from(&buf[crc_pos..]);
+        if crc_bytes.try_read_le_u32().ok() != Some(journal_checksum(&buf[..crc_pos])) {
+            return Err(invalid("journal checksum mismatch"));
+        }
+
+        let state = bytes
+            .try_read_le_u32()
+            .ok()
+            .and_then(JournalState::from_u32)
+            .ok_or_else(|| invalid("invalid journal state"))?;
+        let count = bytes
+            .try_read_le_u32()
+            .map_err(|_| invalid("journal too short"))?;
+
+        let mut entries = vec![];
+        for _ in 0..count {
+            let offset = bytes
+                .try_read_le_u64()
+                .map_err(|_| invalid("journal too short"))?;
+            let len = bytes
+                .try_read_le_u64()
+                .ok()
+                .and_then(|l| usize::try_from(l).ok())
+                .ok_or_else(|| invalid("journal too short"))?;
+            let old = bytes
+                .try_read(len)
+                .map_err(|_| invalid("journal too short"))?
+                .to_vec();
+            let new = bytes
+                .try_read(len)
+                .map_err(|_| invalid("journal too short"))?
+                .to_vec();
+            entries.push(JournalEntry { offset, old, new });
+        }
+
+        if bytes.remaining().len() != 4 {
+            return Err(invalid("trailing bytes in journal"));
+        }
+
+        Ok(Self { state, entries })
+    }
+
+    /// Read a journal from the given file.
+    pub fn read_from(path: impl AsRef<Path>) -> Result<Self, GptError> {
+        let mut buf = vec![];
+        File::open(path)?.read_to_end(&mut buf)?;
+        Self::from_bytes(&buf)
+    }
+
+    /// Write the journal to the given file, making sure it reached the disk.
+    pub(crate) fn persist(&self, path: &Path) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        file.write_all(&self.to_bytes())?;
+        file.sync_all()
+    }
+
+    /// Updates the state in memory and in the file if one is given.
+    pub(crate) fn set_state(&mut self, state: JournalState, path: Option<&Path>) -> io::Result<()> {
+        self.state = state;
+        if let Some(path) = path {
+            let mut file = OpenOptions::new().write(true).open(path)?;
+            file.seek(SeekFrom::Start(STATE_OFFSET))?;
+            file.write_all(&state.to_u32().to_le_bytes())?;
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the old bytes of every entry back to the device.
+    ///
+    /// The device is flushed but not synced.
+    pub fn rollback<D: DiskDevice>(&self, device: &mut D) -> io::Result<()> {
+        self.apply(device, |e| &e.old)
+    }
+
+    /// Writes the new bytes of every entry to the device.
+    ///
+    /// The device is flushed but not synced.
+    pub fn roll_forward<D: DiskDevice>(&self, device: &mut D) -> io::Result<()> {
+        self.apply(device, |e| &e.new)
+    }
+
+    fn apply<D: DiskDevice>(
+        &self,
+        device: &mut D,
+        bytes: impl Fn(&JournalEntry) -> &Vec<u8>,
+    ) -> io::Result<()> {
+        for entry in &self.entries {
+            trace!("restoring {} bytes at {}", entry.old.len(), entry.offset);
+            device.seek(SeekFrom::Start(entry.offset))?;
+            device.write_all(bytes(entry))?;
+        }
+        device.flush()
+    }
+}
+
+fn journal_checksum(buf: &[u8]) -> u32 {
+    // the state is excluded
+    let mut digest = CRC_32.digest();
+    digest.update(&buf[..STATE_OFFSET as usize]);
+    digest.update(&buf[STATE_OFFSET as usize + 4..]);
+    digest.finalize()
+}
+
+/// Finish or undo a write which was interrupted, using the journal
+/// stored at the given path.
+///
+/// If the write is not pending anymore nothing is done. Returns the state
+/// of the journal after the recovery, the journal file is updated
+/// accordingly.
+///
+/// ## Note
+/// The device is flushed, if it is a `fs::File` you should additionally
+/// call `sync_all`.
+///
+/// ## Example
+///
+/// ```rust,no_run
+/// use gpt::journal::{recover_from_journal, RecoveryAction};
+///
+/// let mut device = std::fs::OpenOptions::new()
+///     .read(true)
+///     .write(true)
+///     .open("/dev/sdz")
+///     .unwrap();
+/// recover_from_journal(&mut device, "/var/lib/gpt.journal", RecoveryAction::Rollback).unwrap();
+/// device.sync_all().unwrap();
+/// ```
+pub fn recover_from_journal<D: DiskDevice>(
+    device: &mut D,
+    path: impl AsRef<Path>,
+    action: RecoveryAction,
+) -> Result<JournalState, GptError> {
+    let path = path.as_ref();
+    let mut journal = Journal::read_from(path)?;
+    if journal.state != JournalState::Pending {
+        debug!("journal is {:?}, nothing to recover", journal.state);
+        return Ok(journal.state);
+    }
+
+    let state = match action {
+        RecoveryAction::Rollback => {
+            journal.rollback(device)?;
+            JournalState::RolledBack
+        }
+        RecoveryAction::RollForward => {
+            journal.roll_forward(device)?;
+            JournalState::Committed
+        }
+    };
+    journal.set_state(state, Some(path))?;
+
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn journal_roundtrip() {
+        let mut journal = Journal::new(vec![
+            JournalEntry {
+                offset: 0,
+                old: vec![1; 512],
+                new: vec![2; 512],
+            },
+            JournalEntry {
+                offset: 4096,
+                old: vec![3; 1024],
+                new: vec![4; 1024],
+            },
+        ]);
+        let bytes = journal.to_bytes();
+        assert_eq!(Journal::from_bytes(&bytes).unwrap(), journal);
+
+        // the state can be changed without invalidating the checksum
+        journal.state = JournalState::Committed;
+        let mut updated = bytes.clone();
+        updated[STATE_OFFSET as usize] = 1;
+        assert_eq!(Journal::from_bytes(&updated).unwrap(), journal);
+
+        let mut corrupted = bytes;
+        corrupted[40] ^= 0xff;
+        assert!(matches!(
+            Journal::from_bytes(&corrupted),
+            Err(GptError::InvalidJournal(_))
+        ));
+        assert!(Journal::from_bytes(&[]).is_err());
+    }
+}
@@ -0,0 +1,403 @@
+//! Reconciling a disk with a declared layout, similar to systemd-repart.
+//!
+//! A `Layout` lists the partitions a disk should have as `PartitionSpec`s.
+//! `GptDisk::plan_layout` matches them with the existing partitions and
+//! returns a `LayoutPlan` of the changes needed: partitions which are
+//! missing get created (in the order of the specs, each after the one
+//! before it), partitions with a size range grow into the free space
+//! behind them and flags and names get updated. Nothing is changed until
+//! the plan is passed to `GptDisk::apply_layout`.
+//!
+//! Reconciling never destroys data: partitions never shrink or move and
+//! partitions which are not part of the layout are only removed if
+//! `Layout::allow_removal` is set.
+//!
+//! ## Example
+//!
+//! ```rust,no_run
+//! use gpt::layout::{Layout, PartitionSpec};
+//! use gpt::partition_size::PartitionSize;
+//! use gpt::partition_types;
+//!
+//! let layout = Layout::new()
+//!     .partition(PartitionSpec::new("esp", partition_types::EFI, 512 * 1024 * 1024))
+//!     .partition(
+//!         PartitionSpec::new("root", partition_types::LINUX_FS, 8 * 1024 * 1024 * 1024)
+//!             .max_size(PartitionSize::Remaining),
+//!     );
+//!
+//! let mut disk = gpt::GptConfig::new()
+//!     .writable(true)
+//!     .open("/dev/sdz")
+//!     .unwrap();
+//! let plan = disk.plan_layout(&layout).unwrap();
+//! for action in plan.actions() {
+//!     println!("{action:?}");
+//! }
+//! disk.apply_layout(&plan).unwrap();
+//! disk.write().unwrap();
+//! ```
+
+use crate::partition::Partition;
+use crate::partition_size::PartitionSize;
+use crate::partition_types::Type;
+use crate::placement::Placement;
+use crate::{DiskDevice, GptDisk, GptError};
+
+/// A partition a `Layout` should contain.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PartitionSpec {
+    name: String,
+    part_type: Type,
+    size: PartitionSize,
+    max_size: Option<PartitionSize>,
+    guid: Option<uuid::Uuid>,
+    flags: u64,
+}
+
+impl PartitionSpec {
+    /// A partition with the given name, type and (minimum) size.
+    pub fn new(name: &str, part_type: Type, size: impl Into<PartitionSize>) -> Self {
+        Self {
+            name: name.to_string(),
+            part_type,
+            size: size.into(),
+            max_size: None,
+            guid: None,
+            flags: 0,
+        }
+    }
+
+    /// Sets the maximum size, making the partition grow into the free
+    /// space behind it. Percentages and the remaining space refer to the
+    /// space up to the next partition.
+    pub fn max_size(mut self, max_size: impl Into<PartitionSize>) -> Self {
+        self.max_size = Some(max_size.into());
+        self
+    }
+
+    /// Sets the partition GUID.
+    ///
+    /// A spec with a GUID only matches the partition with that GUID,
+    /// otherwise it matches a partition with the same type and name.
+    pub fn guid(mut self, guid: uuid::Uuid) -> Self {
+        self.guid = Some(guid);
+        self
+    }
+
+    /// Sets the attribute flags of the partition.
+    pub fn flags(mut self, flags: u64) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Returns true if `part` is the partition described by this spec.
+    fn matches(&self, part: &Partition) -> bool {
+        match self.guid {
+            Some(guid) => part.part_guid == guid,
+            None => part.part_type_guid == self.part_type && part.name == self.name,
+        }
+    }
+}
+
+/// The partitions a disk should contain, in the order they should be
+/// placed on the disk.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Layout {
+    partitions: Vec<PartitionSpec>,
+    allow_removal: bool,
+}
+
+impl Layout {
+    /// An empty layout.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a partition to the layout.
+    pub fn partition(mut self, spec: PartitionSpec) -> Self {
+        self.partitions.push(spec);
+        self
+    }
+
+    /// Sets wether partitions which are not part of the layout get
+    /// removed, by default they are kept.
+    pub fn allow_removal(mut self, allow_removal: bool) -> Self {
+        self.allow_removal = allow_removal;
+        self
+    }
+
+    /// The partitions of the layout.
+    pub fn partitions(&self) -> &[PartitionSpec] {
+        &self.partitions
+    }
+}
+
+/// A change needed to reconcile a disk with a layout.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum LayoutAction {
+    /// Remove a partition which is not part of the layout.
+    Remove {
+        /// The id of the partition
+        id: u32,
+    },
+    /// Set the name and flags of an existing partition.
+    Update {
+        /// The id of the partition
+        id: u32,
+        /// The new name
+        name: String,
+        /// The new attribute flags
+        flags: u64,
+    },
+    /// Create a missing partition.
+    Create {
+        /// The id of the new partition
+        id: u32,
+        /// The new partition entry
+        partition: Partition,
+    },
+    /// Grow a partition, keeping its first lba.
+    Grow {
+        /// The id of the partition
+        id: u32,
+        /// The current last lba
+        old_last_lba: u64,
+        /// The new last lba
+        last_lba: u64,
+    },
+}
+
+/// The changes needed to reconcile a disk with a layout.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct LayoutPlan {
+    actions: Vec<LayoutAction>,
+    ids: Vec<u32>,
+}
+
+impl LayoutPlan {
+    /// All changes in the order they get applied.
+    pub fn actions(&self) -> &[LayoutAction] {
+        &self.actions
+    }
+
+    /// Returns true if the disk already matches the layout.
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    /// The partition id of every spec of the layout, in the same order.
+    pub fn ids(&self) -> &[u32] {
+        &self.ids
+    }
+}
+
+impl<D> GptDisk<D>
+where
+    D: DiskDevice,
+{
+    /// Computes the changes needed to make the partitions match the
+    /// layout, without changing anything.
+    ///
+    /// Fails with `GptError::PartitionTypeMismatch` if the partition with
+    /// the GUID of a spec has another type, with `GptError::NotEnoughSpace`
+    /// if a partition can not be created or grown to its minimum size and
+    /// with `GptError::InvalidLayout` if a new partition would collide.
+    pub fn plan_layout(&mut self, layout: &Layout) -> Result<LayoutPlan, GptError> {
+        let mut scratch = self.map_device(Ok)?;
+        let mut plan = LayoutPlan::default();
+
+        // every spec matches at most one partition and the other way around
+        let mut matched: Vec<Option<u32>> = vec![];
+        for spec in &layout.partitions {
+            let id = scratch
+                .partitions
+                .iter()
+                .filter(|(id, p)| p.is_used() && !matched.contains(&Some(**id)))
+                .find(|(_, p)| spec.matches(p))
+                .map(|(id, _)| *id);
+            if let Some(id) = id {
+                if scratch.partitions[&id].part_type_guid != spec.part_type {
+                    return Err(GptError::PartitionTypeMismatch(id));
+                }
+            }
+            matched.push(id);
+        }
+
+        if layout.allow_removal {
+            let unmatched: Vec<u32> = scratch
+                .partitions
+                .iter()
+                .filter(|(id, p)| p.is_used() && !matched.contains(&Some(**id)))
+                .map(|(id, _)| *id)
+                .collect();
+            for id in unmatched {
+                plan.push(&mut scratch, LayoutAction::Remove { id })?;
+            }
+        }
+
+        let lb_size = scratch.config.lb_size.as_u64();
+        let mut previous_end = None;
+        for (spec, id) in layout.partitions.iter().zip(matched) {
+            let id = match id {
+                Some(id) => {
+                    let part = &scratch.partitions[&id];
+                    if part.name != spec.name || part.flags != spec.flags {
+                        let name = spec.name.clone();
+                        let flags = spec.flags;
+                        plan.push(&mut scratch, LayoutAction::Update { id, name, flags })?;
+                    }
+                    id
+                }
+                None => {
+                    // new partitions follow the one of the previous spec
+                    let placement = match previous_end {
+                        Some(end) => Placement::Within {
+                            first_lba: end + 1,
+                            last_lba: u64::MAX,
+                        },
+                        None => scratch.config.placement,
+                    };
+                    let (first_lba, size_lba) = scratch.locate(spec.size, None, placement)?;
+                    let id = scratch.next_partition_id()?;
+                    let partition = Partition {
+                        part_type_guid: spec.part_type.clone(),
+                        part_guid: spec.guid.unwrap_or_else(uuid::Uuid::new_v4),
+                        first_lba,
+                        // fits because the free extent ends at the latest at last_usable
+                        last_lba: first_lba + (size_lba - 1),
+                        flags: spec.flags,
+                        name: spec.name.clone(),
+                        extra: vec![],
+                    };
+                    plan.push(&mut scratch, LayoutAction::Create { id, partition })?;
+                    id
+                }
+            };
+            previous_end = Some(scratch.partitions[&id].last_lba);
+            plan.ids.push(id);
+        }
+
+        // grow in order, every partition only into the space behind it
+        for (spec, id) in layout.partitions.iter().zip(plan.ids.clone()) {
+            let part = &scratch.partitions[&id];
+            let (first_lba, old_last_lba) = (part.first_lba, part.last_lba);
+            let length = part.sectors_len()?;
+            let limit = scratch.partition_end_limit(id, first_lba)?;
+            let available = limit
+                .checked_sub(first_lba)
+                .map_or(0, |len| len.saturating_add(1));
+            let free: &[(u64, u64)] = match available {
+                0 => &[],
+                _ => &[(first_lba, available)],
+            };
+
+            let min_lba = spec.size.to_lba(lb_size, free)?;
+            if min_lba > available {
+                return Err(GptError::NotEnoughSpace {
+                    requested: min_lba,
+                    largest_free: available,
+                });
+            }
+            let max_lba = match spec.max_size {
+                Some(max_size) => max_size.to_lba(lb_size, free)?.min(available),
+                None => min_lba,
+            };
+            let size_lba = max_lba.max(min_lba);
+            if size_lba <= length {
+                continue;
+            }
+
+            let mut last_lba = first_lba + (size_lba - 1);
+            // end at a physical block if that is still large enough
+            let physical = scratch.physical_block_lba();
+            let aligned = last_lba - (last_lba + 1) % physical;
+            if aligned > old_last_lba && aligned - first_lba + 1 >= min_lba {
+                last_lba = aligned;
+            }
+            plan.push(
+                &mut scratch,
+                LayoutAction::Grow {
+                    id,
+                    old_last_lba,
+                    last_lba,
+                },
+            )?;
+        }
+
+        Ok(plan)
+    }
+
+    /// Applies a plan returned by `plan_layout`.
+    ///
+    /// Fails if the partitions changed in a way which conflicts with the
+    /// plan, a grown partition which was changed in between fails with
+    /// `GptError::StaleLayoutPlan`. Either all actions are applied or none.
+    /// No changes are recorded to disk until `write()` is called.
+    pub fn apply_layout(&mut self, plan: &LayoutPlan) -> Result<(), GptError> {
+        let mut scratch = self.map_device(Ok)?;
+        for action in &plan.actions {
+            scratch.apply_layout_action(action)?;
+        }
+
+        // creating a partition might have grown the partition array
+        let guid = scratch.guid;
+        let primary_header = scratch.primary_header;
+        let backup_header = scratch.backup_header;
+        let partitions = scratch.partitions;
+        self.guid = guid;
+        self.primary_header = primary_header;
+        self.backup_header = backup_header;
+        self.partitions = partitions;
+        Ok(())
+    }
+
+    fn apply_layout_action(&mut self, action: &LayoutAction) -> Result<(), GptError> {
+        debug!("layout: {:?}", action);
+        match action {
+            LayoutAction::Remove { id } => {
+                self.remove_partition(*id);
+            }
+            LayoutAction::Update { id, name, flags } => {
+                let part = self
+                    .partitions
+                    .get_mut(id)
+                    .filter(|p| p.is_used())
+                    .ok_or(GptError::PartitionNotFound(*id))?;
+                part.name = name.clone();
+                part.flags = *flags;
+            }
+            LayoutAction::Create { id, partition } => {
+                if self.partitions.get(id).map_or(false, |p| p.is_used()) {
+                    return Err(GptError::PartitionIdAlreadyUsed(*id));
+                }
+                self.insert_partition(*id, partition.clone())?;
+            }
+            LayoutAction::Grow {
+                id,
+                old_last_lba,
+                last_lba,
+            } => {
+                if self.used_partition(*id)?.last_lba != *old_last_lba {
+                    return Err(GptError::StaleLayoutPlan(*id));
+                }
+                self.set_partition_end(*id, *last_lba)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl LayoutPlan {
+    /// Applies the action to the scratch disk and records it.
+    fn push<D: DiskDevice>(
+        &mut self,
+        scratch: &mut GptDisk<D>,
+        action: LayoutAction,
+    ) -> Result<(), GptError> {
+        scratch.apply_layout_action(&action)?;
+        self.actions.push(action);
+        Ok(())
+    }
+}
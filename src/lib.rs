@@ -45,6 +45,8 @@
 //!     let mut gdisk = gpt::GptConfig::default()
 //!         .writable(true)
 //!         .logical_block_size(gpt::disk::LogicalBlockSize::Lb512)
+//!         // the image is too small for the default 1MiB alignment
+//!         .alignment_fallback(true)
 //!         .create_from_device(mem_device, None)
 //!         .expect("failed to crate GptDisk");
 //!
@@ -79,13 +81,29 @@ mod macros;
 #[macro_use]
 mod logging;
 pub mod disk;
+mod geometry;
 pub mod header;
+pub mod journal;
+pub mod layout;
 pub mod mbr;
 pub mod partition;
+pub mod partition_move;
+pub mod partition_size;
 pub mod partition_types;
+pub mod placement;
+pub mod plan;
+mod record;
+pub mod repair;
+pub mod repart;
+#[cfg(feature = "serde")]
+mod serde_hex;
+pub mod sfdisk;
+pub mod sgdisk;
+pub mod verify;
 
 use header::HeaderError;
 use macros::ResultInsert;
+use partition::PartitionError;
 
 /// A generic device that we can read/write partitions from/to.
 pub trait DiskDevice: Read + Write + Seek + std::fmt::Debug {}
@@ -105,6 +123,24 @@ pub enum GptError {
     Io(io::Error),
     /// Error returned from writing or reading the header
     Header(HeaderError),
+    /// Error returned from writing or reading partition entries
+    Partition(PartitionError),
+    /// Error returned from writing or reading the protective MBR
+    Mbr(mbr::MBRError),
+    /// A header of the disk could not be read
+    InvalidHeader {
+        /// The header which failed, either the primary or the backup header
+        structure: GptStructure,
+        /// Why reading the header failed
+        error: HeaderError,
+    },
+    /// A partition array of the disk could not be read
+    InvalidPartitionArray {
+        /// The partition array which failed, either the primary or the backup entries
+        structure: GptStructure,
+        /// Why reading the partition array failed
+        error: PartitionError,
+    },
     /// we were expecting to read an existing partition table, but instead we're
     /// attempting to create a new blank table
     CreatingInitializedDisk,
@@ -112,7 +148,12 @@ pub enum GptError {
     /// This will never occur when dealing with sane values
     Overflow(&'static str),
     /// Unable to find enough space on drive
-    NotEnoughSpace,
+    NotEnoughSpace {
+        /// The number of logical blocks which were requested
+        requested: u64,
+        /// The largest number of logical blocks which would have been available
+        largest_free: u64,
+    },
     /// disk not opened in writable mode
     ReadOnly,
     /// If you try to create more partition than the header supports
@@ -120,7 +161,57 @@ pub enum GptError {
     /// The partition count changes but you did not allow that
     PartitionCountWouldChange,
     /// The id is already been used
-    PartitionIdAlreadyUsed,
+    PartitionIdAlreadyUsed(u32),
+    /// Reading back the written structure did not return what was written
+    WriteVerificationFailed(GptStructure),
+    /// The journal could not be parsed
+    InvalidJournal(&'static str),
+    /// A journaled write failed and restoring the original bytes failed as well
+    ///
+    /// If the journal is stored in a file `journal::recover_from_journal`
+    /// can be used to retry the rollback.
+    RollbackFailed(io::Error),
+    /// The primary and backup partition arrays differ in entry count or size
+    PartitionArrayMismatch,
+    /// The partitions with the given ids do not fit on the device
+    ///
+    /// Either the device shrunk below the end of these partitions or the
+    /// backup structures would be moved on top of them.
+    PartitionsDoNotFit(Vec<u32>),
+    /// No used partition with the given id exists
+    PartitionNotFound(u32),
+    /// A partition needs to be at least one logical block large
+    InvalidPartitionSize,
+    /// Partition ids start at 1
+    InvalidPartitionId,
+    /// Something would be written beyond the end of the device
+    BeyondDeviceEnd {
+        /// The offset in bytes where the write would end
+        end: u64,
+        /// The size of the device in bytes
+        device_size: u64,
+    },
+    /// A change would leave the partitions overlapping, outside the usable
+    /// lbas or with duplicate GUIDs
+    InvalidLayout(verify::Problem),
+    /// The partition with the GUID of a `layout::PartitionSpec` has another type
+    PartitionTypeMismatch(u32),
+    /// Data from a disk with another logical block size can not be applied
+    LogicalBlockSizeMismatch {
+        /// The logical block size of this disk
+        expected: u64,
+        /// The logical block size of the data
+        actual: u64,
+    },
+    /// The header of the partition array is missing and its location is
+    /// neither configured nor the default one
+    UnknownPartitionArrayLocation(GptStructure),
+    /// The partition with the given id changed since the layout plan was made
+    StaleLayoutPlan(u32),
+    /// The partition with the given id changed since its move was prepared
+    StalePartitionMove(u32),
+    /// The partitions were changed in memory and not written yet
+    UnwrittenChanges,
 }
 
 impl From<io::Error> for GptError {
@@ -129,13 +220,35 @@ impl From<io::Error> for GptError {
     }
 }
 
+impl From<mbr::MBRError> for GptError {
+    fn from(e: mbr::MBRError) -> Self {
+        Self::Mbr(e)
+    }
+}
+
 impl From<HeaderError> for GptError {
     fn from(e: HeaderError) -> Self {
         Self::Header(e)
     }
 }
 
-impl std::error::Error for GptError {}
+impl From<PartitionError> for GptError {
+    fn from(e: PartitionError) -> Self {
+        Self::Partition(e)
+    }
+}
+
+impl std::error::Error for GptError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) | Self::RollbackFailed(e) => Some(e),
+            Self::Header(e) | Self::InvalidHeader { error: e, .. } => Some(e),
+            Self::Partition(e) | Self::InvalidPartitionArray { error: e, .. } => Some(e),
+            Self::Mbr(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl fmt::Display for GptError {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -143,20 +256,105 @@ impl fmt::Display for GptError {
         let desc = match self {
             Io(e) => return write!(fmt, "GPT IO Error: {e}"),
             Header(e) => return write!(fmt, "GPT Header Error: {e}"),
+            Partition(e) => return write!(fmt, "GPT Partition Error: {e}"),
+            Mbr(e) => return write!(fmt, "GPT MBR Error: {e}"),
+            InvalidHeader { structure, error } => {
+                return write!(fmt, "invalid {structure}: {error}")
+            }
+            InvalidPartitionArray { structure, error } => {
+                return write!(fmt, "invalid {structure}: {error}")
+            }
             CreatingInitializedDisk => {
                 "we were expecting to read an existing \
                 partition table, but instead we're attempting to create a \
                 new blank table"
             }
             Overflow(m) => return write!(fmt, "GTP error Overflow: {m}"),
-            NotEnoughSpace => "Unable to find enough space on drive",
+            NotEnoughSpace {
+                requested,
+                largest_free,
+            } => {
+                return write!(
+                    fmt,
+                    "Unable to find enough space on drive, requested {requested} \
+                    logical blocks but at most {largest_free} are free"
+                )
+            }
             ReadOnly => "disk not opened in writable mode",
             OverflowPartitionCount => "not enough partition slots",
             PartitionCountWouldChange => {
                 "partition would change but is not \
             allowed"
             }
-            PartitionIdAlreadyUsed => "partition id already used",
+            PartitionIdAlreadyUsed(id) => return write!(fmt, "partition id {id} already used"),
+            WriteVerificationFailed(s) => {
+                return write!(fmt, "read-back verification of the {s} failed")
+            }
+            InvalidJournal(m) => return write!(fmt, "invalid journal: {m}"),
+            RollbackFailed(e) => return write!(fmt, "failed to roll back write: {e}"),
+            PartitionArrayMismatch => "primary and backup partition arrays have different layouts",
+            PartitionsDoNotFit(ids) => {
+                return write!(fmt, "partitions {ids:?} do not fit on the device")
+            }
+            PartitionNotFound(id) => return write!(fmt, "partition {id} not found"),
+            InvalidPartitionSize => "partition size must be greater than zero",
+            InvalidPartitionId => "partition id must be greater than zero",
+            UnwrittenChanges => "the partitions were changed and not written yet",
+            BeyondDeviceEnd { end, device_size } => {
+                return write!(
+                    fmt,
+                    "write would end at byte {end} beyond the end of the device \
+                    ({device_size} bytes)"
+                )
+            }
+            InvalidLayout(problem) => return write!(fmt, "invalid partition layout: {problem}"),
+            PartitionTypeMismatch(id) => {
+                return write!(fmt, "partition {id} does not have the type of its spec")
+            }
+            LogicalBlockSizeMismatch { expected, actual } => {
+                return write!(
+                    fmt,
+                    "logical block size {actual} does not match the disk ({expected})"
+                )
+            }
+            UnknownPartitionArrayLocation(s) => {
+                return write!(fmt, "the location of the {s} is unknown")
+            }
+            StaleLayoutPlan(id) => {
+                return write!(fmt, "partition {id} changed since the layout was planned")
+            }
+            StalePartitionMove(id) => {
+                return write!(fmt, "partition {id} changed since its move was prepared")
+            }
+        };
+        write!(fmt, "{desc}")
+    }
+}
+
+/// The on-disk structures making up a GPT disk.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub enum GptStructure {
+    /// The protective MBR at LBA0
+    ProtectiveMbr,
+    /// The primary header at LBA1
+    PrimaryHeader,
+    /// The partition array the primary header points to
+    PrimaryEntries,
+    /// The partition array the backup header points to
+    BackupEntries,
+    /// The backup header, usually at the last LBA
+    BackupHeader,
+}
+
+impl fmt::Display for GptStructure {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use GptStructure::*;
+        let desc = match self {
+            ProtectiveMbr => "protective MBR",
+            PrimaryHeader => "primary header",
+            PrimaryEntries => "primary partition array",
+            BackupEntries => "backup partition array",
+            BackupHeader => "backup header",
         };
         write!(fmt, "{desc}")
     }
@@ -176,11 +374,20 @@ impl fmt::Display for GptError {
 ///     .logical_block_size(gpt::disk::DEFAULT_SECTOR_SIZE)
 ///     .only_valid_headers(false)
 ///     .readonly_backup(false)
-///     .change_partition_count(false);
+///     .change_partition_count(false)
+///     .verify_writes(false)
+///     .journal(gpt::journal::JournalMode::Disabled)
+///     .primary_part_start(0)
+///     .backup_part_start(0)
+///     .alignment(1024 * 1024)
+///     .physical_block_size(0)
+///     .placement(gpt::placement::Placement::FirstFit);
 /// ```
 //
 // write_backup, allow_first_usable_last_usable, change
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct GptConfig {
     /// Logical block size.
     lb_size: disk::LogicalBlockSize,
@@ -195,6 +402,23 @@ pub struct GptConfig {
     /// ## Warning
     /// This might change the first usable and last usable part
     change_partition_count: bool,
+    /// Read back every written header and partition array
+    verify_writes: bool,
+    /// Where to store the undo journal of a write
+    journal: journal::JournalMode,
+    /// First lba of the primary partition array of new tables (0 means lba 2)
+    primary_part_start: u64,
+    /// First lba of the backup partition array of new tables (0 means
+    /// directly before the backup header)
+    backup_part_start: u64,
+    /// Alignment of new partitions in bytes
+    alignment: u64,
+    /// Place partitions at the physical block size if they don't fit aligned
+    alignment_fallback: bool,
+    /// Size of physical blocks in bytes (0 means the logical block size)
+    physical_block_size: u64,
+    /// Where `add_partition` places new partitions
+    placement: placement::Placement,
 }
 
 impl GptConfig {
@@ -236,8 +460,93 @@ impl GptConfig {
         self
     }
 
+    /// Sets wether every header and partition array should be read back
+    /// after it was written, failing the write if the CRCs don't match.
+    ///
+    /// See `GptDisk::write_inplace` for the order in which the structures
+    /// are written and verified.
+    pub fn verify_writes(mut self, verify_writes: bool) -> Self {
+        self.verify_writes = verify_writes;
+        self
+    }
+
+    /// Sets wether writes should be transactional and where their undo
+    /// journal gets stored.
+    ///
+    /// If a journaled write fails all blocks it touched are restored.
+    /// See the `journal` module for more information.
+    pub fn journal(mut self, journal: journal::JournalMode) -> Self {
+        self.journal = journal;
+        self
+    }
+
+    /// Sets the first lba of the primary partition array when creating
+    /// a new partition table, for example to keep room for boot loader
+    /// payloads after the primary header.
+    ///
+    /// By default (or if set to 0) the array starts at lba 2. The first
+    /// usable lba follows the array. Existing tables keep their layout,
+    /// this is only used for them if the primary header is damaged and
+    /// gets rebuilt.
+    pub fn primary_part_start(mut self, primary_part_start: u64) -> Self {
+        self.primary_part_start = primary_part_start;
+        self
+    }
+
+    /// Sets the first lba of the backup partition array when creating
+    /// a new partition table.
+    ///
+    /// By default (or if set to 0) the array ends directly before the
+    /// backup header. The last usable lba is in front of the array.
+    /// Existing tables keep their layout, this is only used for them if
+    /// the backup header is damaged and gets rebuilt.
+    pub fn backup_part_start(mut self, backup_part_start: u64) -> Self {
+        self.backup_part_start = backup_part_start;
+        self
+    }
+
+    /// Sets the alignment (in bytes) new partitions get placed at if no
+    /// alignment is given explicitly, 1MiB by default.
+    ///
+    /// The alignment is rounded up to whole physical blocks. A partition
+    /// which does not fit anywhere aligned fails with
+    /// `GptError::NotEnoughSpace`, unless `alignment_fallback` is set.
+    /// 0 disables the alignment.
+    pub fn alignment(mut self, alignment: u64) -> Self {
+        self.alignment = alignment;
+        self
+    }
+
+    /// Places partitions which do not fit anywhere at the configured
+    /// alignment at the physical block size instead, false by default.
+    ///
+    /// This is useful for small images, `GptDisk::verify` reports such
+    /// partitions as misaligned.
+    pub fn alignment_fallback(mut self, alignment_fallback: bool) -> Self {
+        self.alignment_fallback = alignment_fallback;
+        self
+    }
+
+    /// Sets the size (in bytes) of the physical blocks of the device, for
+    /// example 4096 for a drive with 4K sectors emulating 512 byte sectors.
+    ///
+    /// Partitions always start at a physical block and `GptDisk::verify`
+    /// warns about partitions which don't. By default (or if set to 0)
+    /// it is the same as the logical block size.
+    pub fn physical_block_size(mut self, physical_block_size: u64) -> Self {
+        self.physical_block_size = physical_block_size;
+        self
+    }
+
+    /// Sets where `GptDisk::add_partition` places new partitions, by
+    /// default at the first free extent which is large enough.
+    pub fn placement(mut self, placement: placement::Placement) -> Self {
+        self.placement = placement;
+        self
+    }
+
     /// Open the GPT disk at the given path and inspect it according
-    /// to configuration options.
+    /// to configuration options, see `open_from_device`.
     pub fn open(self, diskpath: impl AsRef<path::Path>) -> Result<GptDisk<fs::File>, GptError> {
         let file = fs::OpenOptions::new()
             .write(self.writable)
@@ -266,35 +575,75 @@ impl GptConfig {
 
     /// Open the GPT disk from the given DiskDeviceObject and
     /// inspect it according to configuration options.
+    ///
+    /// If the device grew since the table was written the backup header
+    /// is found where the primary header points to, before the end of the
+    /// device. `GptDisk::grown_to` reports this and
+    /// `GptDisk::relocate_backup_to_end` moves it to the end.
     pub fn open_from_device<D>(self, mut device: D) -> Result<GptDisk<D>, GptError>
     where
         D: DiskDevice,
     {
         // Proper GPT disk, fully inspect its layout.
         let h1 = header::read_primary_header(&mut device, self.lb_size);
-        let h2 = header::read_backup_header(&mut device, self.lb_size);
+        if let Ok(h1) = &h1 {
+            geometry::check_device_size(&mut device, h1, self.lb_size)?;
+        }
+        // if the device grew the backup header is not at the end anymore
+        let h2 = match &h1 {
+            Ok(h1) => header::read_header_at(&mut device, h1.backup_lba, self.lb_size)
+                .or_else(|_| header::read_backup_header(&mut device, self.lb_size)),
+            Err(_) => header::read_backup_header(&mut device, self.lb_size),
+        };
 
-        let (h1, h2) = if self.only_valid_headers {
-            (Ok(h1?), Ok(h2?))
-        } else if h1.is_err() && h2.is_err() {
-            return Err(h1.unwrap_err().into());
-        } else {
-            (h1, h2)
+        let invalid_header = |structure, error| GptError::InvalidHeader { structure, error };
+        let (h1, h2) = match (h1, h2) {
+            (Err(e), _) if self.only_valid_headers => {
+                return Err(invalid_header(GptStructure::PrimaryHeader, e))
+            }
+            (_, Err(e)) if self.only_valid_headers => {
+                return Err(invalid_header(GptStructure::BackupHeader, e))
+            }
+            (Err(e), Err(_)) => return Err(invalid_header(GptStructure::PrimaryHeader, e)),
+            (h1, h2) => (h1, h2),
         };
 
-        let header = h1.as_ref().or(h2.as_ref()).unwrap();
-        let table = partition::file_read_partitions(&mut device, header, self.lb_size)?;
+        let (header, entries) = match (&h1, &h2) {
+            (Ok(h1), _) => (h1, GptStructure::PrimaryEntries),
+            (_, Ok(h2)) => (h2, GptStructure::BackupEntries),
+            (Err(e), Err(_)) => return Err(e.lossy_clone().into()),
+        };
+        let invalid_array = |structure, error| GptError::InvalidPartitionArray { structure, error };
+        let table = match (
+            partition::file_read_partitions(&mut device, header, self.lb_size),
+            &h2,
+        ) {
+            (Ok(table), _) => table,
+            // the primary partition array might have been interrupted while
+            // being written, in that case the backup array is still valid
+            (Err(e), Ok(h2)) if !self.only_valid_headers && h1.is_ok() => {
+                debug!("primary partition array invalid ({}), using backup", e);
+                partition::file_read_partitions(&mut device, h2, self.lb_size)
+                    .map_err(|e| invalid_array(GptStructure::BackupEntries, e))?
+            }
+            (Err(e), _) => return Err(invalid_array(entries, e)),
+        };
 
-        let disk = GptDisk {
+        let mut disk = GptDisk {
             config: self,
             device,
             guid: header.disk_guid,
             primary_header: h1,
             backup_header: h2,
             partitions: table,
+            grown_to: None,
             sync_all: None,
         };
         debug!("disk: {:?}", disk);
+        disk.grown_to = disk.disk_grown().ok().flatten();
+        if let Some(end) = disk.grown_to {
+            debug!("device grew, backup header could be moved to lba {}", end);
+        }
         Ok(disk)
     }
 
@@ -315,6 +664,7 @@ impl GptConfig {
             primary_header: Err(HeaderError::InvalidGptSignature),
             backup_header: Err(HeaderError::InvalidGptSignature),
             partitions: BTreeMap::new(),
+            grown_to: None,
             sync_all: None,
         };
         // setup default headers
@@ -331,6 +681,14 @@ impl Default for GptConfig {
             only_valid_headers: false,
             readonly_backup: false,
             change_partition_count: false,
+            verify_writes: false,
+            journal: journal::JournalMode::Disabled,
+            primary_part_start: 0,
+            backup_part_start: 0,
+            alignment: 1024 * 1024,
+            alignment_fallback: false,
+            physical_block_size: 0,
+            placement: placement::Placement::FirstFit,
         }
     }
 }
@@ -345,6 +703,8 @@ pub struct GptDisk<D> {
     backup_header: Result<header::Header, HeaderError>,
     /// partition: 0 does never exist
     partitions: BTreeMap<u32, partition::Partition>,
+    /// the last lba of the device if it grew, found on open
+    grown_to: Option<u64>,
     // we need this because to really make sure all content is written
     // a call to sync_all is required (but this is only possible with a fs::File)
     sync_all: Option<fn(&mut D) -> io::Result<()>>,
@@ -362,6 +722,7 @@ where
             .field("primary_header", &self.primary_header)
             .field("backup_header", &self.backup_header)
             .field("partitions", &self.partitions)
+            .field("grown_to", &self.grown_to)
             .finish()
     }
 }
@@ -383,6 +744,7 @@ impl<D: Clone> Clone for GptDisk<D> {
                 .map_err(|e| e.lossy_clone())
                 .cloned(),
             partitions: self.partitions.clone(),
+            grown_to: self.grown_to,
             sync_all: self.sync_all,
         }
     }
@@ -399,21 +761,16 @@ impl<D> GptDisk<D> {
         self.backup_header.as_ref().map_err(|e| e.lossy_clone())
     }
 
-    /// Retrieve the current valid header.
+    /// Retrieve the current valid header, the primary one if it is valid.
     ///
     /// This can only fail while we're building the disk
-    fn try_header(&self) -> Result<&header::Header, HeaderError> {
+    pub fn header(&self) -> Result<&header::Header, HeaderError> {
         self.primary_header
             .as_ref()
             .or(self.backup_header.as_ref())
             .map_err(|e| e.lossy_clone())
     }
 
-    /// Retrieve the current valid header.
-    pub fn header(&self) -> &header::Header {
-        self.try_header().expect("no primary and no backup header")
-    }
-
     /// Retrieve partition entries.
     pub fn partitions(&self) -> &BTreeMap<u32, partition::Partition> {
         &self.partitions
@@ -455,6 +812,7 @@ impl<D> GptDisk<D> {
                 .map_err(|e| e.lossy_clone())
                 .cloned(),
             partitions: self.partitions.clone(),
+            grown_to: None,
             sync_all: None,
         };
         n.config.writable = writable;
@@ -486,89 +844,147 @@ where
     D: DiskDevice,
 {
     /// Add another partition to this disk.  This tries to find
-    /// the optimum partition location with the lowest block device.
+    /// the optimum partition location according to `GptConfig::placement`,
+    /// by default with the lowest block device.
     /// Returns the new partition id if there was sufficient room
-    /// to add the partition. Size is specified in bytes.
+    /// to add the partition. Size is specified in bytes or as a
+    /// `PartitionSize`, see `resolve_size` for the resulting size.
+    ///
+    /// If no alignment (in lba) is given the partition is aligned according
+    /// to `GptConfig::alignment` and `GptConfig::physical_block_size`.
     ///
-    /// ## Panics
-    /// If size is empty panics
+    /// Returns `GptError::InvalidPartitionSize` if size is zero and
+    /// `GptError::NotEnoughSpace` if it doesn't fit anywhere aligned.
     pub fn add_partition(
         &mut self,
         name: &str,
-        size: u64,
+        size: impl Into<partition_size::PartitionSize>,
         part_type: partition_types::Type,
         flags: u64,
         part_alignment: Option<u64>,
     ) -> Result<u32, GptError> {
-        assert!(size > 0, "size must be greater than zero");
-
-        // Ceiling division which avoids overflow
-        let size_lba = (size - 1)
-            .checked_div(self.config.lb_size.into())
-            .ok_or(GptError::Overflow(
-                "invalid logical block size caused bad \
-                division when calculating size in blocks",
-            ))?
-            // we will never divide by 1 so we always have room for one more
-            + 1;
-
-        // Find the lowest lba that is larger than size.
-        let free_sections = self.find_free_sectors();
-        for (starting_lba, length) in free_sections {
-            // Get the distance between the starting LBA of this section and the next aligned LBA
-            // We don't need to do any checked math here because we guarantee that with `(A % B)`,
-            // `A` will always be between 0 and `B-1`.
-            let alignment_offset_lba = match part_alignment {
-                Some(alignment) => (alignment - (starting_lba % alignment)) % alignment,
-                None => 0_u64,
-            };
+        let placement = self.config.placement;
+        self.add_partition_placed(name, size, part_type, flags, part_alignment, placement)
+    }
 
-            debug!(
-                "starting_lba {}, length {}, alignment_offset_lba {}",
-                starting_lba, length, alignment_offset_lba
-            );
+    /// Add another partition to this disk like `add_partition`, placing
+    /// it according to the given strategy instead of the configured one.
+    pub fn add_partition_placed(
+        &mut self,
+        name: &str,
+        size: impl Into<partition_size::PartitionSize>,
+        part_type: partition_types::Type,
+        flags: u64,
+        part_alignment: Option<u64>,
+        placement: placement::Placement,
+    ) -> Result<u32, GptError> {
+        let (starting_lba, size_lba) = self.locate(size.into(), part_alignment, placement)?;
+
+        // fits because the free extent ends at the latest at last_usable
+        let last_lba = starting_lba + (size_lba - 1);
+        // Found our free slice.
+        let partition_id = self.next_partition_id()?;
+        debug!(
+            "Adding partition id: {} {:?}.  first_lba: {} last_lba: {}",
+            partition_id, part_type, starting_lba, last_lba
+        );
+
+        let part = partition::Partition {
+            part_type_guid: part_type,
+            part_guid: uuid::Uuid::new_v4(),
+            first_lba: starting_lba,
+            last_lba,
+            flags,
+            name: name.to_string(),
+            extra: vec![],
+        };
+        self.insert_partition(partition_id, part)?;
+        Ok(partition_id)
+    }
 
-            if length >= (alignment_offset_lba + size_lba) {
-                let starting_lba = starting_lba + alignment_offset_lba;
-                // Found our free slice.
-                let partition_id = self
-                    .find_next_partition_id()
-                    .unwrap_or_else(|| self.header().num_parts + 1);
-                debug!(
-                    "Adding partition id: {} {:?}.  first_lba: {} last_lba: {}",
-                    partition_id,
-                    part_type,
-                    starting_lba,
-                    starting_lba + size_lba - 1_u64
-                );
-
-                // let's try to increase the num parts
-                // because partition_id 0 will never exist the num_parts is without + 1
-                let num_parts_changes = self.header().num_parts_would_change(partition_id);
-                if num_parts_changes && !self.config.change_partition_count {
-                    return Err(GptError::PartitionCountWouldChange);
-                }
+    /// The id a new partition gets, one past the partition array if it
+    /// is full.
+    pub(crate) fn next_partition_id(&self) -> Result<u32, GptError> {
+        match self.find_next_partition_id() {
+            Some(id) => Ok(id),
+            None => self
+                .header()?
+                .num_parts
+                .checked_add(1)
+                .ok_or(GptError::OverflowPartitionCount),
+        }
+    }
 
-                let part = partition::Partition {
-                    part_type_guid: part_type,
-                    part_guid: uuid::Uuid::new_v4(),
-                    first_lba: starting_lba,
-                    last_lba: starting_lba + size_lba - 1_u64,
-                    flags,
-                    name: name.to_string(),
-                };
-                if let Some(p) = self.partitions.insert(partition_id, part.clone()) {
-                    debug!("Replacing\n{}\nwith\n{}", p, part);
-                }
-                if num_parts_changes {
-                    // update headers
-                    self.init_headers()?;
-                }
-                return Ok(partition_id);
+    /// Inserts `part` as partition `id` after checking the layout,
+    /// growing the partition array if that is allowed.
+    pub(crate) fn insert_partition(
+        &mut self,
+        id: u32,
+        part: partition::Partition,
+    ) -> Result<(), GptError> {
+        // let's try to increase the num parts
+        // because partition_id 0 will never exist the num_parts is without + 1
+        let num_parts_changes = self.header()?.num_parts_would_change(id);
+        if num_parts_changes && !self.config.change_partition_count {
+            return Err(GptError::PartitionCountWouldChange);
+        }
+
+        if !num_parts_changes {
+            self.check_partition(id, &part)?;
+            if let Some(p) = self.partitions.insert(id, part.clone()) {
+                debug!("Replacing\n{}\nwith\n{}", p, part);
             }
+            return Ok(());
+        }
+
+        // the larger partition array moves the usable lbas, so every
+        // partition is checked against the grown headers
+        let mut scratch = self.map_device(Ok)?;
+        scratch.partitions.insert(id, part);
+        scratch.init_headers()?;
+        scratch.check_layout(&scratch.partitions, None)?;
+
+        let primary_header = scratch.primary_header;
+        let backup_header = scratch.backup_header;
+        let partitions = scratch.partitions;
+        self.primary_header = primary_header;
+        self.backup_header = backup_header;
+        self.partitions = partitions;
+        Ok(())
+    }
+
+    /// The alignments (in lba) to try when placing a partition.
+    ///
+    /// An explicitly given alignment is used as is, otherwise the
+    /// configured alignment is tried before the physical block size if
+    /// `GptConfig::alignment_fallback` is set.
+    fn alignments(&self, part_alignment: Option<u64>) -> Vec<u64> {
+        if let Some(alignment) = part_alignment {
+            return vec![alignment];
+        }
+        let (alignment, physical) = (self.alignment_lba(), self.physical_block_lba());
+        if alignment == physical || !self.config.alignment_fallback {
+            vec![alignment]
+        } else {
+            vec![alignment, physical]
         }
+    }
 
-        Err(GptError::NotEnoughSpace)
+    /// The configured alignment in lba, rounded up to whole physical blocks.
+    pub fn alignment_lba(&self) -> u64 {
+        let lb_size = self.config.lb_size.as_u64();
+        let physical = self.physical_block_lba();
+        let lba = match self.config.alignment {
+            0 => 1,
+            alignment => (alignment - 1) / lb_size + 1,
+        };
+        lba.saturating_add(physical - 1) / physical * physical
+    }
+
+    /// The size of a physical block in lba, 1 if it is not larger than a
+    /// logical block.
+    pub fn physical_block_lba(&self) -> u64 {
+        (self.config.physical_block_size / self.config.lb_size.as_u64()).max(1)
     }
 
     /// create a new partition with a specific id
@@ -578,9 +994,8 @@ where
     /// a specific part_type
     /// a specific flags
     ///
-    /// ## Panics
-    /// If length is empty panics
-    /// If id zero panics
+    /// Returns `GptError::InvalidPartitionSize` if length is zero and
+    /// `GptError::InvalidPartitionId` if id is zero.
     pub fn add_partition_at(
         &mut self,
         name: &str,
@@ -590,12 +1005,19 @@ where
         part_type: partition_types::Type,
         flags: u64,
     ) -> Result<u32, GptError> {
-        assert!(length_lba > 0, "length must be greater than zero");
-        assert!(id > 0, "id must be greater than zero");
+        if length_lba == 0 {
+            return Err(GptError::InvalidPartitionSize);
+        }
+        if id == 0 {
+            return Err(GptError::InvalidPartitionId);
+        }
+        let last_lba = first_lba
+            .checked_add(length_lba - 1)
+            .ok_or(GptError::Overflow("partition end"))?;
 
         //check id
         match self.partitions.get(&id) {
-            Some(p) if p.is_used() => return Err(GptError::PartitionIdAlreadyUsed),
+            Some(p) if p.is_used() => return Err(GptError::PartitionIdAlreadyUsed(id)),
             // Allow unused ids , because we can allow to modify the part count
             _ => {
                 // will override unused partition
@@ -603,7 +1025,8 @@ where
         }
 
         for (starting_lba, length) in self.find_free_sectors() {
-            if !(first_lba >= starting_lba && length_lba <= length) {
+            // the whole partition needs to be inside the free section
+            if first_lba < starting_lba || last_lba - starting_lba >= length {
                 continue;
             }
 
@@ -614,43 +1037,194 @@ where
             );
             debug!(
                 "Adding partition id: {} {:?}.  first_lba: {} last_lba: {}",
-                id,
-                part_type,
-                first_lba,
-                first_lba + length_lba - 1_u64
+                id, part_type, first_lba, last_lba
             );
 
-            // let's try to increase the num parts
-            // because partition_id 0 will never exist the num_parts is without + 1
-            let num_parts_changes = self.header().num_parts_would_change(id);
-
-            if num_parts_changes && !self.config.change_partition_count {
-                return Err(GptError::PartitionCountWouldChange);
-            }
-
             let part = partition::Partition {
                 part_type_guid: part_type,
                 part_guid: uuid::Uuid::new_v4(),
                 first_lba,
-                last_lba: first_lba + length_lba - 1_u64,
+                last_lba,
                 flags,
                 name: name.to_string(),
+                extra: vec![],
             };
 
-            if let Some(p) = self.partitions.insert(id, part.clone()) {
-                debug!("Replacing\n{}\nwith\n{}", p, part);
-            }
+            self.insert_partition(id, part)?;
+            return Ok(id);
+        }
 
-            if num_parts_changes {
-                // update headers
-                self.init_headers()?;
+        //given segment is illegal
+        Err(self.not_enough_space(length_lba))
+    }
+
+    /// Change the size of the partition with the given id, keeping
+    /// its first lba.
+    ///
+    /// The size is given in bytes and rounded up to whole logical blocks,
+    /// or as a `PartitionSize` where percentages and the remaining space
+    /// refer to the space up to the next partition.
+    /// If an alignment (in lba) is given the size is rounded up further so
+    /// the partition ends right before an aligned lba, otherwise it ends
+    /// at the end of a physical block. The new extent may
+    /// not overlap the next partition or go beyond the last usable lba.
+    ///
+    /// Shrinking a partition is allowed, the data at the end is not
+    /// touched. Returns the new `(first_lba, last_lba)`.
+    pub fn resize_partition(
+        &mut self,
+        id: u32,
+        new_size: impl Into<partition_size::PartitionSize>,
+        part_alignment: Option<u64>,
+    ) -> Result<(u64, u64), GptError> {
+        let new_size = new_size.into();
+        let first_lba = self.used_partition(id)?.first_lba;
+        let limit = self.partition_end_limit(id, first_lba)?;
+        let available = limit
+            .checked_sub(first_lba)
+            .map_or(0, |len| len.saturating_add(1));
+        let free: &[(u64, u64)] = match available {
+            0 => &[],
+            _ => &[(first_lba, available)],
+        };
+
+        let size_lba = new_size.to_lba(self.config.lb_size.as_u64(), free)?;
+        let mut last_lba = first_lba
+            .checked_add(size_lba - 1)
+            .ok_or(GptError::Overflow("partition end"))?;
+        let alignment = part_alignment.unwrap_or_else(|| self.physical_block_lba());
+        if alignment > 1 {
+            let end = last_lba.saturating_add(1);
+            last_lba = last_lba
+                .checked_add((alignment - end % alignment) % alignment)
+                .ok_or(GptError::Overflow("partition end"))?;
+            // relative sizes rather shrink than not fit
+            let relative = matches!(
+                new_size,
+                partition_size::PartitionSize::PercentOfFree(_)
+                    | partition_size::PartitionSize::Remaining
+            );
+            if relative && last_lba > limit && last_lba - first_lba >= alignment {
+                last_lba -= alignment;
             }
+        }
 
-            return Ok(id);
+        if last_lba > limit {
+            return Err(GptError::NotEnoughSpace {
+                requested: last_lba - first_lba + 1,
+                largest_free: available,
+            });
         }
 
-        //given segment is illegal
-        Err(GptError::NotEnoughSpace)
+        self.set_partition_end(id, last_lba)
+    }
+
+    /// Grow the partition with the given id up to the next partition
+    /// or the last usable lba, like `growpart`.
+    ///
+    /// The partition ends right before an lba aligned to the given
+    /// alignment (in lba) or the configured one, see `GptConfig::alignment`,
+    /// if that is possible without shrinking it.
+    /// Returns the new `(first_lba, last_lba)`.
+    pub fn grow_partition_to_fill(
+        &mut self,
+        id: u32,
+        part_alignment: Option<u64>,
+    ) -> Result<(u64, u64), GptError> {
+        let part = self.used_partition(id)?;
+        let (first_lba, old_last_lba) = (part.first_lba, part.last_lba);
+
+        let limit = self.partition_end_limit(id, first_lba)?;
+        // only the end gets aligned, as far as possible
+        let alignments = match part_alignment {
+            Some(alignment) => vec![alignment],
+            None => vec![self.alignment_lba(), self.physical_block_lba()],
+        };
+        let last_lba = alignments
+            .into_iter()
+            .map(|alignment| match alignment {
+                0 | 1 => limit,
+                alignment => limit.saturating_sub(limit.saturating_add(1) % alignment),
+            })
+            .find(|last_lba| *last_lba >= old_last_lba)
+            .unwrap_or(old_last_lba);
+
+        self.set_partition_end(id, last_lba)
+    }
+
+    fn used_partition(&self, id: u32) -> Result<&partition::Partition, GptError> {
+        self.partitions
+            .get(&id)
+            .filter(|p| p.is_used())
+            .ok_or(GptError::PartitionNotFound(id))
+    }
+
+    /// The error returned if `requested` lba could not be found,
+    /// reporting the largest free extent.
+    pub(crate) fn not_enough_space(&self, requested: u64) -> GptError {
+        let largest_free = self
+            .find_free_sectors()
+            .iter()
+            .map(|(_, length)| *length)
+            .max()
+            .unwrap_or(0);
+        GptError::NotEnoughSpace {
+            requested,
+            largest_free,
+        }
+    }
+
+    /// The last lba a partition starting at `first_lba` can use without
+    /// overlapping the next partition, ignoring the partition `id`.
+    fn partition_end_limit(&self, id: u32, first_lba: u64) -> Result<u64, GptError> {
+        Ok(self
+            .partitions
+            .iter()
+            .filter(|(i, p)| **i != id && p.is_used() && p.first_lba > first_lba)
+            .map(|(_, p)| p.first_lba - 1)
+            .fold(self.header()?.last_usable, u64::min))
+    }
+
+    fn set_partition_end(&mut self, id: u32, last_lba: u64) -> Result<(u64, u64), GptError> {
+        let mut part = self.used_partition(id)?.clone();
+        debug!(
+            "Resizing partition id: {}. last_lba: {} -> {}",
+            id, part.last_lba, last_lba
+        );
+        part.last_lba = last_lba;
+        self.check_partition(id, &part)?;
+
+        let extent = (part.first_lba, part.last_lba);
+        self.partitions.insert(id, part);
+        Ok(extent)
+    }
+
+    /// Checks the layout if `part` would become the partition `id`.
+    ///
+    /// Problems of other partitions are ignored, so a damaged table can
+    /// still be fixed one partition at a time.
+    pub(crate) fn check_partition(
+        &self,
+        id: u32,
+        part: &partition::Partition,
+    ) -> Result<(), GptError> {
+        let mut partitions = self.partitions.clone();
+        partitions.insert(id, part.clone());
+        self.check_layout(&partitions, Some(id))
+    }
+
+    /// The validator every change of the partitions goes through.
+    ///
+    /// Fails with `GptError::InvalidLayout` if a used partition ends before
+    /// it starts, is outside the usable lbas, overlaps another partition
+    /// or has the same GUID as another partition. If an id is given only
+    /// problems concerning that partition are reported.
+    fn check_layout(
+        &self,
+        partitions: &BTreeMap<u32, partition::Partition>,
+        id: Option<u32>,
+    ) -> Result<(), GptError> {
+        verify::check_layout(self.header()?, partitions, id).map_err(GptError::InvalidLayout)
     }
 
     /// calculate sector alignment based on the current partitions
@@ -658,27 +1232,43 @@ where
     /// return 0 if no partitions existed
     /// return 1 if partitions are not well aligned ( can be seen as 1 alignment )
     /// MAX ALIGNMENT IS 2048 ! And the result is ok ONLY if existed partitions is well aligned
+    ///
+    /// New partitions are aligned according to `GptConfig::alignment`,
+    /// this only inspects the existing ones.
     pub fn calculate_alignment(&self) -> u64 {
         if self.partitions.is_empty() {
             return 0;
         }
 
         const MAX_ALIGN: u64 = 2048;
-        let mut align = MAX_ALIGN;
-        let mut exponent = (align as f64).log2() as u32;
-
-        for partition in self.partitions.values().filter(|p| p.is_used()) {
-            loop {
-                align = u64::pow(2, exponent);
-                if (partition.first_lba % align) == 0 {
-                    break;
-                }
+        // the largest power of two dividing every first lba
+        self.partitions
+            .values()
+            .filter(|p| p.is_used())
+            .map(|p| 1 << p.first_lba.trailing_zeros().min(MAX_ALIGN.trailing_zeros()))
+            .fold(MAX_ALIGN, u64::min)
+    }
 
-                exponent -= 1;
-            }
+    /// Renumber the partitions so their ids follow their position on
+    /// the disk, like the sort command of gdisk.
+    ///
+    /// Unused entries are dropped. Returns a map from the old to the new
+    /// id of every partition.
+    pub fn sort_partitions(&mut self) -> BTreeMap<u32, u32> {
+        let mut parts: Vec<_> = std::mem::take(&mut self.partitions)
+            .into_iter()
+            .filter(|(_, p)| p.is_used())
+            .collect();
+        parts.sort_by_key(|(_, p)| p.first_lba);
+
+        let mut ids = BTreeMap::new();
+        for (new_id, (old_id, part)) in (1..).zip(parts) {
+            debug!("Renumbering partition {} to {}", old_id, new_id);
+            ids.insert(old_id, new_id);
+            self.partitions.insert(new_id, part);
         }
 
-        align
+        ids
     }
 
     /// Remove partition from this disk.
@@ -706,37 +1296,46 @@ where
     /// Find free space on the disk.
     /// Returns a tuple of (starting_lba, length in lba's).
     pub fn find_free_sectors(&self) -> Vec<(u64, u64)> {
-        let header = self.header();
+        let header = match self.header() {
+            Ok(header) => header,
+            Err(_) => return vec![],
+        };
 
         trace!("first_usable: {}", header.first_usable);
-        // a list of boundaries between partitions each point is a used lba
-        let mut disk_positions = vec![header.first_usable - 1];
-        for part in self.partitions().iter().filter(|p| p.1.is_used()) {
-            trace!(
-                "used partition: ({}, {})",
-                part.1.first_lba,
-                part.1.last_lba
-            );
-            disk_positions.push(part.1.first_lba);
-            disk_positions.push(part.1.last_lba);
-        }
-        disk_positions.push(header.last_usable + 1);
-        trace!("last_usable: {}", header.last_usable);
-        disk_positions.sort_unstable();
-
-        disk_positions
-            // Walk through the LBA's in chunks of 2 (ending, starting).
-            .chunks_exact(2)
-            // Add 1 to the ending and then subtract the starting if NOT the first usable sector
-            .filter_map(|p| {
-                // free lba
-                let start = p[0] + 1;
-                let end = p[1] - 1;
-
-                #[allow(clippy::unnecessary_lazy_evaluations)]
-                (start <= end).then(|| (start, end - start + 1))
+        let mut used: Vec<_> = self
+            .partitions()
+            .values()
+            .filter(|p| p.is_used())
+            .map(|p| {
+                trace!("used partition: ({}, {})", p.first_lba, p.last_lba);
+                (p.first_lba, p.last_lba)
             })
-            .collect()
+            .collect();
+        used.sort_unstable();
+        trace!("last_usable: {}", header.last_usable);
+
+        // walk through the partitions, `next` is the first lba which
+        // might be free, None if the end of the disk was reached
+        let mut free = vec![];
+        let mut next = Some(header.first_usable);
+        for (first_lba, last_lba) in used {
+            let start = match next {
+                Some(start) => start,
+                None => break,
+            };
+            let end = first_lba.min(header.last_usable.saturating_add(1));
+            if start < end {
+                free.push((start, end - start));
+            }
+            next = last_lba.checked_add(1).map(|n| n.max(start));
+        }
+        if let Some(start) = next {
+            if start <= header.last_usable {
+                free.push((start, header.last_usable - start + 1));
+            }
+        }
+
+        free
     }
 
     /// Find next highest partition id.
@@ -750,7 +1349,7 @@ where
         }
 
         // get the first free partition slot
-        for i in 1..=self.header().num_parts {
+        for i in 1..=self.header().map_or(0, |h| h.num_parts) {
             // todo should unused ones be included?
             match self.partitions.get(&i) {
                 Some(p) if !p.is_used() => return Some(i),
@@ -787,21 +1386,40 @@ where
     ///
     /// No changes are recorded to disk until `write()` is called.
     ///
-    /// ## Note
-    /// you need to make sure that all values in the partition are set correctly
-    ///
-    /// ## Panics
-    /// If a partition 0 exists
+    /// Returns `GptError::InvalidPartitionId` if a partition 0 exists and
+    /// `GptError::InvalidLayout` if partitions overlap, are outside the
+    /// usable lbas or share a GUID.
     pub fn update_partitions(
         &mut self,
         pp: BTreeMap<u32, partition::Partition>,
     ) -> Result<(), GptError> {
-        assert!(!pp.contains_key(&0));
+        if pp.contains_key(&0) {
+            return Err(GptError::InvalidPartitionId);
+        }
+        self.check_layout(&pp, None)?;
+
+        self.update_partitions_unchecked(pp)
+    }
 
-        // TODO(lucab): validate partitions.
-        let num_parts = pp.len() as u32;
+    /// Update current partition table without validating the layout.
+    ///
+    /// Unlike `update_partitions` this accepts overlapping partitions,
+    /// partitions outside the usable lbas and duplicate GUIDs, for example
+    /// to restore a damaged table exactly as it was found. Only use this
+    /// if you know the table is what you want.
+    ///
+    /// No changes are recorded to disk until `write()` is called.
+    pub fn update_partitions_unchecked(
+        &mut self,
+        pp: BTreeMap<u32, partition::Partition>,
+    ) -> Result<(), GptError> {
+        if pp.contains_key(&0) {
+            return Err(GptError::InvalidPartitionId);
+        }
 
-        let num_parts_changes = self.header().num_parts_would_change(num_parts);
+        let max_id = pp.keys().next_back().copied().unwrap_or(0);
+
+        let num_parts_changes = self.header()?.num_parts_would_change(max_id);
         if num_parts_changes && !self.config.change_partition_count {
             return Err(GptError::PartitionCountWouldChange);
         }
@@ -811,26 +1429,174 @@ where
         self.init_headers()
     }
 
+    /// Change the number of entries in the partition array.
+    ///
+    /// The first and last usable lba of both headers are recomputed to fit
+    /// the new array, which can also be smaller than the usual 128 entries.
+    /// Fails with `GptError::OverflowPartitionCount` if a partition id
+    /// would not fit in the array and with `GptError::PartitionsDoNotFit`
+    /// if a partition would overlap a larger array, such partitions can be
+    /// moved first with `move_partition`.
+    ///
+    /// No changes are recorded to disk until `write()` is called.
+    pub fn set_entry_capacity(&mut self, num_parts: u32) -> Result<(), GptError> {
+        if self.header()?.num_parts == num_parts {
+            return Ok(());
+        }
+        let max_id = self
+            .partitions
+            .iter()
+            .filter(|(_, p)| p.is_used())
+            .map(|(id, _)| *id)
+            .max()
+            .unwrap_or(0);
+        if max_id > num_parts {
+            return Err(GptError::OverflowPartitionCount);
+        }
+
+        let mut builder = self.header_builder(self.header()?)?;
+        builder.num_parts(num_parts).first_usable(0).last_usable(0);
+        let primary = builder.clone().primary(true).build(self.config.lb_size)?;
+
+        let ids: Vec<u32> = self
+            .partitions
+            .iter()
+            .filter(|(_, p)| {
+                p.is_used()
+                    && (p.first_lba < primary.first_usable || p.last_lba > primary.last_usable)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        if !ids.is_empty() {
+            return Err(GptError::PartitionsDoNotFit(ids));
+        }
+
+        debug!(
+            "changing partition array to {} entries, usable lba {}..={}",
+            num_parts, primary.first_usable, primary.last_usable
+        );
+        // drop unused entries which do not fit anymore
+        self.partitions.retain(|id, _| *id <= num_parts);
+        if !self.config.readonly_backup {
+            let backup = builder.primary(false).build(self.config.lb_size)?;
+            self.backup_header = Ok(backup);
+        }
+        self.primary_header = Ok(primary);
+
+        Ok(())
+    }
+
+    /// Returns a builder for headers copied from `from` which keeps the
+    /// locations of both partition arrays, see `primary_array_start` and
+    /// `backup_array_start`.
+    pub(crate) fn header_builder(
+        &self,
+        from: &header::Header,
+    ) -> Result<header::HeaderBuilder, GptError> {
+        let mut builder = header::HeaderBuilder::from_header(from);
+        builder
+            .primary_part_start(self.primary_array_start()?)
+            .backup_part_start(self.backup_array_start()?);
+        Ok(builder)
+    }
+
+    /// The first lba of the primary partition array to keep when the
+    /// headers get rebuilt, 0 meaning directly after the primary header.
+    ///
+    /// An array at that default location stays there, so it fits a new
+    /// array size. If the primary header is missing the location is taken
+    /// from `GptConfig::primary_part_start`, or is the default one if the
+    /// backup header has its first usable lba directly after an array
+    /// there. Otherwise this fails with
+    /// `GptError::UnknownPartitionArrayLocation`.
+    pub(crate) fn primary_array_start(&self) -> Result<u64, GptError> {
+        let lb_size = self.config.lb_size;
+        match (&self.primary_header, &self.backup_header) {
+            (Ok(primary), _) if primary.current_lba.checked_add(1) == Some(primary.part_start) => {
+                Ok(0)
+            }
+            (Ok(primary), _) => Ok(primary.part_start),
+            _ if self.config.primary_part_start != 0 => Ok(self.config.primary_part_start),
+            (_, Ok(backup))
+                if backup
+                    .backup_lba
+                    .checked_add(1 + geometry::part_array_lbs(backup, lb_size))
+                    == Some(backup.first_usable) =>
+            {
+                Ok(0)
+            }
+            _ => Err(GptError::UnknownPartitionArrayLocation(
+                GptStructure::PrimaryEntries,
+            )),
+        }
+    }
+
+    /// The first lba of the backup partition array to keep when the
+    /// headers get rebuilt, 0 meaning directly before the backup header.
+    ///
+    /// Like `primary_array_start`, if the backup header is missing the
+    /// location is taken from `GptConfig::backup_part_start`, or is the
+    /// default one if the primary header has its last usable lba directly
+    /// in front of an array there.
+    pub(crate) fn backup_array_start(&self) -> Result<u64, GptError> {
+        let lb_size = self.config.lb_size;
+        match (&self.backup_header, &self.primary_header) {
+            (Ok(backup), _)
+                if backup
+                    .part_start
+                    .checked_add(geometry::part_array_lbs(backup, lb_size))
+                    == Some(backup.current_lba) =>
+            {
+                Ok(0)
+            }
+            (Ok(backup), _) => Ok(backup.part_start),
+            _ if self.config.backup_part_start != 0 => Ok(self.config.backup_part_start),
+            (_, Ok(primary))
+                if primary
+                    .last_usable
+                    .checked_add(1 + geometry::part_array_lbs(primary, lb_size))
+                    == Some(primary.backup_lba) =>
+            {
+                Ok(0)
+            }
+            _ => Err(GptError::UnknownPartitionArrayLocation(
+                GptStructure::BackupEntries,
+            )),
+        }
+    }
+
     /// Makes sure there exists a primary header and if allowed also creates the backup
     /// header.
     pub(crate) fn init_headers(&mut self) -> Result<(), GptError> {
         let bak = header::find_backup_lba(&mut self.device, self.config.lb_size)?;
-        let num_parts = self.partitions.len() as u32;
-
-        let h1 = header::HeaderBuilder::from_maybe_header(self.try_header())
+        // the partition array only ever grows here, see `set_entry_capacity`
+        let num_parts = self
+            .header()
+            .map(|h| h.num_parts)
+            .unwrap_or(header::MIN_NUM_PARTS)
+            .max(self.partitions.keys().next_back().copied().unwrap_or(0));
+
+        let mut builder = match self.header() {
+            Ok(header) => self.header_builder(header)?,
+            Err(_) => {
+                let mut builder = header::HeaderBuilder::new();
+                builder
+                    .primary_part_start(self.config.primary_part_start)
+                    .backup_part_start(self.config.backup_part_start);
+                builder
+            }
+        };
+        builder
             .num_parts(num_parts)
             .backup_lba(bak)
-            .disk_guid(self.guid)
-            .primary(true)
-            .build(self.config.lb_size)?;
-        let header = self.primary_header.insert_ok(h1);
+            .disk_guid(self.guid);
+        let h1 = builder.clone().primary(true).build(self.config.lb_size)?;
 
         if !self.config.readonly_backup {
-            let h2 = header::HeaderBuilder::from_header(header)
-                .primary(false)
-                .build(self.config.lb_size)?;
+            let h2 = builder.primary(false).build(self.config.lb_size)?;
             self.backup_header = Ok(h2);
         }
+        self.primary_header = Ok(h1);
 
         Ok(())
     }
@@ -856,6 +1622,40 @@ where
     /// and partitions entries on disk. All writes are flushed
     /// to disk before returning.
     ///
+    /// Each partition is written to the entry slot matching its id
+    /// (partition `n` is stored at index `n - 1` of the partition array),
+    /// every other slot is zeroed. This means the ids returned by
+    /// `partitions()` are preserved across a write and a reopen,
+    /// gaps included.
+    ///
+    /// ## Crash consistency
+    /// The table is written in two stages, so that at any point in time
+    /// at least one complete copy of it is valid on disk:
+    /// 1. the backup partition array, then the backup header
+    /// 2. the primary partition array, then the primary header
+    ///
+    /// After each stage the device is flushed (and synced if it is a
+    /// `fs::File`). If `GptConfig::verify_writes` is set, the stage is then
+    /// read back and its header and partition array CRCs are checked before
+    /// continuing, a mismatch fails the write with
+    /// `GptError::WriteVerificationFailed`.
+    ///
+    /// If the write is interrupted during the first stage the old primary
+    /// table is still intact, during the second stage the new backup table
+    /// is. Opening the disk (without `only_valid_headers`) picks whichever
+    /// copy is valid.
+    ///
+    /// ## Transactions
+    /// If `GptConfig::journal` is set, every block touched by the write
+    /// is saved to the journal before the write starts. If any step fails
+    /// the original bytes are restored and the error is returned.
+    ///
+    /// The state of the journal is updated after the write or the
+    /// rollback. If only that update fails the result of the write is
+    /// returned anyway and the journal file stays pending. Rolling it
+    /// forward after a successful write, or back after a failed one,
+    /// with `journal::recover_from_journal` leaves the disk as it is.
+    ///
     /// ## Note
     /// If you provided you're own DiskDevice you need to make sure
     /// that the device is flushed to disk for example via `sync_all`.
@@ -867,105 +1667,238 @@ where
             return Err(GptError::ReadOnly);
         }
 
+        let journal_path = match &self.config.journal {
+            journal::JournalMode::Disabled => return self.write_staged(),
+            journal::JournalMode::Memory => None,
+            journal::JournalMode::File(path) => Some(path.clone()),
+        };
+        self.write_journaled(journal_path.as_deref())
+    }
+
+    /// Writes the table while keeping an undo journal, rolling
+    /// back if anything fails.
+    fn write_journaled(&mut self, path: Option<&path::Path>) -> Result<(), GptError> {
+        let mut journal = self.record_journal()?;
+        if let Some(path) = path {
+            debug!("Writing journal to {}", path.display());
+            journal.persist(path)?;
+        }
+
+        let primary_header = self
+            .primary_header
+            .as_ref()
+            .map_err(|e| e.lossy_clone())
+            .cloned();
+        let backup_header = self
+            .backup_header
+            .as_ref()
+            .map_err(|e| e.lossy_clone())
+            .cloned();
+
+        match self.write_staged() {
+            Ok(()) => {
+                // the table is on disk, a journal left pending can still
+                // be rolled forward safely
+                if let Err(e) = journal.set_state(journal::JournalState::Committed, path) {
+                    debug!("failed to mark the journal as committed: {}", e);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                debug!("write failed: {}, rolling back", e);
+                journal
+                    .rollback(&mut self.device)
+                    .and_then(|_| match self.sync_all {
+                        Some(sync_all) => sync_all(&mut self.device),
+                        None => Ok(()),
+                    })
+                    .map_err(GptError::RollbackFailed)?;
+                self.primary_header = primary_header;
+                self.backup_header = backup_header;
+                if let Err(e) = journal.set_state(journal::JournalState::RolledBack, path) {
+                    debug!("failed to mark the journal as rolled back: {}", e);
+                }
+
+                Err(e)
+            }
+        }
+    }
+
+    /// Returns a copy of this disk which records all writes instead
+    /// of applying them to the device.
+    pub(crate) fn recording(&mut self) -> io::Result<GptDisk<record::RecordingDevice<'_, D>>> {
+        self.map_device(record::RecordingDevice::new)
+    }
+
+    /// A copy of the in-memory state using a device wrapping this one,
+    /// to try out changes without touching this disk.
+    pub(crate) fn map_device<'a, N>(
+        &'a mut self,
+        wrap: impl FnOnce(&'a mut D) -> io::Result<N>,
+    ) -> io::Result<GptDisk<N>> {
+        Ok(GptDisk {
+            config: self.config.clone(),
+            device: wrap(&mut self.device)?,
+            guid: self.guid,
+            primary_header: self
+                .primary_header
+                .as_ref()
+                .map_err(|e| e.lossy_clone())
+                .cloned(),
+            backup_header: self
+                .backup_header
+                .as_ref()
+                .map_err(|e| e.lossy_clone())
+                .cloned(),
+            partitions: self.partitions.clone(),
+            grown_to: self.grown_to,
+            sync_all: None,
+        })
+    }
+
+    /// Runs the write against a recording device and returns
+    /// every touched block with its old and new content.
+    fn record_journal(&mut self) -> Result<journal::Journal, GptError> {
+        let lb_size = self.config.lb_size;
+        let mut recording = self.recording()?;
+
+        // the protective MBR is always part of the journal
+        let mut lba0 = vec![0; lb_size.as_usize()];
+        recording.device.seek(io::SeekFrom::Start(0))?;
+        recording.device.read_exact(&mut lba0)?;
+        recording.device.seek(io::SeekFrom::Start(0))?;
+        recording.device.write_all(&lba0)?;
+
+        recording.write_staged()?;
+        let entries = recording
+            .device
+            .into_regions(lb_size.as_u64())?
+            .into_iter()
+            .map(|r| journal::JournalEntry {
+                offset: r.offset,
+                old: r.old,
+                new: r.new,
+            })
+            .collect();
+
+        Ok(journal::Journal::new(entries))
+    }
+
+    /// Writes the backup and then the primary table.
+    pub(crate) fn write_staged(&mut self) -> Result<(), GptError> {
         debug!("Computing new headers");
         trace!("old primary header: {:?}", self.primary_header);
         trace!("old backup header: {:?}", self.backup_header);
         let bak = header::find_backup_lba(&mut self.device, self.config.lb_size)?;
         trace!("old backup lba: {}", bak);
 
-        let primary_header = header::HeaderBuilder::from_header(self.header())
-            .primary(true)
-            .build(self.config.lb_size)?;
-        let primary_header = self.primary_header.insert_ok(primary_header);
+        let mut builder = self.header_builder(self.header()?)?;
+        let mut primary_header = builder.clone().primary(true).build(self.config.lb_size)?;
+        self.check_in_device(&primary_header)?;
 
-        let backup_header = if !self.config.readonly_backup {
-            let header = header::HeaderBuilder::from_header(primary_header)
-                .primary(false)
-                .build(self.config.lb_size)?;
+        if !self.config.readonly_backup {
+            let mut backup_header = builder.primary(false).build(self.config.lb_size)?;
+            self.check_in_device(&backup_header)?;
 
-            Some(self.backup_header.insert_ok(header))
-        } else {
-            None
-        };
+            debug!("Writing backup partition array and header");
+            self.write_partition_array(&backup_header)?;
+            backup_header.write_backup(&mut self.device, self.config.lb_size)?;
+            self.sync()?;
+            let backup_header = self.backup_header.insert_ok(backup_header).clone();
+            if self.config.verify_writes {
+                self.verify_written(&backup_header, GptStructure::BackupHeader)?;
+            }
+        }
+
+        debug!("Writing primary partition array and header");
+        self.write_partition_array(&primary_header)?;
+        primary_header.write_primary(&mut self.device, self.config.lb_size)?;
+        self.sync()?;
+        let primary_header = self.primary_header.insert_ok(primary_header).clone();
+        if self.config.verify_writes {
+            self.verify_written(&primary_header, GptStructure::PrimaryHeader)?;
+        }
 
-        // Write all of the used partitions at the start of the partition array.
+        Ok(())
+    }
+
+    /// Writes the partition array described by the given header.
+    ///
+    /// Every used partition is written to the slot matching its id (`id - 1`),
+    /// all other slots are zeroed (ensures any newly deleted partitions are
+    /// truly removed from disk, etc.)
+    fn write_partition_array(&mut self, header: &header::Header) -> Result<(), GptError> {
         let mut next_partition_index = 0;
-        for (part_idx, partition) in self
-            .partitions
-            .clone()
-            .iter()
-            .filter(|p| p.1.is_used())
-            .enumerate()
-        {
+        for (id, partition) in self.partitions.iter().filter(|p| p.1.is_used()) {
+            let part_idx = id.checked_sub(1).ok_or(GptError::InvalidPartitionId)?;
+
             // don't allow us to overflow partition array...
             // todo this should not be possible since we
             // check in add partition that it is valid
-            if part_idx >= primary_header.num_parts as usize {
+            if part_idx >= header.num_parts {
                 return Err(GptError::OverflowPartitionCount);
             }
 
-            // Write to primary partition array
-            partition.1.write_to_device(
+            // Zero the unused slots in front of this partition
+            partition::Partition::write_zero_entries_to_device(
                 &mut self.device,
-                part_idx as u64,
-                primary_header.part_start,
+                next_partition_index.into(),
+                u64::from(part_idx - next_partition_index),
+                header.part_start,
                 self.config.lb_size,
-                primary_header.part_size,
+                header.part_size,
+            )?;
+            partition.write_to_device(
+                &mut self.device,
+                part_idx.into(),
+                header.part_start,
+                self.config.lb_size,
+                header.part_size,
             )?;
-            // IMPORTANT: must also write it to the backup header if it uses a different
-            // area to store the partition array; otherwise backup header will not point
-            // to an up to date partition array on disk.
-            if let Some(backup_header) = &backup_header {
-                if part_idx >= backup_header.num_parts as usize {
-                    return Err(GptError::OverflowPartitionCount);
-                }
-
-                if primary_header.part_start != backup_header.part_start {
-                    partition.1.write_to_device(
-                        &mut self.device,
-                        part_idx as u64,
-                        backup_header.part_start,
-                        self.config.lb_size,
-                        backup_header.part_size,
-                    )?;
-                }
-            }
             next_partition_index = part_idx + 1;
         }
 
-        // Next, write zeros to the rest of the primary/backup partition array
-        // (ensures any newly deleted partitions are truly removed from disk, etc.)
+        // Next, write zeros to the rest of the partition array
         // NOTE: we should never underflow here because of boundary checking in loop above.
         partition::Partition::write_zero_entries_to_device(
             &mut self.device,
-            next_partition_index as u64,
-            (primary_header.num_parts as u64)
-                .checked_sub(next_partition_index as u64)
-                .unwrap(),
-            primary_header.part_start,
+            next_partition_index.into(),
+            u64::from(header.num_parts - next_partition_index),
+            header.part_start,
             self.config.lb_size,
-            primary_header.part_size,
+            header.part_size,
         )?;
-        if let Some(backup_header) = &backup_header {
-            partition::Partition::write_zero_entries_to_device(
-                &mut self.device,
-                next_partition_index as u64,
-                (backup_header.num_parts as u64)
-                    .checked_sub(next_partition_index as u64)
-                    .unwrap(),
-                backup_header.part_start,
-                self.config.lb_size,
-                backup_header.part_size,
-            )?;
-        }
 
-        if let Some(backup_header) = backup_header {
-            debug!("Writing backup header");
-            backup_header.write_backup(&mut self.device, self.config.lb_size)?;
+        Ok(())
+    }
+
+    /// Fails if the header or its partition array would not be
+    /// written inside the device.
+    pub(crate) fn check_in_device(&mut self, header: &header::Header) -> Result<(), GptError> {
+        let lb_size = self.config.lb_size.as_u64();
+        let array_len = u64::from(header.num_parts) * u64::from(header.part_size);
+        let header_end = header
+            .current_lba
+            .checked_add(1)
+            .and_then(|lbs| lbs.checked_mul(lb_size));
+        let array_end = header
+            .part_start
+            .checked_mul(lb_size)
+            .and_then(|start| start.checked_add(array_len));
+
+        let end = match (header_end, array_end) {
+            (Some(h), Some(a)) => h.max(a),
+            _ => return Err(GptError::Overflow("header or partition array end")),
+        };
+        let device_size = self.device.seek(io::SeekFrom::End(0))?;
+        if end > device_size {
+            return Err(GptError::BeyondDeviceEnd { end, device_size });
         }
-        debug!("Writing primary header");
-        primary_header.write_primary(&mut self.device, self.config.lb_size)?;
+        Ok(())
+    }
 
+    /// Flushes the device and if possible makes sure the data reached the disk.
+    fn sync(&mut self) -> Result<(), GptError> {
         self.device.flush()?;
 
         if let Some(sync_all) = self.sync_all {
@@ -974,6 +1907,43 @@ where
 
         Ok(())
     }
+
+    /// Reads back a header which was just written and checks that it
+    /// and the partition array it points to match what we expect.
+    ///
+    /// `structure` is either the primary or the backup header.
+    fn verify_written(
+        &mut self,
+        expected: &header::Header,
+        structure: GptStructure,
+    ) -> Result<(), GptError> {
+        let entries = match structure {
+            GptStructure::PrimaryHeader => GptStructure::PrimaryEntries,
+            _ => GptStructure::BackupEntries,
+        };
+
+        let offset = expected
+            .current_lba
+            .checked_mul(self.config.lb_size.into())
+            .ok_or(GptError::Overflow("verifying header: lba * lbs"))?;
+        match header::file_read_header(&mut self.device, offset) {
+            Ok(h) if h == *expected => {}
+            Ok(_)
+            | Err(HeaderError::InvalidGptSignature | HeaderError::InvalidCRC32Checksum { .. }) => {
+                return Err(GptError::WriteVerificationFailed(structure))
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let parts_checksum =
+            header::partentry_checksum(&mut self.device, expected, self.config.lb_size)?;
+        if parts_checksum != expected.crc32_parts {
+            return Err(GptError::WriteVerificationFailed(entries));
+        }
+
+        trace!("verified {}", structure);
+        Ok(())
+    }
 }
 
 fn file_sync_all(device: &mut fs::File) -> io::Result<()> {
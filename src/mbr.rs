@@ -16,11 +16,16 @@ pub enum MBRError {
     /// Generic IO Error
     Io(io::Error),
     /// The provided buffer does not match the expected mbr length
-    InvalidMBRLength,
-    /// invalid MBR signature
-    InvalidMBRSignature,
-    /// Invalid Partition Length != 16
-    InvalidPartitionLength,
+    InvalidMBRLength {
+        /// The length of an mbr, the logical block size
+        expected: usize,
+        /// The length of the provided buffer
+        actual: usize,
+    },
+    /// invalid MBR signature, contains the signature which was found
+    InvalidMBRSignature([u8; 2]),
+    /// Invalid Partition Length != 16, contains the provided length
+    InvalidPartitionLength(usize),
     /// Somthing Overflowed or Underflowed
     /// This will never occur when dealing with sane values
     Overflow(&'static str),
@@ -32,27 +37,44 @@ impl From<io::Error> for MBRError {
     }
 }
 
-impl std::error::Error for MBRError {}
+impl std::error::Error for MBRError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl fmt::Display for MBRError {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         use MBRError::*;
-        let desc = match self {
-            Io(e) => return write!(fmt, "MBR IO Error: {e}"),
-            InvalidMBRLength => "The provided buffer does not match the expected mbr length",
-            InvalidMBRSignature => "Invalid MBR signature",
-            InvalidPartitionLength => "Invalid Partition length expected 16",
-            Overflow(m) => return write!(fmt, "MBR error Overflow: {m}"),
-        };
-        write!(fmt, "{desc}")
+        match self {
+            Io(e) => write!(fmt, "MBR IO Error: {e}"),
+            InvalidMBRLength { expected, actual } => write!(
+                fmt,
+                "The provided buffer does not match the expected mbr length \
+                (expected {expected} got {actual})"
+            ),
+            InvalidMBRSignature(sig) => {
+                write!(fmt, "Invalid MBR signature {:02x}{:02x}", sig[0], sig[1])
+            }
+            InvalidPartitionLength(len) => {
+                write!(fmt, "Invalid Partition length {len} expected 16")
+            }
+            Overflow(m) => write!(fmt, "MBR error Overflow: {m}"),
+        }
     }
 }
 
 const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
 
 /// Protective MBR, as defined by GPT.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ProtectiveMBR {
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_hex"))]
     bootcode: [u8; 440],
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_hex"))]
     disk_signature: [u8; 4],
     unknown: u16,
     partitions: [PartRecord; 4],
@@ -112,7 +134,10 @@ impl ProtectiveMBR {
         let totlen: u64 = sector_size.into();
 
         if buf.len() != (totlen as usize) {
-            return Err(MBRError::InvalidMBRLength);
+            return Err(MBRError::InvalidMBRLength {
+                expected: totlen as usize,
+                actual: buf.len(),
+            });
         }
 
         let mut bytes = Bytes::from(buf);
@@ -125,13 +150,11 @@ impl ProtectiveMBR {
             *p = PartRecord::from_bytes(bytes.read(16))?;
         }
 
-        assert_eq!(simple_bytes::BytesSeek::position(&bytes), 510);
-
         pmbr.signature.copy_from_slice(bytes.read(2));
         if pmbr.signature == MBR_SIGNATURE {
             Ok(pmbr)
         } else {
-            Err(MBRError::InvalidMBRSignature)
+            Err(MBRError::InvalidMBRSignature(pmbr.signature))
         }
     }
 
@@ -264,6 +287,7 @@ impl ProtectiveMBR {
 
 /// A partition record, MBR-style.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PartRecord {
     /// Bit 7 set if partition is active (bootable)
     pub boot_indicator: u8,
@@ -324,7 +348,7 @@ impl PartRecord {
     /// Parse input bytes into a Partition Record.
     pub fn from_bytes(buf: &[u8]) -> Result<Self, MBRError> {
         if buf.len() != 16 {
-            return Err(MBRError::InvalidPartitionLength);
+            return Err(MBRError::InvalidPartitionLength(buf.len()));
         }
 
         let mut bytes = Bytes::from(buf);
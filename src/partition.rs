@@ -8,7 +8,7 @@ use crc::Crc;
 use std::collections::BTreeMap;
 use std::fmt;
 use std::fs::{File, OpenOptions};
-use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 
 use crate::disk;
@@ -18,8 +18,102 @@ use crate::DiskDevice;
 
 use simple_bytes::{Bytes, BytesRead};
 
+#[non_exhaustive]
+#[derive(Debug)]
+/// Errors returned when reading or writing partition entries.
+pub enum PartitionError {
+    /// Generic IO Error
+    Io(io::Error),
+    /// The partition entry size is not 128 * 2^n
+    InvalidEntrySize(u32),
+    /// The last lba of a partition is before its first lba
+    InvalidExtent {
+        /// The first lba of the partition
+        first_lba: u64,
+        /// The last lba of the partition
+        last_lba: u64,
+    },
+    /// The CRC32 of the partition array does not match the one in the header
+    ///
+    /// This means the partition array was corrupted or not fully written.
+    InvalidCRC32Checksum {
+        /// The checksum stored in the header
+        expected: u32,
+        /// The checksum of the partition array on disk
+        actual: u32,
+    },
+    /// The extra bytes of a partition do not fit into the entry size
+    ExtraBytesDoNotFit {
+        /// The number of extra bytes of the partition
+        len: usize,
+        /// The size of a partition entry
+        entry_size: u32,
+    },
+    /// The partition array extends beyond the end of the device
+    ArrayBeyondDeviceEnd {
+        /// The offset in bytes where the array ends
+        end: u64,
+        /// The size of the device in bytes
+        device_size: u64,
+    },
+    /// Somthing Overflowed
+    /// This will never occur when dealing with sane values
+    Overflow(&'static str),
+}
+
+impl From<io::Error> for PartitionError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl std::error::Error for PartitionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PartitionError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use PartitionError::*;
+        match self {
+            Io(e) => write!(fmt, "Partition IO Error: {e}"),
+            InvalidEntrySize(size) => write!(
+                fmt,
+                "the partition entry size {size} is not 128 * 2^n bytes"
+            ),
+            InvalidExtent {
+                first_lba,
+                last_lba,
+            } => write!(
+                fmt,
+                "invalid partition, last lba {last_lba} is before first lba {first_lba}"
+            ),
+            InvalidCRC32Checksum { expected, actual } => write!(
+                fmt,
+                "partition array CRC32 mismatch, expected {expected:#010x} got {actual:#010x}"
+            ),
+            ExtraBytesDoNotFit { len, entry_size } => write!(
+                fmt,
+                "{len} extra bytes do not fit into a partition entry of {entry_size} bytes"
+            ),
+            ArrayBeyondDeviceEnd { end, device_size } => write!(
+                fmt,
+                "the partition array ends at byte {end} beyond the end of the \
+                device ({device_size} bytes)"
+            ),
+            Overflow(m) => write!(fmt, "Partition error Overflow: {m}"),
+        }
+    }
+}
+
 bitflags! {
     /// Partition entry attributes, defined for UEFI.
+    #[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+    #[cfg_attr(feature = "serde", serde(transparent))]
     pub struct PartitionAttributes: u64 {
         /// Required platform partition.
         const PLATFORM   = 1;
@@ -32,6 +126,7 @@ bitflags! {
 
 /// A partition entry in a GPT partition table.
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub struct Partition {
     /// GUID of the partition type.
     pub part_type_guid: Type,
@@ -45,6 +140,13 @@ pub struct Partition {
     pub flags: u64,
     /// Partition name.
     pub name: String,
+    /// Bytes following the standard 128 byte entry, only present if the
+    /// partition array uses larger entries. They are written back as is,
+    /// if they are all zero they are read as an empty vec. Writing fails
+    /// with `PartitionError::ExtraBytesDoNotFit` if they are longer than
+    /// the entry size minus 128.
+    #[cfg_attr(feature = "serde", serde(default, with = "crate::serde_hex"))]
+    pub extra: Vec<u8>,
 }
 
 impl Partition {
@@ -57,11 +159,22 @@ impl Partition {
             last_lba: 0,
             flags: 0,
             name: "".to_string(),
+            extra: vec![],
         }
     }
 
     /// Serialize this partition entry to its bytes representation.
-    fn as_bytes(&self, entry_size: u32) -> Result<Vec<u8>> {
+    ///
+    /// Fails with `PartitionError::ExtraBytesDoNotFit` instead of
+    /// dropping extra bytes which do not fit into `entry_size`.
+    pub(crate) fn as_bytes(&self, entry_size: u32) -> Result<Vec<u8>, PartitionError> {
+        check_entry_size(entry_size)?;
+        if self.extra.len() > (entry_size - MIN_ENTRY_SIZE) as usize {
+            return Err(PartitionError::ExtraBytesDoNotFit {
+                len: self.extra.len(),
+                entry_size,
+            });
+        }
         let mut buf: Vec<u8> = Vec::with_capacity(entry_size as usize);
 
         // Type GUID.
@@ -89,8 +202,10 @@ impl Partition {
             buf.write_all(&utf16_char.to_le_bytes())?; // TODO: Check this
         }
 
-        // Resize buffer to exact entry size.
-        buf.resize(usize::try_from(entry_size).unwrap(), 0x00);
+        // Pad the name and append the extra bytes of larger entries.
+        buf.resize(MIN_ENTRY_SIZE as usize, 0x00);
+        buf.extend_from_slice(&self.extra);
+        buf.resize(entry_size as usize, 0x00);
 
         Ok(buf)
     }
@@ -103,7 +218,7 @@ impl Partition {
         partition_index: u64,
         start_lba: u64,
         lb_size: disk::LogicalBlockSize,
-    ) -> Result<()> {
+    ) -> Result<(), PartitionError> {
         let mut file = OpenOptions::new().write(true).read(true).open(p)?;
         self.write_to_device(&mut file, partition_index, start_lba, lb_size, 128)
     }
@@ -117,17 +232,22 @@ impl Partition {
         start_lba: u64,
         lb_size: disk::LogicalBlockSize,
         bytes_per_partition: u32,
-    ) -> Result<()> {
+    ) -> Result<(), PartitionError> {
         debug!("writing partition to: {:?}", device);
         let pstart = start_lba
             .checked_mul(lb_size.into())
-            .ok_or_else(|| Error::new(ErrorKind::Other, "partition overflow - start offset"))?;
+            .ok_or(PartitionError::Overflow(
+                "partition overflow - start offset",
+            ))?;
         // The offset is bytes_per_partition * partition_index
         let offset = partition_index
             .checked_mul(u64::from(bytes_per_partition))
-            .ok_or_else(|| Error::new(ErrorKind::Other, "partition overflow"))?;
-        trace!("seeking to partition start: {}", pstart + offset);
-        device.seek(SeekFrom::Start(pstart + offset))?;
+            .ok_or(PartitionError::Overflow("partition overflow"))?;
+        let pos = pstart.checked_add(offset).ok_or(PartitionError::Overflow(
+            "partition overflow - entry offset",
+        ))?;
+        trace!("seeking to partition start: {}", pos);
+        device.seek(SeekFrom::Start(pos))?;
         trace!("writing {:?}", &self.as_bytes(bytes_per_partition));
         device.write_all(&self.as_bytes(bytes_per_partition)?)?;
 
@@ -143,7 +263,7 @@ impl Partition {
         start_lba: u64,
         lb_size: disk::LogicalBlockSize,
         bytes_per_partition: u32,
-    ) -> Result<()> {
+    ) -> Result<(), PartitionError> {
         trace!(
             "writing {} unused partition entries starting at index {}, start_lba={}",
             number_entries,
@@ -152,35 +272,49 @@ impl Partition {
         );
         let pstart = start_lba
             .checked_mul(lb_size.into())
-            .ok_or_else(|| Error::new(ErrorKind::Other, "partition overflow - start offset"))?;
+            .ok_or(PartitionError::Overflow(
+                "partition overflow - start offset",
+            ))?;
         let offset = starting_partition_index
             .checked_mul(u64::from(bytes_per_partition))
-            .ok_or_else(|| Error::new(ErrorKind::Other, "partition overflow"))?;
-        trace!("seeking to starting partition start: {}", pstart + offset);
-        device.seek(SeekFrom::Start(pstart + offset))?;
-        let bytes_to_zero = u64::from(bytes_per_partition)
+            .ok_or(PartitionError::Overflow("partition overflow"))?;
+        let pos = pstart.checked_add(offset).ok_or(PartitionError::Overflow(
+            "partition overflow - entry offset",
+        ))?;
+        trace!("seeking to starting partition start: {}", pos);
+        device.seek(SeekFrom::Start(pos))?;
+        let mut bytes_to_zero = u64::from(bytes_per_partition)
             .checked_mul(number_entries)
-            .and_then(|x| usize::try_from(x).ok())
-            .ok_or_else(|| Error::new(ErrorKind::Other, "partition overflow - bytes to zero"))?;
-        device.write_all(&vec![0_u8; bytes_to_zero])?;
+            .ok_or(PartitionError::Overflow(
+                "partition overflow - bytes to zero",
+            ))?;
+        // write in chunks, the array described by a header can be huge
+        let zeros = vec![0_u8; ZERO_CHUNK.min(bytes_to_zero) as usize];
+        while bytes_to_zero > 0 {
+            let n = ZERO_CHUNK.min(bytes_to_zero) as usize;
+            device.write_all(&zeros[..n])?;
+            bytes_to_zero -= n as u64;
+        }
         Ok(())
     }
 
     /// Return the length (in bytes) of this partition.
     /// Partition size is calculated as (last_lba + 1 - first_lba) * block_size
     /// Bounds are inclusive, meaning we add one to account for the full last logical block
-    pub fn bytes_len(&self, lb_size: disk::LogicalBlockSize) -> Result<u64> {
+    pub fn bytes_len(&self, lb_size: disk::LogicalBlockSize) -> Result<u64, PartitionError> {
         self.sectors_len()?
             .checked_mul(lb_size.into())
-            .ok_or_else(|| Error::new(ErrorKind::Other, "partition length overflow - bytes"))
+            .ok_or(PartitionError::Overflow(
+                "partition length overflow - bytes",
+            ))
     }
 
     /// Return the starting offset (in bytes) of this partition.
-    pub fn bytes_start(&self, lb_size: disk::LogicalBlockSize) -> Result<u64> {
+    pub fn bytes_start(&self, lb_size: disk::LogicalBlockSize) -> Result<u64, PartitionError> {
         let len = self
             .first_lba
             .checked_mul(lb_size.into())
-            .ok_or_else(|| Error::new(ErrorKind::Other, "partition start overflow - bytes"))?;
+            .ok_or(PartitionError::Overflow("partition start overflow - bytes"))?;
         Ok(len)
     }
 
@@ -190,14 +324,17 @@ impl Partition {
     }
 
     /// Return the length (in sectors) of this partition.
-    pub fn sectors_len(&self) -> Result<u64> {
+    pub fn sectors_len(&self) -> Result<u64, PartitionError> {
         self.last_lba
             .checked_sub(self.first_lba)
-            .ok_or_else(|| {
-                Error::new(ErrorKind::Other, "Invalid partition.  last_lba < first_lba")
+            .ok_or(PartitionError::InvalidExtent {
+                first_lba: self.first_lba,
+                last_lba: self.last_lba,
             })?
             .checked_add(1)
-            .ok_or_else(|| Error::new(ErrorKind::Other, "partition length overflow - sectors"))
+            .ok_or(PartitionError::Overflow(
+                "partition length overflow - sectors",
+            ))
     }
 }
 
@@ -235,7 +372,7 @@ pub fn read_partitions(
     path: impl AsRef<Path>,
     header: &Header,
     lb_size: disk::LogicalBlockSize,
-) -> Result<BTreeMap<u32, Partition>> {
+) -> Result<BTreeMap<u32, Partition>, PartitionError> {
     debug!("reading partitions from file: {}", path.as_ref().display());
     let mut file = File::open(path)?;
     file_read_partitions(&mut file, header, lb_size)
@@ -243,32 +380,59 @@ pub fn read_partitions(
 
 const CRC_32: Crc<u32> = Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
 
+/// The maximum number of zero bytes written at once
+const ZERO_CHUNK: u64 = 64 * 1024;
+
+/// The size of a partition entry defined by the spec, larger entries
+/// need to be 128 * 2^n bytes large
+const MIN_ENTRY_SIZE: u32 = 128;
+
+fn check_entry_size(entry_size: u32) -> Result<(), PartitionError> {
+    if entry_size < MIN_ENTRY_SIZE || !entry_size.is_power_of_two() {
+        return Err(PartitionError::InvalidEntrySize(entry_size));
+    }
+    Ok(())
+}
+
 /// Read a GPT partition table from an open `Read` + `Seek` object.
 pub fn file_read_partitions<D: Read + Seek>(
     file: &mut D,
     header: &Header,
     lb_size: disk::LogicalBlockSize,
-) -> Result<BTreeMap<u32, Partition>> {
+) -> Result<BTreeMap<u32, Partition>, PartitionError> {
     let pstart = header
         .part_start
         .checked_mul(lb_size.into())
-        .ok_or_else(|| Error::new(ErrorKind::Other, "partition overflow - start offset"))?;
+        .ok_or(PartitionError::Overflow(
+            "partition overflow - start offset",
+        ))?;
+    check_entry_size(header.part_size)?;
+
+    // don't trust the header to describe an array which fits the device
+    let pt_len = u64::from(header.num_parts)
+        .checked_mul(header.part_size.into())
+        .ok_or(PartitionError::Overflow("partitions - size"))?;
+    let end = pstart
+        .checked_add(pt_len)
+        .ok_or(PartitionError::Overflow("partitions - end offset"))?;
+    let device_size = file.seek(SeekFrom::End(0))?;
+    if end > device_size {
+        return Err(PartitionError::ArrayBeyondDeviceEnd { end, device_size });
+    }
+
     trace!("seeking to partitions start: {:#x}", pstart);
     let _ = file.seek(SeekFrom::Start(pstart))?;
     let mut parts: BTreeMap<u32, Partition> = BTreeMap::new();
-
-    // todo how should we deal with unuals part_sizes?
-    assert_eq!(header.part_size, 128);
+    let mut digest = CRC_32.digest();
 
     trace!("scanning {} partitions", header.num_parts);
     let mut empty_parts = 0;
-    let empty_bytes = [0u8; 128];
+    let mut bytes = vec![0u8; header.part_size as usize];
     for i in 0..header.num_parts {
-        let mut bytes = empty_bytes;
-
         file.read_exact(&mut bytes)?;
+        digest.update(&bytes);
         // Note: unused partition entries are zeroed, so skip them
-        if bytes.eq(&empty_bytes) {
+        if bytes.iter().all(|b| *b == 0) {
             empty_parts += 1;
             continue;
         }
@@ -296,6 +460,10 @@ pub fn file_read_partitions<D: Read + Seek>(
             last_lba,
             flags,
             name: String::from_utf16_lossy(&name_bytes[..zero_pos]),
+            extra: match &bytes[MIN_ENTRY_SIZE as usize..] {
+                extra if extra.iter().all(|b| *b == 0) => vec![],
+                extra => extra.to_vec(),
+            },
         };
 
         parts.insert(i + 1, p);
@@ -303,16 +471,12 @@ pub fn file_read_partitions<D: Read + Seek>(
     debug!("Num Zeroed partitions {:?}\n\n", empty_parts);
 
     debug!("checking partition table CRC");
-    let _ = file.seek(SeekFrom::Start(pstart))?;
-    let pt_len = u64::from(header.num_parts)
-        .checked_mul(header.part_size.into())
-        .ok_or_else(|| Error::new(ErrorKind::Other, "partitions - size"))?;
-    let mut table = vec![0; pt_len as usize];
-    file.read_exact(&mut table)?;
-
-    let comp_crc = CRC_32.checksum(&table);
+    let comp_crc = digest.finalize();
     if comp_crc != header.crc32_parts {
-        return Err(Error::new(ErrorKind::Other, "partition table CRC mismatch"));
+        return Err(PartitionError::InvalidCRC32Checksum {
+            expected: header.crc32_parts,
+            actual: comp_crc,
+        });
     }
 
     Ok(parts)
@@ -367,7 +531,7 @@ mod tests {
         {
             // Overflowing u64 length.
             let mut p2 = partition::Partition::zero();
-            p2.last_lba = <u64>::max_value();
+            p2.last_lba = u64::MAX;
             p2.sectors_len().unwrap_err();
             p2.bytes_len(disk::LogicalBlockSize::Lb512).unwrap_err();
             p2.bytes_len(disk::LogicalBlockSize::Lb4096).unwrap_err();
@@ -403,7 +567,7 @@ mod tests {
         {
             // Overflowing u64 start.
             let mut p1 = partition::Partition::zero();
-            p1.first_lba = <u64>::max_value();
+            p1.first_lba = u64::MAX;
             p1.bytes_len(disk::LogicalBlockSize::Lb512).unwrap_err();
             p1.bytes_len(disk::LogicalBlockSize::Lb4096).unwrap_err();
         }
@@ -419,4 +583,20 @@ mod tests {
             assert_eq!(b4096start, 2 * 4096);
         }
     }
+
+    #[test]
+    fn test_entry_extra_bytes() {
+        let mut p = partition::Partition::zero();
+        p.name = "test".to_string();
+        p.extra = vec![0xaa; 64];
+
+        let b256 = p.as_bytes(256).unwrap();
+        assert_eq!(b256.len(), 256);
+        assert_eq!(&b256[56..58], &[b't', 0]);
+        assert_eq!(&b256[128..192], &[0xaa; 64][..]);
+        assert!(b256[192..].iter().all(|b| *b == 0));
+
+        p.as_bytes(0).unwrap_err();
+        p.as_bytes(200).unwrap_err();
+    }
 }
@@ -0,0 +1,240 @@
+//! Moving a partition together with its data.
+//!
+//! A move copies the content of the partition chunk by chunk to its new
+//! location and only updates the partition entry once everything was
+//! copied. The chunks never overlap data which still needs to be copied,
+//! so an interrupted move can be resumed from the last finished chunk by
+//! keeping the `PartitionMove` around (all its fields are public so it
+//! can be stored somewhere).
+//!
+//! This is why a chunk is at most as large as the distance the partition
+//! moves, and the device gets synced after every chunk. Short moves of
+//! large partitions are slow because of this, moving by a single lba
+//! copies one lba at a time. `move_partition` can't be resumed anyway and
+//! uses chunks of 1MiB.
+//!
+//! ## Example
+//!
+//! ```rust,no_run
+//! let mut disk = gpt::GptConfig::new()
+//!     .writable(true)
+//!     .open("/dev/sdz")
+//!     .unwrap();
+//!
+//! let mut mv = disk.prepare_partition_move(2, 2048).unwrap();
+//! while !disk.copy_partition_chunk(&mut mv).unwrap() {
+//!     // persist mv.copied_lba to be able to resume after a crash
+//! }
+//! disk.finish_partition_move(&mut mv).unwrap();
+//! disk.write().unwrap();
+//! ```
+
+use std::io::SeekFrom;
+
+use crate::partition::Partition;
+use crate::{DiskDevice, GptDisk, GptError};
+
+/// the maximum amount of bytes copied at once
+const MAX_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// The state of a partition move.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PartitionMove {
+    /// The id of the partition which gets moved.
+    pub id: u32,
+    /// The first lba before the move.
+    pub from_lba: u64,
+    /// The first lba after the move.
+    pub to_lba: u64,
+    /// The length of the partition in lba.
+    pub length_lba: u64,
+    /// How many lba were already copied.
+    pub copied_lba: u64,
+    /// How many lba get copied at once, at most 1MiB.
+    ///
+    /// `prepare_partition_move` limits this to the distance between
+    /// `from_lba` and `to_lba`. Larger chunks are faster but an interrupted
+    /// chunk overlapping its source can't be copied again.
+    pub chunk_lba: u64,
+}
+
+impl PartitionMove {
+    /// Returns true if all data was copied.
+    pub fn is_copied(&self) -> bool {
+        self.copied_lba >= self.length_lba
+    }
+
+    /// The offset (in lba from the start of the partition) and
+    /// length of the next chunk to copy.
+    fn next_chunk(&self) -> (u64, u64) {
+        let len = self.chunk_lba.min(self.length_lba - self.copied_lba);
+        if self.to_lba < self.from_lba {
+            // moving to the front, copy from the start
+            (self.copied_lba, len)
+        } else {
+            // moving to the back, copy from the end
+            (self.length_lba - self.copied_lba - len, len)
+        }
+    }
+}
+
+impl<D> GptDisk<D>
+where
+    D: DiskDevice,
+{
+    /// Prepares moving the partition with the given id to start at
+    /// `new_first_lba`.
+    ///
+    /// The new extent needs to be within the usable lba and may only
+    /// overlap the partition itself, otherwise this fails with
+    /// `GptError::InvalidLayout`. Nothing is changed yet.
+    ///
+    /// The chunks are at most as large as the distance of the move, so
+    /// that the move can be resumed, see `PartitionMove::chunk_lba`.
+    pub fn prepare_partition_move(
+        &self,
+        id: u32,
+        new_first_lba: u64,
+    ) -> Result<PartitionMove, GptError> {
+        let part = self.used_partition(id)?;
+        let length_lba = part.sectors_len()?;
+
+        let mut moved = part.clone();
+        moved.first_lba = new_first_lba;
+        moved.last_lba = new_first_lba
+            .checked_add(length_lba - 1)
+            .ok_or(GptError::Overflow("partition end"))?;
+        self.check_partition(id, &moved)?;
+
+        let distance = part.first_lba.max(new_first_lba) - part.first_lba.min(new_first_lba);
+
+        Ok(PartitionMove {
+            id,
+            from_lba: part.first_lba,
+            to_lba: new_first_lba,
+            length_lba,
+            copied_lba: if distance == 0 { length_lba } else { 0 },
+            chunk_lba: self.max_chunk_lba().min(distance).max(1),
+        })
+    }
+
+    /// Copies the next chunk of a move and syncs the device.
+    ///
+    /// Returns true once all data was copied. If this fails it can just be
+    /// called again, also after the disk was reopened.
+    ///
+    /// Nothing is copied if the partition entry changed since the move was
+    /// prepared (`GptError::StalePartitionMove`) or if the new extent is
+    /// not valid anymore (`GptError::InvalidLayout`).
+    pub fn copy_partition_chunk(&mut self, mv: &mut PartitionMove) -> Result<bool, GptError> {
+        if !self.config.writable {
+            return Err(GptError::ReadOnly);
+        }
+        if mv.is_copied() {
+            return Ok(true);
+        }
+        self.moved_partition(mv)?;
+
+        let lb_size = self.config.lb_size.as_u64();
+        // the move might have been stored and changed in between
+        if mv.chunk_lba == 0 || mv.chunk_lba > self.max_chunk_lba() {
+            return Err(GptError::Overflow("move chunk size"));
+        }
+        let (offset, len) = mv.next_chunk();
+        let src = mv
+            .from_lba
+            .checked_add(offset)
+            .and_then(|lba| lba.checked_mul(lb_size))
+            .ok_or(GptError::Overflow("move source"))?;
+        let dst = mv
+            .to_lba
+            .checked_add(offset)
+            .and_then(|lba| lba.checked_mul(lb_size))
+            .ok_or(GptError::Overflow("move destination"))?;
+        trace!("copying {} lba from byte {} to {}", len, src, dst);
+
+        // never write beyond the end of the device
+        let end = dst
+            .checked_add(len * lb_size)
+            .ok_or(GptError::Overflow("move destination"))?;
+        let device_size = self.device.seek(SeekFrom::End(0))?;
+        if end > device_size {
+            return Err(GptError::BeyondDeviceEnd { end, device_size });
+        }
+
+        let mut buf = vec![0; (len * lb_size) as usize];
+        self.device.seek(SeekFrom::Start(src))?;
+        self.device.read_exact(&mut buf)?;
+        self.device.seek(SeekFrom::Start(dst))?;
+        self.device.write_all(&buf)?;
+        self.sync()?;
+
+        mv.copied_lba += len;
+        Ok(mv.is_copied())
+    }
+
+    /// Copies the remaining data of a move and updates the partition entry.
+    ///
+    /// Fails with `GptError::StalePartitionMove` if the partition entry
+    /// changed since the move was prepared. The partition table still
+    /// needs to be written afterwards.
+    /// Returns the new `(first_lba, last_lba)`.
+    pub fn finish_partition_move(
+        &mut self,
+        mv: &mut PartitionMove,
+    ) -> Result<(u64, u64), GptError> {
+        let part = self.moved_partition(mv)?;
+
+        while !self.copy_partition_chunk(mv)? {}
+
+        debug!(
+            "Moved partition id: {}. first_lba: {} -> {}",
+            mv.id, mv.from_lba, mv.to_lba
+        );
+        let extent = (part.first_lba, part.last_lba);
+        self.partitions.insert(mv.id, part);
+        Ok(extent)
+    }
+
+    /// Moves the partition with the given id and its data to start at
+    /// `new_first_lba`, see `prepare_partition_move`.
+    ///
+    /// The data is copied in chunks of 1MiB regardless of the distance,
+    /// an interrupted move can't be resumed.
+    ///
+    /// The partition table still needs to be written afterwards.
+    /// Returns the new `(first_lba, last_lba)`.
+    pub fn move_partition(&mut self, id: u32, new_first_lba: u64) -> Result<(u64, u64), GptError> {
+        let mut mv = self.prepare_partition_move(id, new_first_lba)?;
+        // every chunk is read completely before it is written, and the next
+        // one comes from the side which is not written to
+        mv.chunk_lba = self.max_chunk_lba();
+        self.finish_partition_move(&mut mv)
+    }
+
+    /// The partition entry after the move, checking that the move still
+    /// matches the current entry and that the new extent is valid.
+    fn moved_partition(&self, mv: &PartitionMove) -> Result<Partition, GptError> {
+        let part = self.used_partition(mv.id)?;
+        if part.first_lba != mv.from_lba
+            || part.sectors_len().ok() != Some(mv.length_lba)
+            || mv.copied_lba > mv.length_lba
+        {
+            // the entry (or the move) was changed in between
+            return Err(GptError::StalePartitionMove(mv.id));
+        }
+
+        let mut part = part.clone();
+        part.last_lba = mv
+            .to_lba
+            .checked_add(mv.length_lba - 1)
+            .ok_or(GptError::Overflow("partition end"))?;
+        part.first_lba = mv.to_lba;
+        self.check_partition(mv.id, &part)?;
+        Ok(part)
+    }
+
+    fn max_chunk_lba(&self) -> u64 {
+        (MAX_CHUNK_SIZE / self.config.lb_size.as_u64()).max(1)
+    }
+}
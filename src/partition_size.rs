@@ -0,0 +1,327 @@
+//! Sizes of new partitions.
+//!
+//! A `PartitionSize` can be given in bytes, in logical blocks, as a
+//! percentage of the free space or as all the remaining space. It can
+//! also be parsed from the strings humans write, like `512MiB`, `1.5G`,
+//! `250 MB`, `2048s`, `25%` or `remaining`.
+//!
+//! ## Example
+//!
+//! ```rust,no_run
+//! use gpt::partition_size::PartitionSize;
+//!
+//! let mut disk = gpt::GptConfig::new()
+//!     .writable(true)
+//!     .open("/dev/sdz")
+//!     .unwrap();
+//!
+//! let size: PartitionSize = "1.5G".parse().unwrap();
+//! let resolved = disk.resolve_size(size, None).unwrap();
+//! println!("{} bytes, {} lba", resolved.bytes, resolved.lba);
+//! disk.add_partition("root", size, gpt::partition_types::LINUX_FS, 0, None)
+//!     .unwrap();
+//! disk.add_partition(
+//!     "home",
+//!     PartitionSize::Remaining,
+//!     gpt::partition_types::LINUX_FS,
+//!     0,
+//!     None,
+//! )
+//! .unwrap();
+//! disk.write().unwrap();
+//! ```
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::{DiskDevice, GptDisk, GptError};
+
+/// The size of a partition.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PartitionSize {
+    /// A size in bytes, rounded up to whole logical blocks.
+    Bytes(u64),
+    /// A size in logical blocks.
+    Lba(u64),
+    /// A percentage (1 to 100) of the free space.
+    PercentOfFree(u8),
+    /// All the space of the largest free extent.
+    Remaining,
+}
+
+impl From<u64> for PartitionSize {
+    fn from(bytes: u64) -> Self {
+        Self::Bytes(bytes)
+    }
+}
+
+/// The size of a partition in bytes and logical blocks.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ResolvedSize {
+    /// The size in bytes, always whole logical blocks.
+    pub bytes: u64,
+    /// The size in logical blocks.
+    pub lba: u64,
+}
+
+impl PartitionSize {
+    /// The size in lba, percentages and the remaining space refer to the
+    /// given free extents (as `(first_lba, length)`).
+    ///
+    /// Fails with `GptError::InvalidPartitionSize` if the size is zero.
+    pub(crate) fn to_lba(self, lb_size: u64, free: &[(u64, u64)]) -> Result<u64, GptError> {
+        let lba = match self {
+            Self::Bytes(bytes) => match bytes {
+                0 => 0,
+                // Ceiling division which avoids overflow
+                bytes => (bytes - 1) / lb_size + 1,
+            },
+            Self::Lba(lba) => lba,
+            Self::PercentOfFree(percent) => {
+                if percent == 0 || percent > 100 {
+                    return Err(GptError::InvalidPartitionSize);
+                }
+                let total: u128 = free.iter().map(|(_, length)| u128::from(*length)).sum();
+                // at most the free space, which fits in u64
+                let lba = (total * u128::from(percent) / 100) as u64;
+                lba.max(1)
+            }
+            Self::Remaining => free
+                .iter()
+                .map(|(_, length)| *length)
+                .max()
+                .unwrap_or(0)
+                .max(1),
+        };
+        if lba == 0 {
+            return Err(GptError::InvalidPartitionSize);
+        }
+
+        Ok(lba)
+    }
+}
+
+/// Errors returned when parsing a `PartitionSize`.
+#[non_exhaustive]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ParseSizeError {
+    /// The string is empty
+    Empty,
+    /// The number is not a valid decimal number
+    InvalidNumber(String),
+    /// The unit is not known
+    UnknownUnit(String),
+    /// A percentage is not a whole number between 1 and 100
+    InvalidPercentage(String),
+    /// The size does not fit in 64 bits
+    Overflow,
+}
+
+impl std::error::Error for ParseSizeError {}
+
+impl fmt::Display for ParseSizeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ParseSizeError::*;
+        match self {
+            Empty => write!(fmt, "empty size"),
+            InvalidNumber(n) => write!(fmt, "invalid number {n:?}"),
+            UnknownUnit(u) => write!(fmt, "unknown unit {u:?}"),
+            InvalidPercentage(p) => write!(fmt, "invalid percentage {p:?}"),
+            Overflow => write!(fmt, "size too large"),
+        }
+    }
+}
+
+impl FromStr for PartitionSize {
+    type Err = ParseSizeError;
+
+    /// Parses a size like `512MiB`, `1.5G`, `250 MB`, `2048s`, `25%` or
+    /// `remaining`.
+    ///
+    /// `KiB`, `MiB`, `GiB`, `TiB`, `PiB` and `EiB` are powers of 1024 and
+    /// `KB`, `MB`, ... powers of 1000. Like in util-linux a single letter
+    /// (`K`, `M`, `G`, ...) means the power of 1024. Units are case
+    /// insensitive, a number without unit is in bytes and `s` stands for
+    /// logical blocks (sectors).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(ParseSizeError::Empty);
+        }
+        if s.eq_ignore_ascii_case("remaining") {
+            return Ok(Self::Remaining);
+        }
+        if let Some(percent) = s.strip_suffix('%') {
+            return percent
+                .trim()
+                .parse()
+                .ok()
+                .filter(|p| (1..=100).contains(p))
+                .map(Self::PercentOfFree)
+                .ok_or_else(|| ParseSizeError::InvalidPercentage(s.to_string()));
+        }
+
+        let split = s
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let unit = unit.trim().to_ascii_uppercase();
+        if unit == "S" {
+            return match number.parse() {
+                Ok(lba) => Ok(Self::Lba(lba)),
+                Err(_) => Err(ParseSizeError::InvalidNumber(number.to_string())),
+            };
+        }
+
+        parse_decimal(number, unit_factor(&unit)?).map(Self::Bytes)
+    }
+}
+
+/// The number of bytes of a unit (already in upper case).
+fn unit_factor(unit: &str) -> Result<u64, ParseSizeError> {
+    if unit.is_empty() || unit == "B" {
+        return Ok(1);
+    }
+
+    let mut chars = unit.chars();
+    let exponent = chars
+        .next()
+        .and_then(|prefix| "KMGTPE".find(prefix))
+        .ok_or_else(|| ParseSizeError::UnknownUnit(unit.to_string()))?;
+    let base: u64 = match chars.as_str() {
+        "" | "IB" => 1024,
+        "B" => 1000,
+        _ => return Err(ParseSizeError::UnknownUnit(unit.to_string())),
+    };
+
+    // at most 1024^6 which fits in u64
+    Ok(base.pow(exponent as u32 + 1))
+}
+
+/// Parses a decimal number and multiplies it by `factor`, rounding up to
+/// whole bytes.
+fn parse_decimal(number: &str, factor: u64) -> Result<u64, ParseSizeError> {
+    let invalid = || ParseSizeError::InvalidNumber(number.to_string());
+
+    let (int, frac) = number.split_once('.').unwrap_or((number, ""));
+    // trailing zeros don't change the value
+    let frac = frac.trim_end_matches('0');
+    if (int.is_empty() && frac.is_empty()) || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let int: u64 = match int {
+        "" => 0,
+        int => int.parse().map_err(|_| ParseSizeError::Overflow)?,
+    };
+    // more digits than can matter for a size in bytes
+    if frac.len() > 19 {
+        return Err(invalid());
+    }
+
+    let scale = 10u128.pow(frac.len() as u32);
+    let frac = match frac {
+        "" => 0,
+        frac => frac.parse::<u128>().map_err(|_| invalid())?,
+    };
+    let value = (u128::from(int) * scale + frac)
+        .checked_mul(u128::from(factor))
+        .ok_or(ParseSizeError::Overflow)?;
+    let bytes = value / scale + u128::from(value % scale != 0);
+
+    u64::try_from(bytes).map_err(|_| ParseSizeError::Overflow)
+}
+
+impl<D> GptDisk<D>
+where
+    D: DiskDevice,
+{
+    /// Returns the size `add_partition` would give a new partition of
+    /// `size` with the given alignment (in lba).
+    ///
+    /// Fails with `GptError::NotEnoughSpace` if the partition would not fit.
+    pub fn resolve_size(
+        &self,
+        size: impl Into<PartitionSize>,
+        part_alignment: Option<u64>,
+    ) -> Result<ResolvedSize, GptError> {
+        let (_, lba) = self.locate(size.into(), part_alignment, self.config.placement)?;
+        let bytes = lba
+            .checked_mul(self.config.lb_size.as_u64())
+            .ok_or(GptError::Overflow("partition size in bytes"))?;
+
+        Ok(ResolvedSize { bytes, lba })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        let parse = |s: &str| s.parse::<PartitionSize>();
+        let bytes = |s: &str| match parse(s) {
+            Ok(PartitionSize::Bytes(bytes)) => bytes,
+            r => panic!("unexpected result {r:?} for {s:?}"),
+        };
+
+        assert_eq!(bytes("4096"), 4096);
+        assert_eq!(bytes(" 100 B "), 100);
+        assert_eq!(bytes("512MiB"), 512 << 20);
+        assert_eq!(bytes("512M"), 512 << 20);
+        assert_eq!(bytes("512mb"), 512_000_000);
+        assert_eq!(bytes("1.5G"), 3 << 29);
+        assert_eq!(bytes("1.5 GB"), 1_500_000_000);
+        assert_eq!(bytes(".5k"), 512);
+        assert_eq!(bytes("2.000TiB"), 2 << 40);
+        assert_eq!(bytes("0.1KiB"), 103);
+        assert_eq!(bytes("6EiB"), 6 << 60);
+        assert_eq!(parse("2048s"), Ok(PartitionSize::Lba(2048)));
+        assert_eq!(parse("25%"), Ok(PartitionSize::PercentOfFree(25)));
+        assert_eq!(parse("Remaining"), Ok(PartitionSize::Remaining));
+
+        assert_eq!(parse(""), Err(ParseSizeError::Empty));
+        assert_eq!(
+            parse("0%"),
+            Err(ParseSizeError::InvalidPercentage("0%".into()))
+        );
+        assert_eq!(
+            parse("101 %"),
+            Err(ParseSizeError::InvalidPercentage("101 %".into()))
+        );
+        assert_eq!(
+            parse("1.5.1G"),
+            Err(ParseSizeError::InvalidNumber("1.5.1".into()))
+        );
+        assert_eq!(parse("G"), Err(ParseSizeError::InvalidNumber("".into())));
+        assert_eq!(
+            parse("1.5s"),
+            Err(ParseSizeError::InvalidNumber("1.5".into()))
+        );
+        assert_eq!(
+            parse("12 ZiB"),
+            Err(ParseSizeError::UnknownUnit("ZIB".into()))
+        );
+        assert_eq!(
+            parse("12 Kibi"),
+            Err(ParseSizeError::UnknownUnit("KIBI".into()))
+        );
+        assert_eq!(parse("16EiB"), Err(ParseSizeError::Overflow));
+        assert_eq!(parse("99999999999999999999"), Err(ParseSizeError::Overflow));
+    }
+
+    #[test]
+    fn test_to_lba() {
+        let free = [(34, 24), (94, 13)];
+        let lba = |size: PartitionSize| size.to_lba(512, &free);
+        assert_eq!(lba(PartitionSize::Bytes(1)).unwrap(), 1);
+        assert_eq!(lba(PartitionSize::Bytes(1025)).unwrap(), 3);
+        assert_eq!(lba(PartitionSize::Lba(7)).unwrap(), 7);
+        assert_eq!(lba(PartitionSize::PercentOfFree(50)).unwrap(), 18);
+        assert_eq!(lba(PartitionSize::PercentOfFree(1)).unwrap(), 1);
+        assert_eq!(lba(PartitionSize::Remaining).unwrap(), 24);
+        assert!(lba(PartitionSize::Bytes(0)).is_err());
+        assert!(lba(PartitionSize::PercentOfFree(101)).is_err());
+        assert_eq!(PartitionSize::Remaining.to_lba(512, &[]).unwrap(), 1);
+    }
+}
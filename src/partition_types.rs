@@ -4,6 +4,7 @@ use std::str::FromStr;
 
 /// The type
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Type {
     /// Type-GUID for a GPT partition.
     pub guid: Uuid,
@@ -13,6 +14,7 @@ pub struct Type {
 
 /// Operating System
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum OperatingSystem {
     /// No OS
     None,
@@ -0,0 +1,171 @@
+//! Strategies to choose where a new partition is placed.
+//!
+//! `GptDisk::add_partition` uses the strategy set with
+//! `GptConfig::placement`, `GptDisk::add_partition_placed` takes one per
+//! call. Every strategy honors the alignment of the partition.
+//!
+//! ## Example
+//!
+//! ```rust,no_run
+//! use gpt::placement::Placement;
+//!
+//! let mut disk = gpt::GptConfig::new()
+//!     .writable(true)
+//!     .open("/dev/sdz")
+//!     .unwrap();
+//!
+//! // a recovery partition at the end of the disk
+//! disk.add_partition_placed(
+//!     "recovery",
+//!     1024 * 1024 * 512,
+//!     gpt::partition_types::WINDOWS_RECOVERY,
+//!     0,
+//!     None,
+//!     Placement::LastFit,
+//! )
+//! .unwrap();
+//! disk.write().unwrap();
+//! ```
+
+use crate::partition_size::PartitionSize;
+use crate::{DiskDevice, GptDisk, GptError};
+
+/// Where a new partition is placed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Placement {
+    /// At the start of the first free extent which is large enough.
+    #[default]
+    FirstFit,
+    /// At the start of the smallest free extent which is large enough,
+    /// keeping large extents for large partitions.
+    BestFit,
+    /// At the end of the last free extent which is large enough, for
+    /// example for recovery partitions.
+    LastFit,
+    /// At the start of the first free extent which is large enough
+    /// inside the given lba window.
+    Within {
+        /// The first lba the partition may use
+        first_lba: u64,
+        /// The last lba the partition may use
+        last_lba: u64,
+    },
+}
+
+impl<D> GptDisk<D>
+where
+    D: DiskDevice,
+{
+    /// Find free space on the disk starting at an lba aligned to
+    /// `alignment` (in lba, 0 or 1 means no alignment) with a length
+    /// of at least `min_length` lba.
+    ///
+    /// Returns a tuple of (starting_lba, length in lba's).
+    pub fn find_free_sectors_aligned(&self, alignment: u64, min_length: u64) -> Vec<(u64, u64)> {
+        align_extents(self.find_free_sectors(), alignment, min_length)
+    }
+
+    /// The first lba and the length of a new partition of `size`.
+    ///
+    /// Every alignment of `GptDisk::alignments` is tried until the
+    /// partition fits, percentages and the remaining space refer to the
+    /// free extents of the placement with that alignment. If it doesn't
+    /// fit `GptError::NotEnoughSpace` is returned for the first one.
+    pub(crate) fn locate(
+        &self,
+        size: PartitionSize,
+        part_alignment: Option<u64>,
+        placement: Placement,
+    ) -> Result<(u64, u64), GptError> {
+        let lb_size = self.config.lb_size.as_u64();
+        let mut requested = None;
+        for alignment in self.alignments(part_alignment) {
+            let size_lba = size.to_lba(lb_size, &self.free_extents(alignment, 1, placement))?;
+            requested.get_or_insert(size_lba);
+            if let Some(first_lba) = self.place(size_lba, alignment, placement) {
+                return Ok((first_lba, size_lba));
+            }
+        }
+
+        Err(self.not_enough_space(requested.unwrap_or(0)))
+    }
+
+    /// The aligned free extents a partition can be placed in, the ones in
+    /// the window for `Placement::Within`.
+    fn free_extents(
+        &self,
+        alignment: u64,
+        min_length: u64,
+        placement: Placement,
+    ) -> Vec<(u64, u64)> {
+        match placement {
+            Placement::Within {
+                first_lba,
+                last_lba,
+            } => {
+                let window = self
+                    .find_free_sectors()
+                    .into_iter()
+                    .filter_map(|(start, length)| {
+                        let end = (start + (length - 1)).min(last_lba);
+                        let start = start.max(first_lba);
+                        (start <= end).then(|| (start, end - start + 1))
+                    });
+                align_extents(window, alignment, min_length)
+            }
+            _ => self.find_free_sectors_aligned(alignment, min_length),
+        }
+    }
+
+    /// The first lba of a partition of `size_lba` placed according to
+    /// `placement`, None if it does not fit.
+    fn place(&self, size_lba: u64, alignment: u64, placement: Placement) -> Option<u64> {
+        let free = self.free_extents(alignment, size_lba, placement);
+        match placement {
+            Placement::FirstFit | Placement::Within { .. } => free.first().map(|(start, _)| *start),
+            Placement::BestFit => free
+                .iter()
+                .min_by_key(|(_, length)| *length)
+                .map(|(start, _)| *start),
+            Placement::LastFit => {
+                let (start, length) = *free.last()?;
+                // the last aligned lba leaving room for the partition,
+                // at least the aligned start of the extent
+                let first_lba = start + (length - size_lba);
+                Some(match alignment {
+                    0 | 1 => first_lba,
+                    alignment => first_lba - (first_lba - start) % alignment,
+                })
+            }
+        }
+    }
+}
+
+/// Moves the start of every extent to the next aligned lba, dropping
+/// extents which end up shorter than `min_length` (or empty).
+fn align_extents(
+    extents: impl IntoIterator<Item = (u64, u64)>,
+    alignment: u64,
+    min_length: u64,
+) -> Vec<(u64, u64)> {
+    extents
+        .into_iter()
+        .filter_map(|(start, length)| {
+            // We don't need to do any checked math here because we guarantee that with `(A % B)`,
+            // `A` will always be between 0 and `B-1`.
+            let offset = match alignment {
+                0 | 1 => 0,
+                alignment => (alignment - (start % alignment)) % alignment,
+            };
+            trace!(
+                "free extent ({}, {}), alignment offset {}",
+                start,
+                length,
+                offset
+            );
+            let length = length.checked_sub(offset)?;
+            (length > 0 && length >= min_length).then(|| (start + offset, length))
+        })
+        .collect()
+}
@@ -0,0 +1,138 @@
+//! Dry-run planning of writes.
+//!
+//! `GptDisk::plan_write` runs the same logic as `GptDisk::write_inplace`
+//! against a device which only records the writes. The resulting
+//! `WritePlan` lists every byte range which would change, grouped by the
+//! structure it belongs to, so that changes can be reviewed before they
+//! hit the device.
+
+use std::ops::Range;
+
+use crate::header::Header;
+use crate::{DiskDevice, GptDisk, GptError, GptStructure};
+
+/// A change a write would make to one on-disk structure.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PlannedWrite {
+    /// The structure the changed bytes belong to.
+    pub structure: GptStructure,
+    /// Offset of the first changed byte, from the start of the disk.
+    pub offset: u64,
+    /// Bytes currently on the device.
+    pub old: Vec<u8>,
+    /// Bytes which would be written, always the same length as `old`.
+    pub new: Vec<u8>,
+}
+
+/// Every byte range a write would change.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct WritePlan {
+    writes: Vec<PlannedWrite>,
+}
+
+impl WritePlan {
+    /// All changes, sorted by structure and offset.
+    ///
+    /// Ranges which would be written with the bytes already on the
+    /// device are not included.
+    pub fn writes(&self) -> &[PlannedWrite] {
+        &self.writes
+    }
+
+    /// Returns true if writing would not change a single byte.
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Returns the changes to the given structure.
+    pub fn structure(&self, structure: GptStructure) -> impl Iterator<Item = &PlannedWrite> {
+        self.writes.iter().filter(move |w| w.structure == structure)
+    }
+}
+
+impl<D> GptDisk<D>
+where
+    D: DiskDevice,
+{
+    /// Computes what `write_inplace` would change on the device,
+    /// without writing anything.
+    ///
+    /// This also works if the disk was not opened in writable mode.
+    ///
+    /// ## Example
+    ///
+    /// ```rust,no_run
+    /// let mut disk = gpt::GptConfig::new().open("/dev/sdz").unwrap();
+    /// disk.remove_partition(2);
+    ///
+    /// for w in disk.plan_write().unwrap().writes() {
+    ///     println!("{}: {} bytes at {}", w.structure, w.new.len(), w.offset);
+    /// }
+    /// ```
+    pub fn plan_write(&mut self) -> Result<WritePlan, GptError> {
+        let lb_size = self.config.lb_size.as_u64();
+        let readonly_backup = self.config.readonly_backup;
+
+        let mut recording = self.recording()?;
+        recording.write_staged()?;
+
+        let mut structures = vec![(GptStructure::ProtectiveMbr, 0..lb_size)];
+        if let Ok(h) = &recording.primary_header {
+            structures.push((GptStructure::PrimaryHeader, header_range(h, lb_size)));
+            structures.push((GptStructure::PrimaryEntries, entries_range(h, lb_size)));
+        }
+        if let (Ok(h), false) = (&recording.backup_header, readonly_backup) {
+            structures.push((GptStructure::BackupEntries, entries_range(h, lb_size)));
+            structures.push((GptStructure::BackupHeader, header_range(h, lb_size)));
+        }
+
+        let regions = recording.device.into_regions(1)?;
+
+        let mut writes = vec![];
+        for (structure, range) in structures {
+            for region in &regions {
+                let region_end = region.offset + region.old.len() as u64;
+                let start = range.start.max(region.offset);
+                let end = range.end.min(region_end);
+                if start >= end {
+                    continue;
+                }
+
+                let idx = (start - region.offset) as usize..(end - region.offset) as usize;
+                let old = &region.old[idx.clone()];
+                let new = &region.new[idx];
+                let first = match old.iter().zip(new).position(|(o, n)| o != n) {
+                    Some(first) => first,
+                    None => continue,
+                };
+                let last = old
+                    .iter()
+                    .zip(new)
+                    .rposition(|(o, n)| o != n)
+                    .unwrap_or(first);
+
+                writes.push(PlannedWrite {
+                    structure,
+                    offset: start + first as u64,
+                    old: old[first..=last].to_vec(),
+                    new: new[first..=last].to_vec(),
+                });
+            }
+        }
+
+        Ok(WritePlan { writes })
+    }
+}
+
+fn header_range(header: &Header, lb_size: u64) -> Range<u64> {
+    let start = header.current_lba.saturating_mul(lb_size);
+    start..start.saturating_add(lb_size)
+}
+
+fn entries_range(header: &Header, lb_size: u64) -> Range<u64> {
+    let start = header.part_start.saturating_mul(lb_size);
+    let len = u64::from(header.num_parts).saturating_mul(header.part_size.into());
+    // the array always occupies whole blocks
+    let len = len.saturating_add(lb_size - 1) / lb_size * lb_size;
+    start..start.saturating_add(len)
+}
@@ -0,0 +1,203 @@
+//! A device recording writes instead of applying them.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Wraps a device, reads go to the device (with all recorded writes
+/// applied on top), writes are only kept in memory.
+#[derive(Debug)]
+pub(crate) struct RecordingDevice<'a, D> {
+    inner: &'a mut D,
+    pos: u64,
+    len: u64,
+    /// every write in the order it happened
+    writes: Vec<(u64, Vec<u8>)>,
+}
+
+/// A contiguous region of the device touched by the recorded writes.
+#[derive(Debug)]
+pub(crate) struct Region {
+    /// offset in bytes
+    pub offset: u64,
+    /// bytes currently on the device
+    pub old: Vec<u8>,
+    /// bytes after applying the recorded writes
+    pub new: Vec<u8>,
+}
+
+impl<'a, D: Read + Seek> RecordingDevice<'a, D> {
+    pub fn new(inner: &'a mut D) -> io::Result<Self> {
+        let pos = inner.stream_position()?;
+        let len = inner.seek(SeekFrom::End(0))?;
+        inner.seek(SeekFrom::Start(pos))?;
+
+        Ok(Self {
+            inner,
+            pos,
+            len,
+            writes: vec![],
+        })
+    }
+
+    /// Returns the recorded writes merged into non overlapping regions,
+    /// sorted by offset. Each region is extended to the given block size,
+    /// the bytes which were not written are read from the underlying device.
+    pub fn into_regions(self, block_size: u64) -> io::Result<Vec<Region>> {
+        let mut spans: Vec<(u64, u64)> = self
+            .writes
+            .iter()
+            .map(|(offset, data)| {
+                let start = offset / block_size * block_size;
+                let end = offset + data.len() as u64;
+                let end = (end + block_size - 1) / block_size * block_size;
+                (start, end.min(self.len.max(start)))
+            })
+            .collect();
+        spans.sort_unstable();
+
+        let mut merged: Vec<(u64, u64)> = vec![];
+        for (start, end) in spans {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+
+        let mut regions = Vec::with_capacity(merged.len());
+        for (start, end) in merged {
+            let mut old = vec![0; (end - start) as usize];
+            self.inner.seek(SeekFrom::Start(start))?;
+            read_full(self.inner, &mut old)?;
+
+            let mut new = old.clone();
+            for (offset, data) in &self.writes {
+                overlay(&mut new, start, *offset, data);
+            }
+            regions.push(Region {
+                offset: start,
+                old,
+                new,
+            });
+        }
+
+        Ok(regions)
+    }
+}
+
+/// Copies the part of `data` (located at `data_offset`) overlapping
+/// `buf` (located at `buf_offset`) into `buf`.
+fn overlay(buf: &mut [u8], buf_offset: u64, data_offset: u64, data: &[u8]) {
+    let start = buf_offset.max(data_offset);
+    let end = (buf_offset + buf.len() as u64).min(data_offset + data.len() as u64);
+    if start >= end {
+        return;
+    }
+
+    let dst = (start - buf_offset) as usize..(end - buf_offset) as usize;
+    let src = (start - data_offset) as usize..(end - data_offset) as usize;
+    buf[dst].copy_from_slice(&data[src]);
+}
+
+/// Reads until the buffer is full or the end of the device is reached,
+/// the remaining bytes are left untouched.
+fn read_full<R: Read>(r: &mut R, mut buf: &mut [u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+        match r.read(buf) {
+            Ok(0) => break,
+            Ok(n) => buf = &mut buf[n..],
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+impl<'a, D: Read + Seek> Read for RecordingDevice<'a, D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (self.len.saturating_sub(self.pos)).min(buf.len() as u64) as usize;
+        let buf = &mut buf[..n];
+        buf.fill(0);
+
+        self.inner.seek(SeekFrom::Start(self.pos))?;
+        read_full(self.inner, buf)?;
+        for (offset, data) in &self.writes {
+            overlay(buf, self.pos, *offset, data);
+        }
+
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<'a, D> Write for RecordingDevice<'a, D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes.push((self.pos, buf.to_vec()));
+        self.pos += buf.len() as u64;
+        self.len = self.len.max(self.pos);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a, D> Seek for RecordingDevice<'a, D> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let n_pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(p) => add_signed(self.len, p),
+            SeekFrom::Current(p) => add_signed(self.pos, p),
+        };
+        self.pos = n_pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+fn add_signed(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.unsigned_abs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn record_and_merge() {
+        let mut inner = Cursor::new(vec![1u8; 64]);
+        let mut dev = RecordingDevice::new(&mut inner).unwrap();
+
+        dev.seek(SeekFrom::Start(10)).unwrap();
+        dev.write_all(&[2; 4]).unwrap();
+        dev.seek(SeekFrom::Start(12)).unwrap();
+        dev.write_all(&[3; 4]).unwrap();
+        dev.seek(SeekFrom::Start(40)).unwrap();
+        dev.write_all(&[4; 2]).unwrap();
+
+        // reads see the recorded writes
+        let mut buf = [0; 8];
+        dev.seek(SeekFrom::Start(9)).unwrap();
+        dev.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 2, 3, 3, 3, 3, 1]);
+
+        let regions = dev.into_regions(16).unwrap();
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].offset, 0);
+        assert_eq!(regions[0].old, vec![1; 16]);
+        assert_eq!(regions[0].new[9..16], [1, 2, 2, 3, 3, 3, 3]);
+        assert_eq!(regions[1].offset, 32);
+        assert_eq!(regions[1].new[8..10], [4, 4]);
+
+        // nothing reached the device
+        assert_eq!(inner.into_inner(), vec![1u8; 64]);
+    }
+}
@@ -0,0 +1,297 @@
+//! Repairing a damaged primary or backup GPT from the other copy.
+//!
+//! `GptConfig::open_from_device` accepts a disk with one damaged header
+//! (unless `only_valid_headers` is set), these operations rebuild the
+//! damaged copy on disk. Similar to the recovery menu of gdisk, every
+//! operation only touches the structures it names and recomputes the
+//! CRCs of the headers it writes.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::header::{self, Header, HeaderError};
+use crate::{partition, DiskDevice, GptDisk, GptError, GptStructure};
+
+/// A structure written by a repair operation.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RepairedStructure {
+    /// The structure which was written.
+    pub structure: GptStructure,
+    /// The LBA the structure starts at.
+    pub lba: u64,
+    /// The CRC32 of the structure before the repair, if it could be read.
+    ///
+    /// For headers this is the header CRC, for partition arrays the
+    /// CRC over the whole array.
+    pub old_crc32: Option<u32>,
+    /// The CRC32 of the structure after the repair.
+    pub new_crc32: u32,
+}
+
+/// What a repair operation wrote to the disk.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct RepairReport {
+    /// The structure the data was taken from.
+    pub source: Option<GptStructure>,
+    /// Every structure written, in the order it was written.
+    pub repaired: Vec<RepairedStructure>,
+}
+
+impl<D> GptDisk<D>
+where
+    D: DiskDevice,
+{
+    /// Rebuild the primary header from the backup header.
+    ///
+    /// The new header points to the partition array where the old primary
+    /// header did if it is valid, otherwise to `GptConfig::primary_part_start`.
+    /// If that is not set either the array is expected at the usual
+    /// location after the primary header, if the backup header has its
+    /// first usable lba directly behind it. Otherwise this fails with
+    /// `GptError::UnknownPartitionArrayLocation`, so that nothing gets
+    /// written over data in front of a custom array location.
+    ///
+    /// The CRCs of the new header are computed from what is currently on
+    /// disk in the array.
+    pub fn rebuild_primary_header(&mut self) -> Result<RepairReport, GptError> {
+        self.check_writable()?;
+        let backup = self
+            .backup_header
+            .as_ref()
+            .map_err(invalid_header(GptStructure::BackupHeader))?;
+        let mut primary = self
+            .header_builder(backup)?
+            .primary(true)
+            .build(self.config.lb_size)?;
+        self.check_in_device(&primary)?;
+        let old_crc32 = self.primary_header.as_ref().ok().map(|h| h.crc32);
+
+        primary.write_primary(&mut self.device, self.config.lb_size)?;
+        self.sync()?;
+        debug!("rebuilt primary header: {:?}", primary);
+
+        let report = RepairReport {
+            source: Some(GptStructure::BackupHeader),
+            repaired: vec![header_repaired(&primary, old_crc32)],
+        };
+        self.primary_header = Ok(primary);
+        Ok(report)
+    }
+
+    /// Rebuild the backup header from the primary header.
+    ///
+    /// The new header is written at the backup LBA the primary header
+    /// points to. Like for `rebuild_primary_header` its partition array
+    /// is where the old backup header pointed to, at
+    /// `GptConfig::backup_part_start` or directly in front of the header
+    /// if the primary header has its last usable lba in front of an array
+    /// there. Its CRCs are computed from what is currently on disk in the
+    /// backup partition array.
+    pub fn rebuild_backup_header(&mut self) -> Result<RepairReport, GptError> {
+        self.check_writable()?;
+        let primary = self
+            .primary_header
+            .as_ref()
+            .map_err(invalid_header(GptStructure::PrimaryHeader))?;
+        let mut backup = self
+            .header_builder(primary)?
+            .primary(false)
+            .build(self.config.lb_size)?;
+        self.check_in_device(&backup)?;
+        let old_crc32 = self.backup_header.as_ref().ok().map(|h| h.crc32);
+
+        backup.write_backup(&mut self.device, self.config.lb_size)?;
+        self.sync()?;
+        debug!("rebuilt backup header: {:?}", backup);
+
+        let report = RepairReport {
+            source: Some(GptStructure::PrimaryHeader),
+            repaired: vec![header_repaired(&backup, old_crc32)],
+        };
+        self.backup_header = Ok(backup);
+        Ok(report)
+    }
+
+    /// Copy the backup partition array over the primary one and update
+    /// the CRC in the primary header.
+    ///
+    /// The entries are copied byte by byte, the in-memory partitions are
+    /// reloaded from the repaired array. Both headers need to be valid.
+    /// Fails with `GptError::UnwrittenChanges` if the partitions were
+    /// changed since they were read, as these changes would be lost.
+    pub fn restore_primary_entries(&mut self) -> Result<RepairReport, GptError> {
+        self.restore_entries(true)
+    }
+
+    /// Copy the primary partition array over the backup one and update
+    /// the CRC in the backup header.
+    ///
+    /// The entries are copied byte by byte, the in-memory partitions are
+    /// reloaded from the repaired array. Both headers need to be valid.
+    /// Fails with `GptError::UnwrittenChanges` if the partitions were
+    /// changed since they were read, as these changes would be lost.
+    pub fn restore_backup_entries(&mut self) -> Result<RepairReport, GptError> {
+        self.restore_entries(false)
+    }
+
+    /// Rewrite both headers with CRCs recomputed from their
+    /// partition arrays, leaving everything else as is.
+    ///
+    /// Headers which could not be read are skipped.
+    pub fn recompute_crcs(&mut self) -> Result<RepairReport, GptError> {
+        self.check_writable()?;
+        let lb_size = self.config.lb_size;
+        let mut report = RepairReport::default();
+
+        if let Ok(backup) = &self.backup_header {
+            let mut backup = backup.clone();
+            self.check_in_device(&backup)?;
+            let old_crc32 = Some(backup.crc32);
+            backup.write_backup(&mut self.device, lb_size)?;
+            report.repaired.push(header_repaired(&backup, old_crc32));
+            self.backup_header = Ok(backup);
+        }
+        if let Ok(primary) = &self.primary_header {
+            let mut primary = primary.clone();
+            self.check_in_device(&primary)?;
+            let old_crc32 = Some(primary.crc32);
+            primary.write_primary(&mut self.device, lb_size)?;
+            report.repaired.push(header_repaired(&primary, old_crc32));
+            self.primary_header = Ok(primary);
+        }
+        self.sync()?;
+
+        Ok(report)
+    }
+
+    fn restore_entries(&mut self, to_primary: bool) -> Result<RepairReport, GptError> {
+        self.check_writable()?;
+        let lb_size = self.config.lb_size;
+        let primary = self
+            .primary_header
+            .as_ref()
+            .map_err(invalid_header(GptStructure::PrimaryHeader))?;
+        let backup = self
+            .backup_header
+            .as_ref()
+            .map_err(invalid_header(GptStructure::BackupHeader))?;
+        if primary.num_parts != backup.num_parts || primary.part_size != backup.part_size {
+            return Err(GptError::PartitionArrayMismatch);
+        }
+        // the partitions get reloaded, they need to be one of the arrays
+        let unchanged = [primary, backup].iter().any(|h| {
+            partition::file_read_partitions(&mut self.device, h, lb_size)
+                .map_or(false, |p| p == self.partitions)
+        });
+        if !unchanged {
+            return Err(GptError::UnwrittenChanges);
+        }
+
+        let (source, mut target) = if to_primary {
+            (backup.clone(), primary.clone())
+        } else {
+            (primary.clone(), backup.clone())
+        };
+        let (source_structure, entries_structure) = if to_primary {
+            (GptStructure::BackupEntries, GptStructure::PrimaryEntries)
+        } else {
+            (GptStructure::PrimaryEntries, GptStructure::BackupEntries)
+        };
+
+        self.check_in_device(&target)?;
+        let old_entries_crc32 = header::partentry_checksum(&mut self.device, &target, lb_size)?;
+        let old_header_crc32 = Some(target.crc32);
+
+        let len = u64::from(source.num_parts)
+            .checked_mul(source.part_size.into())
+            .ok_or(GptError::Overflow("partition array size"))?;
+        let from = source
+            .part_start
+            .checked_mul(lb_size.into())
+            .ok_or(GptError::Overflow("partition array start"))?;
+        let to = target
+            .part_start
+            .checked_mul(lb_size.into())
+            .ok_or(GptError::Overflow("partition array start"))?;
+        debug!("copying {} bytes of entries from {} to {}", len, from, to);
+        copy_bytes(&mut self.device, from, to, len)?;
+
+        if to_primary {
+            target.write_primary(&mut self.device, lb_size)?;
+        } else {
+            target.write_backup(&mut self.device, lb_size)?;
+        }
+        self.sync()?;
+
+        let report = RepairReport {
+            source: Some(source_structure),
+            repaired: vec![
+                RepairedStructure {
+                    structure: entries_structure,
+                    lba: target.part_start,
+                    old_crc32: Some(old_entries_crc32),
+                    new_crc32: target.crc32_parts,
+                },
+                header_repaired(&target, old_header_crc32),
+            ],
+        };
+
+        self.partitions = partition::file_read_partitions(&mut self.device, &target, lb_size)?;
+        if to_primary {
+            self.primary_header = Ok(target);
+        } else {
+            self.backup_header = Ok(target);
+        }
+
+        Ok(report)
+    }
+
+    fn check_writable(&self) -> Result<(), GptError> {
+        if self.config.writable {
+            Ok(())
+        } else {
+            Err(GptError::ReadOnly)
+        }
+    }
+}
+
+fn invalid_header(structure: GptStructure) -> impl Fn(&HeaderError) -> GptError {
+    move |e| GptError::InvalidHeader {
+        structure,
+        error: e.lossy_clone(),
+    }
+}
+
+fn header_repaired(header: &Header, old_crc32: Option<u32>) -> RepairedStructure {
+    let primary = header.current_lba < header.backup_lba;
+    RepairedStructure {
+        structure: if primary {
+            GptStructure::PrimaryHeader
+        } else {
+            GptStructure::BackupHeader
+        },
+        lba: header.current_lba,
+        old_crc32,
+        new_crc32: header.crc32,
+    }
+}
+
+/// Copies `len` bytes from `from` to `to`, the ranges may not overlap.
+fn copy_bytes<D: Read + Write + Seek>(
+    device: &mut D,
+    from: u64,
+    to: u64,
+    len: u64,
+) -> std::io::Result<()> {
+    const CHUNK: u64 = 64 * 1024;
+    let mut buf = vec![0; CHUNK.min(len) as usize];
+    let mut done = 0;
+    while done < len {
+        let n = CHUNK.min(len - done) as usize;
+        device.seek(SeekFrom::Start(from + done))?;
+        device.read_exact(&mut buf[..n])?;
+        device.seek(SeekFrom::Start(to + done))?;
+        device.write_all(&buf[..n])?;
+        done += n as u64;
+    }
+    Ok(())
+}
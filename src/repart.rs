@@ -0,0 +1,459 @@
+//! Reading and writing systemd-repart partition definition files.
+//!
+//! A `RepartDefinition` is the `[Partition]` section of a `repart.d`
+//! `.conf` file. It can be parsed from a file, turned into the
+//! `PartitionSpec`s of a `Layout` to reconcile a disk with and written
+//! back. `GptDisk::repart_definitions` describes an existing table.
+//!
+//! The settings `Type=`, `Label=`, `UUID=`, `SizeMinBytes=`,
+//! `SizeMaxBytes=`, `Priority=`, `Weight=`, `PaddingMinBytes=`, `Flags=`
+//! and `GrowFileSystem=` are supported, other settings are ignored.
+//! `Priority=`, `Weight=` and `PaddingMinBytes=` are kept but can't be
+//! reconciled: partitions are placed in the order of the definitions and
+//! free space goes to the growable partitions in order, so `layout` fails
+//! with `RepartError::Unsupported` if they are not the default.
+//!
+//! ## Example
+//!
+//! ```rust,no_run
+//! use gpt::repart::{self, RepartDefinition};
+//!
+//! let esp: RepartDefinition = "[Partition]\nType=esp\nSizeMinBytes=512M\nSizeMaxBytes=512M\n"
+//!     .parse()
+//!     .unwrap();
+//! let root: RepartDefinition = "[Partition]\nType=root-x86-64\nGrowFileSystem=yes\n"
+//!     .parse()
+//!     .unwrap();
+//!
+//! let mut disk = gpt::GptConfig::new()
+//!     .writable(true)
+//!     .open("/dev/sdz")
+//!     .unwrap();
+//! let plan = disk.plan_layout(&repart::layout(&[esp, root]).unwrap()).unwrap();
+//! disk.apply_layout(&plan).unwrap();
+//!
+//! for definition in disk.repart_definitions() {
+//!     println!("{definition}");
+//! }
+//! disk.write().unwrap();
+//! ```
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::layout::{Layout, PartitionSpec};
+use crate::partition_size::PartitionSize;
+use crate::partition_types::{self, Type};
+use crate::{DiskDevice, GptDisk};
+
+/// The partition attribute systemd-growfs looks for.
+pub const GROW_FILE_SYSTEM_FLAG: u64 = 1 << 59;
+
+/// The minimum size systemd-repart gives partitions without `SizeMinBytes=`.
+pub const DEFAULT_SIZE_MIN_BYTES: u64 = 10 * 1024 * 1024;
+
+/// The default `Weight=`.
+pub const DEFAULT_WEIGHT: u32 = 1000;
+
+/// The type names of systemd-repart which have a constant in `partition_types`.
+const TYPE_NAMES: &[(&str, Type)] = &[
+    ("esp", partition_types::EFI),
+    ("xbootldr", partition_types::FREEDESK_BOOT),
+    ("swap", partition_types::LINUX_SWAP),
+    ("home", partition_types::LINUX_HOME),
+    ("srv", partition_types::LINUX_SRV),
+    ("linux-generic", partition_types::LINUX_FS),
+    ("root-x86", partition_types::LINUX_ROOT_X86),
+    ("root-x86-64", partition_types::LINUX_ROOT_X64),
+    ("root-arm", partition_types::LINUX_ROOT_ARM_32),
+    ("root-arm64", partition_types::LINUX_ROOT_ARM_64),
+];
+
+/// Looks up a systemd-repart type name like `esp` or `root-x86-64`, or a
+/// type GUID.
+pub fn type_from_name(name: &str) -> Option<Type> {
+    TYPE_NAMES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, t)| t.clone())
+        .or_else(|| uuid::Uuid::parse_str(name).ok().map(Type::from))
+}
+
+/// The systemd-repart name of a partition type, None if it has none.
+pub fn type_name(part_type: &Type) -> Option<&'static str> {
+    TYPE_NAMES
+        .iter()
+        .find(|(_, t)| t.guid == part_type.guid)
+        .map(|(n, _)| *n)
+}
+
+/// Errors returned when parsing a partition definition or turning it
+/// into a layout.
+#[non_exhaustive]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RepartError {
+    /// The line is neither a section, a setting nor a comment
+    InvalidLine(usize),
+    /// The value of a setting is not valid
+    InvalidValue {
+        /// The line of the setting
+        line: usize,
+        /// The name of the setting
+        key: String,
+        /// The invalid value
+        value: String,
+    },
+    /// `Type=` is missing
+    MissingType,
+    /// The setting with the given name has a value which can't be reconciled
+    Unsupported(&'static str),
+}
+
+impl std::error::Error for RepartError {}
+
+impl fmt::Display for RepartError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use RepartError::*;
+        match self {
+            InvalidLine(line) => write!(fmt, "line {line} is not a valid setting"),
+            InvalidValue { line, key, value } => {
+                write!(fmt, "invalid value {value:?} for {key}= in line {line}")
+            }
+            MissingType => write!(fmt, "the partition definition has no Type="),
+            Unsupported(key) => write!(fmt, "{key}= is only supported with its default"),
+        }
+    }
+}
+
+/// The `[Partition]` section of a systemd-repart definition file.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RepartDefinition {
+    /// `Type=`, the partition type.
+    pub part_type: Type,
+    /// `Label=`, the partition name.
+    pub label: Option<String>,
+    /// `UUID=`, the partition GUID.
+    pub uuid: Option<uuid::Uuid>,
+    /// `SizeMinBytes=`, the minimum size.
+    pub size_min_bytes: Option<u64>,
+    /// `SizeMaxBytes=`, the maximum size.
+    pub size_max_bytes: Option<u64>,
+    /// `Priority=`, partitions with higher values are dropped first if
+    /// there is not enough space.
+    pub priority: i32,
+    /// `Weight=`, the share of the free space the partition grows into.
+    pub weight: u32,
+    /// `PaddingMinBytes=`, the free space to keep after the partition.
+    pub padding_min_bytes: Option<u64>,
+    /// `Flags=`, the partition attribute flags.
+    pub flags: Option<u64>,
+    /// `GrowFileSystem=`, sets `GROW_FILE_SYSTEM_FLAG`.
+    pub grow_file_system: Option<bool>,
+}
+
+impl RepartDefinition {
+    /// A definition of the given type with every other setting at its
+    /// default.
+    pub fn new(part_type: Type) -> Self {
+        Self {
+            part_type,
+            label: None,
+            uuid: None,
+            size_min_bytes: None,
+            size_max_bytes: None,
+            priority: 0,
+            weight: DEFAULT_WEIGHT,
+            padding_min_bytes: None,
+            flags: None,
+            grow_file_system: None,
+        }
+    }
+
+    /// The name of the partition, like systemd-repart the type name if
+    /// there is no `Label=`.
+    pub fn name(&self) -> String {
+        match &self.label {
+            Some(label) => label.clone(),
+            None => type_name(&self.part_type).unwrap_or_default().to_string(),
+        }
+    }
+
+    /// The attribute flags of the partition.
+    pub fn partition_flags(&self) -> u64 {
+        let flags = self.flags.unwrap_or(0);
+        match self.grow_file_system {
+            Some(true) => flags | GROW_FILE_SYSTEM_FLAG,
+            Some(false) => flags & !GROW_FILE_SYSTEM_FLAG,
+            None => flags,
+        }
+    }
+
+    /// The spec of the partition, growing without limit if there is no
+    /// `SizeMaxBytes=`.
+    ///
+    /// Fails with `RepartError::Unsupported` if `Priority=`, `Weight=` or
+    /// `PaddingMinBytes=` are set to something else than their default.
+    pub fn to_spec(&self) -> Result<PartitionSpec, RepartError> {
+        if self.priority != 0 {
+            return Err(RepartError::Unsupported("Priority"));
+        }
+        if self.weight != DEFAULT_WEIGHT {
+            return Err(RepartError::Unsupported("Weight"));
+        }
+        if self.padding_min_bytes.map_or(false, |p| p != 0) {
+            return Err(RepartError::Unsupported("PaddingMinBytes"));
+        }
+
+        let size = self.size_min_bytes.unwrap_or(DEFAULT_SIZE_MIN_BYTES);
+        let max_size = match self.size_max_bytes {
+            Some(max) => PartitionSize::Bytes(max),
+            None => PartitionSize::Remaining,
+        };
+        let spec = PartitionSpec::new(&self.name(), self.part_type.clone(), size)
+            .max_size(max_size)
+            .flags(self.partition_flags());
+        Ok(match self.uuid {
+            Some(uuid) => spec.guid(uuid),
+            None => spec,
+        })
+    }
+}
+
+/// The layout described by the definitions, in the given order (the
+/// order of their file names for systemd-repart).
+///
+/// Fails if a definition can't be reconciled, see `RepartDefinition::to_spec`.
+pub fn layout(definitions: &[RepartDefinition]) -> Result<Layout, RepartError> {
+    definitions.iter().try_fold(
+        Layout::new(),
+        |layout, d| Ok(layout.partition(d.to_spec()?)),
+    )
+}
+
+impl FromStr for RepartDefinition {
+    type Err = RepartError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut part_type = None;
+        let mut definition = Self::new(partition_types::UNUSED);
+        let mut in_partition = false;
+
+        for (i, line) in s.lines().enumerate() {
+            let line_nr = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                in_partition = line == "[Partition]";
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or(RepartError::InvalidLine(line_nr))?;
+            if !in_partition {
+                continue;
+            }
+            let (key, value) = (key.trim(), value.trim());
+            let invalid = || RepartError::InvalidValue {
+                line: line_nr,
+                key: key.to_string(),
+                value: value.to_string(),
+            };
+
+            match key {
+                "Type" => part_type = Some(type_from_name(value).ok_or_else(invalid)?),
+                "Label" => definition.label = Some(value.replace("%%", "%")),
+                "UUID" => definition.uuid = Some(value.parse().map_err(|_| invalid())?),
+                "SizeMinBytes" => {
+                    definition.size_min_bytes = Some(parse_bytes(value).ok_or_else(invalid)?)
+                }
+                "SizeMaxBytes" => {
+                    definition.size_max_bytes = Some(parse_bytes(value).ok_or_else(invalid)?)
+                }
+                "Priority" => definition.priority = value.parse().map_err(|_| invalid())?,
+                "Weight" => definition.weight = value.parse().map_err(|_| invalid())?,
+                "PaddingMinBytes" => {
+                    definition.padding_min_bytes = Some(parse_bytes(value).ok_or_else(invalid)?)
+                }
+                "Flags" => definition.flags = Some(parse_flags(value).ok_or_else(invalid)?),
+                "GrowFileSystem" => {
+                    definition.grow_file_system = Some(parse_bool(value).ok_or_else(invalid)?)
+                }
+                _ => {
+                    debug!("ignoring repart setting {}={}", key, value);
+                }
+            }
+        }
+
+        definition.part_type = part_type.ok_or(RepartError::MissingType)?;
+        Ok(definition)
+    }
+}
+
+impl fmt::Display for RepartDefinition {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(fmt, "[Partition]")?;
+        match type_name(&self.part_type) {
+            Some(name) => writeln!(fmt, "Type={name}")?,
+            None => writeln!(fmt, "Type={}", self.part_type.guid)?,
+        }
+        if let Some(label) = &self.label {
+            writeln!(fmt, "Label={}", label.replace('%', "%%"))?;
+        }
+        if let Some(uuid) = &self.uuid {
+            writeln!(fmt, "UUID={uuid}")?;
+        }
+        if let Some(size) = self.size_min_bytes {
+            writeln!(fmt, "SizeMinBytes={size}")?;
+        }
+        if let Some(size) = self.size_max_bytes {
+            writeln!(fmt, "SizeMaxBytes={size}")?;
+        }
+        if self.priority != 0 {
+            writeln!(fmt, "Priority={}", self.priority)?;
+        }
+        if self.weight != DEFAULT_WEIGHT {
+            writeln!(fmt, "Weight={}", self.weight)?;
+        }
+        if let Some(size) = self.padding_min_bytes {
+            writeln!(fmt, "PaddingMinBytes={size}")?;
+        }
+        if let Some(flags) = self.flags {
+            writeln!(fmt, "Flags={flags:#x}")?;
+        }
+        if let Some(grow) = self.grow_file_system {
+            writeln!(fmt, "GrowFileSystem={}", if grow { "yes" } else { "no" })?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a size in bytes like `512M`, the suffixes are powers of 1024.
+fn parse_bytes(value: &str) -> Option<u64> {
+    match value.parse() {
+        Ok(PartitionSize::Bytes(bytes)) => Some(bytes),
+        _ => None,
+    }
+}
+
+/// Parses flags in hexadecimal (with `0x`) or decimal.
+fn parse_flags(value: &str) -> Option<u64> {
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "yes" | "y" | "true" | "t" | "on" => Some(true),
+        "0" | "no" | "n" | "false" | "f" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+impl<D> GptDisk<D>
+where
+    D: DiskDevice,
+{
+    /// Describes the used partitions as systemd-repart definitions, in
+    /// the order they are placed on the disk.
+    ///
+    /// Every definition has a fixed size and the GUID of the partition,
+    /// so reconciling the disk with them changes nothing.
+    pub fn repart_definitions(&self) -> Vec<RepartDefinition> {
+        let lb_size = self.config.lb_size;
+        let mut partitions: Vec<_> = self.partitions.values().filter(|p| p.is_used()).collect();
+        partitions.sort_by_key(|p| p.first_lba);
+
+        partitions
+            .into_iter()
+            .map(|p| {
+                let size = p.bytes_len(lb_size).ok();
+                RepartDefinition {
+                    label: Some(p.name.clone()),
+                    uuid: Some(p.part_guid),
+                    size_min_bytes: size,
+                    size_max_bytes: size,
+                    flags: Some(p.flags).filter(|f| *f != 0),
+                    ..RepartDefinition::new(p.part_type_guid.clone())
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_definition() {
+        let d: RepartDefinition = "# the root partition\n\
+            [Partition]\n\
+            Type=root-x86-64\n\
+            Label=root %%a\n\
+            UUID=4b2f3c0e-2fb0-4a43-9bd5-3d0c55e8f1d1\n\
+            SizeMinBytes=1G\n\
+            SizeMaxBytes = 2G\n\
+            Priority=1\n\
+            Weight=500\n\
+            PaddingMinBytes=1M\n\
+            Flags=0x8000000000000000\n\
+            GrowFileSystem=yes\n\
+            Format=ext4\n\
+            \n\
+            [Other]\n\
+            Type=nonsense\n"
+            .parse()
+            .unwrap();
+
+        assert_eq!(d.part_type, partition_types::LINUX_ROOT_X64);
+        assert_eq!(d.label.as_deref(), Some("root %a"));
+        assert_eq!(
+            d.uuid.unwrap().to_string(),
+            "4b2f3c0e-2fb0-4a43-9bd5-3d0c55e8f1d1"
+        );
+        assert_eq!(d.size_min_bytes, Some(1 << 30));
+        assert_eq!(d.size_max_bytes, Some(2 << 30));
+        assert_eq!((d.priority, d.weight), (1, 500));
+        assert_eq!(d.padding_min_bytes, Some(1 << 20));
+        assert_eq!(d.partition_flags(), 1 << 63 | GROW_FILE_SYSTEM_FLAG);
+
+        // written and parsed again
+        assert_eq!(d.to_string().parse::<RepartDefinition>().unwrap(), d);
+        assert!(d.to_string().contains("Label=root %%a\n"));
+
+        let d: RepartDefinition = "[Partition]\nType=C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+            .parse()
+            .unwrap();
+        assert_eq!(d, RepartDefinition::new(partition_types::EFI));
+        assert_eq!(d.name(), "esp");
+        assert_eq!(d.to_string(), "[Partition]\nType=esp\n");
+    }
+
+    #[test]
+    fn test_parse_definition_errors() {
+        let parse = |s: &str| s.parse::<RepartDefinition>();
+        assert_eq!(parse("[Partition]\n"), Err(RepartError::MissingType));
+        assert_eq!(
+            parse("[Partition]\nType=esp\nWeight\n"),
+            Err(RepartError::InvalidLine(3))
+        );
+        assert_eq!(
+            parse("[Partition]\nType=root-sparc\n"),
+            Err(RepartError::InvalidValue {
+                line: 2,
+                key: "Type".into(),
+                value: "root-sparc".into()
+            })
+        );
+        assert!(parse("[Partition]\nType=esp\nSizeMinBytes=10%\n").is_err());
+        assert!(parse("[Partition]\nType=esp\nFlags=0xZZ\n").is_err());
+        assert!(parse("[Partition]\nType=esp\nGrowFileSystem=maybe\n").is_err());
+    }
+}
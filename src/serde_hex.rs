@@ -0,0 +1,36 @@
+//! Serializes byte buffers as lower case hex strings, for `serde(with)`.
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serializer};
+
+pub(crate) fn serialize<S, T>(bytes: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: AsRef<[u8]>,
+{
+    let hex: String = bytes.as_ref().iter().map(|b| format!("{b:02x}")).collect();
+    serializer.serialize_str(&hex)
+}
+
+pub(crate) fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<Vec<u8>>,
+{
+    let hex = String::deserialize(deserializer)?;
+    if hex.len() % 2 != 0 {
+        return Err(D::Error::custom("hex string of odd length"));
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .filter(|b| b.bytes().all(|c| c.is_ascii_hexdigit()))
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(|| D::Error::custom(format!("invalid hex string {hex:?}")))
+        })
+        .collect::<Result<Vec<u8>, _>>()?;
+    let len = bytes.len();
+
+    T::try_from(bytes).map_err(|_| D::Error::invalid_length(len, &"the buffer size"))
+}
@@ -0,0 +1,646 @@
+//! Reading and writing partition tables in the `sfdisk --dump` format.
+//!
+//! An `SfdiskScript` is the text `sfdisk --dump` prints and `sfdisk`
+//! reads back, for example:
+//!
+//! ```text
+//! label: gpt
+//! label-id: 5B2E5C9A-6C8D-4E0A-8F5E-2B1C0F3E9A11
+//! device: /dev/sda
+//! unit: sectors
+//! first-lba: 34
+//! last-lba: 1953525134
+//! sector-size: 512
+//!
+//! /dev/sda1 : start=        2048, size=     1048576, type=C12A7328-F81F-11D2-BA4B-00A0C93EC93B, uuid=0F8C4E5E-8B0B-4E3B-9C1A-6A2F1D4E7B21, name="EFI System Partition", attrs="RequiredPartition"
+//! ```
+//!
+//! `GptDisk::sfdisk_script` describes a disk, `GptDisk::load_sfdisk_script`
+//! replaces the partitions of a disk with the ones of a script. Partitions
+//! without `start=` are placed after the previous one, partitions without
+//! `size=` (or with `size=+`) take the remaining space and partitions
+//! without `type=` are Linux filesystems.
+//!
+//! ## Example
+//!
+//! ```rust,no_run
+//! use gpt::sfdisk::SfdiskScript;
+//!
+//! let script: SfdiskScript = std::fs::read_to_string("machine.sfdisk")
+//!     .unwrap()
+//!     .parse()
+//!     .unwrap();
+//!
+//! let mut disk = gpt::GptConfig::new()
+//!     .writable(true)
+//!     .open("/dev/sdz")
+//!     .unwrap();
+//! disk.load_sfdisk_script(&script).unwrap();
+//! print!("{}", disk.sfdisk_script().unwrap());
+//! disk.write().unwrap();
+//! ```
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::partition::Partition;
+use crate::partition_size::PartitionSize;
+use crate::partition_types::{self, Type};
+use crate::placement::Placement;
+use crate::{DiskDevice, GptDisk, GptError};
+
+/// Errors returned when parsing an `SfdiskScript`.
+#[non_exhaustive]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum SfdiskError {
+    /// The line is neither a header, a partition nor a comment
+    InvalidLine(usize),
+    /// The value of a header or partition field is not valid
+    InvalidValue {
+        /// The line of the value
+        line: usize,
+        /// The name of the header or field
+        key: String,
+        /// The invalid value
+        value: String,
+    },
+}
+
+impl std::error::Error for SfdiskError {}
+
+impl fmt::Display for SfdiskError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use SfdiskError::*;
+        match self {
+            InvalidLine(line) => write!(fmt, "line {line} is not a valid sfdisk line"),
+            InvalidValue { line, key, value } => {
+                write!(fmt, "invalid value {value:?} for {key} in line {line}")
+            }
+        }
+    }
+}
+
+/// A partition line of an sfdisk script.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SfdiskPartition {
+    /// The partition id, the number at the end of the device name.
+    pub id: Option<u32>,
+    /// `start=`, the first lba.
+    pub start: Option<u64>,
+    /// `size=`, a number of sectors, a size with a unit like `512MiB` or
+    /// `+` for the remaining space.
+    pub size: Option<PartitionSize>,
+    /// `type=`, the partition type.
+    pub part_type: Option<Type>,
+    /// `uuid=`, the partition GUID.
+    pub uuid: Option<uuid::Uuid>,
+    /// `name=`, the partition name.
+    pub name: Option<String>,
+    /// `attrs=`, the partition attribute flags.
+    pub attrs: Option<u64>,
+}
+
+/// A partition table in the `sfdisk --dump` format.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SfdiskScript {
+    /// `label-id:`, the disk GUID.
+    pub label_id: Option<uuid::Uuid>,
+    /// `device:`, the device the script was dumped from, partition lines
+    /// are prefixed with the partition device names if set.
+    pub device: Option<String>,
+    /// `first-lba:`, the first usable lba.
+    pub first_lba: Option<u64>,
+    /// `last-lba:`, the last usable lba.
+    pub last_lba: Option<u64>,
+    /// `sector-size:`, the logical block size.
+    pub sector_size: Option<u64>,
+    /// `table-length:`, the number of entries of the partition array.
+    pub table_length: Option<u32>,
+    /// The partitions in the order of the script.
+    pub partitions: Vec<SfdiskPartition>,
+}
+
+impl SfdiskScript {
+    /// The device name of partition `id`, like `/dev/sda1` or
+    /// `/dev/nvme0n1p1`.
+    fn partition_device(device: &str, id: u32) -> String {
+        match device.ends_with(|c: char| c.is_ascii_digit()) {
+            true => format!("{device}p{id}"),
+            false => format!("{device}{id}"),
+        }
+    }
+}
+
+impl fmt::Display for SfdiskScript {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(fmt, "label: gpt")?;
+        if let Some(label_id) = self.label_id {
+            writeln!(fmt, "label-id: {}", upper(&label_id))?;
+        }
+        if let Some(device) = &self.device {
+            writeln!(fmt, "device: {device}")?;
+        }
+        writeln!(fmt, "unit: sectors")?;
+        if let Some(first_lba) = self.first_lba {
+            writeln!(fmt, "first-lba: {first_lba}")?;
+        }
+        if let Some(last_lba) = self.last_lba {
+            writeln!(fmt, "last-lba: {last_lba}")?;
+        }
+        if let Some(table_length) = self.table_length {
+            writeln!(fmt, "table-length: {table_length}")?;
+        }
+        if let Some(sector_size) = self.sector_size {
+            writeln!(fmt, "sector-size: {sector_size}")?;
+        }
+        writeln!(fmt)?;
+
+        for part in &self.partitions {
+            let mut fields = vec![];
+            if let Some(start) = part.start {
+                fields.push(format!("start={start:>12}"));
+            }
+            if let Some(size) = part.size {
+                fields.push(format!("size={:>12}", format_size(size)));
+            }
+            if let Some(part_type) = &part.part_type {
+                fields.push(format!("type={}", upper(&part_type.guid)));
+            }
+            if let Some(uuid) = part.uuid {
+                fields.push(format!("uuid={}", upper(&uuid)));
+            }
+            if let Some(name) = &part.name {
+                fields.push(format!("name={}", quote(name)));
+            }
+            if let Some(attrs) = part.attrs.filter(|a| *a != 0) {
+                fields.push(format!("attrs={}", quote(&format_attrs(attrs))));
+            }
+
+            match (&self.device, part.id) {
+                (Some(device), Some(id)) => {
+                    let device = Self::partition_device(device, id);
+                    writeln!(fmt, "{device} : {}", fields.join(", "))?
+                }
+                _ => writeln!(fmt, "{}", fields.join(", "))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SfdiskScript {
+    type Err = SfdiskError;
+
+    /// Parses a script, comments start with `#` and unknown headers and
+    /// fields are ignored. Partitions have to use the named
+    /// `key=value` fields.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut script = Self::default();
+
+        for (i, line) in s.lines().enumerate() {
+            let line_nr = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |key: &str, value: &str| SfdiskError::InvalidValue {
+                line: line_nr,
+                key: key.to_string(),
+                value: value.to_string(),
+            };
+
+            let Some(eq) = line.find('=') else {
+                // a header
+                let (key, value) = line
+                    .split_once(':')
+                    .ok_or(SfdiskError::InvalidLine(line_nr))?;
+                let (key, value) = (key.trim(), value.trim());
+                match key {
+                    "label" if value == "gpt" => {}
+                    "unit" if value == "sectors" => {}
+                    "label" | "unit" => return Err(invalid(key, value)),
+                    "label-id" => {
+                        script.label_id = Some(value.parse().map_err(|_| invalid(key, value))?)
+                    }
+                    "device" => script.device = Some(value.to_string()),
+                    "first-lba" => {
+                        script.first_lba = Some(value.parse().map_err(|_| invalid(key, value))?)
+                    }
+                    "last-lba" => {
+                        script.last_lba = Some(value.parse().map_err(|_| invalid(key, value))?)
+                    }
+                    "sector-size" => {
+                        script.sector_size = Some(value.parse().map_err(|_| invalid(key, value))?)
+                    }
+                    "table-length" => {
+                        script.table_length = Some(value.parse().map_err(|_| invalid(key, value))?)
+                    }
+                    _ => {
+                        debug!("ignoring sfdisk header {}: {}", key, value);
+                    }
+                }
+                continue;
+            };
+
+            // a partition, maybe prefixed with its device name
+            let mut part = SfdiskPartition::default();
+            let fields = match line[..eq].find(':') {
+                Some(colon) => {
+                    let device = line[..colon].trim();
+                    let digits = device.trim_end_matches(|c: char| c.is_ascii_digit());
+                    part.id = Some(
+                        device[digits.len()..]
+                            .parse()
+                            .map_err(|_| invalid("device", device))?,
+                    );
+                    &line[colon + 1..]
+                }
+                None => line,
+            };
+            for field in split_fields(fields) {
+                let (key, value) = field
+                    .split_once('=')
+                    .ok_or(SfdiskError::InvalidLine(line_nr))?;
+                let (key, value) = (key.trim(), value.trim());
+                match key {
+                    "start" => part.start = Some(value.parse().map_err(|_| invalid(key, value))?),
+                    "size" => {
+                        part.size = Some(parse_size(value).ok_or_else(|| invalid(key, value))?)
+                    }
+                    "type" => {
+                        part.part_type = Some(parse_type(value).ok_or_else(|| invalid(key, value))?)
+                    }
+                    "uuid" => part.uuid = Some(value.parse().map_err(|_| invalid(key, value))?),
+                    "name" => part.name = Some(unquote(value).ok_or_else(|| invalid(key, value))?),
+                    "attrs" => {
+                        let attrs = unquote(value).ok_or_else(|| invalid(key, value))?;
+                        part.attrs = Some(parse_attrs(&attrs).ok_or_else(|| invalid(key, value))?)
+                    }
+                    _ => {
+                        debug!("ignoring sfdisk field {}={}", key, value);
+                    }
+                }
+            }
+            script.partitions.push(part);
+        }
+
+        Ok(script)
+    }
+}
+
+/// A GUID in upper case like sfdisk writes them.
+fn upper(guid: &uuid::Uuid) -> String {
+    guid.to_string().to_uppercase()
+}
+
+/// Sectors as a plain number, bytes with the largest binary unit which
+/// keeps them exact.
+fn format_size(size: PartitionSize) -> String {
+    match size {
+        PartitionSize::Lba(lba) => lba.to_string(),
+        PartitionSize::Remaining => "+".to_string(),
+        PartitionSize::PercentOfFree(percent) => format!("{percent}%"),
+        PartitionSize::Bytes(bytes) => {
+            let units = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+            let mut size = (bytes, "B");
+            for unit in units {
+                if size.0 == 0 || size.0 % 1024 != 0 {
+                    break;
+                }
+                size = (size.0 / 1024, unit);
+            }
+            format!("{}{}", size.0, size.1)
+        }
+    }
+}
+
+/// A number of sectors, `+` for the remaining space or a size with a unit.
+fn parse_size(value: &str) -> Option<PartitionSize> {
+    if value == "+" {
+        return Some(PartitionSize::Remaining);
+    }
+    match value.parse() {
+        Ok(lba) => Some(PartitionSize::Lba(lba)),
+        Err(_) => value.parse().ok(),
+    }
+}
+
+/// A type GUID or one of the sfdisk shortcuts.
+fn parse_type(value: &str) -> Option<Type> {
+    match value {
+        "L" => Some(partition_types::LINUX_FS),
+        "S" => Some(partition_types::LINUX_SWAP),
+        "H" => Some(partition_types::LINUX_HOME),
+        "U" => Some(partition_types::EFI),
+        "R" => Some(partition_types::LINUX_RAID),
+        "V" => Some(partition_types::LINUX_LVM),
+        value => uuid::Uuid::parse_str(value).ok().map(Type::from),
+    }
+}
+
+/// The names sfdisk uses for the attribute bits defined by UEFI.
+const ATTR_NAMES: [&str; 3] = [
+    "RequiredPartition",
+    "NoBlockIOProtocol",
+    "LegacyBIOSBootable",
+];
+
+/// Formats attribute flags like `RequiredPartition GUID:60,63`, bits
+/// without a name or type specific meaning as plain bit numbers.
+fn format_attrs(attrs: u64) -> String {
+    let mut words = vec![];
+    let mut guid_bits = vec![];
+    for bit in (0..64).filter(|bit| attrs & (1 << bit) != 0) {
+        match bit {
+            0..=2 => words.push(ATTR_NAMES[bit].to_string()),
+            3..=47 => words.push(bit.to_string()),
+            _ => guid_bits.push(bit.to_string()),
+        }
+    }
+    if !guid_bits.is_empty() {
+        words.push(format!("GUID:{}", guid_bits.join(",")));
+    }
+    words.join(" ")
+}
+
+fn parse_attrs(attrs: &str) -> Option<u64> {
+    let bit = |b: &str| b.parse::<u64>().ok().filter(|b| *b < 64);
+    let mut flags = 0;
+    for word in attrs.split_whitespace() {
+        if let Some(bits) = word.strip_prefix("GUID:") {
+            for b in bits.split(',') {
+                flags |= 1 << bit(b).filter(|b| *b >= 48)?;
+            }
+        } else if let Some(b) = ATTR_NAMES.iter().position(|n| *n == word) {
+            flags |= 1 << b;
+        } else {
+            flags |= 1 << bit(word)?;
+        }
+    }
+    Some(flags)
+}
+
+/// Quotes a string, escaping `"` and `\` with a backslash.
+fn quote(s: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Removes the quotes of a value written by `quote`, None if the quotes
+/// are unbalanced. Values without quotes are returned as they are.
+fn unquote(value: &str) -> Option<String> {
+    let Some(inner) = value.strip_prefix('"') else {
+        return Some(value.to_string());
+    };
+    let inner = inner.strip_suffix('"')?;
+    let mut s = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => s.push(chars.next()?),
+            '"' => return None,
+            c => s.push(c),
+        }
+    }
+    Some(s)
+}
+
+/// Splits the fields of a partition line at commas outside of quotes.
+fn split_fields(line: &str) -> Vec<&str> {
+    let mut fields = vec![];
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                fields.push(&line[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    fields.push(&line[start..]);
+    fields
+        .into_iter()
+        .filter(|f| !f.trim().is_empty())
+        .collect()
+}
+
+impl<D> GptDisk<D>
+where
+    D: DiskDevice,
+{
+    /// Describes the disk as an sfdisk script, with the used partitions in
+    /// the order of their ids and without a device name.
+    pub fn sfdisk_script(&self) -> Result<SfdiskScript, GptError> {
+        let header = self.header()?;
+        let partitions = self
+            .partitions
+            .iter()
+            .filter(|(_, p)| p.is_used())
+            .map(|(id, p)| {
+                Ok(SfdiskPartition {
+                    id: Some(*id),
+                    start: Some(p.first_lba),
+                    size: Some(PartitionSize::Lba(p.sectors_len()?)),
+                    part_type: Some(p.part_type_guid.clone()),
+                    uuid: Some(p.part_guid),
+                    name: Some(p.name.clone()),
+                    attrs: Some(p.flags).filter(|f| *f != 0),
+                })
+            })
+            .collect::<Result<_, GptError>>()?;
+
+        Ok(SfdiskScript {
+            label_id: Some(self.guid),
+            device: None,
+            first_lba: Some(header.first_usable),
+            last_lba: Some(header.last_usable),
+            sector_size: Some(self.config.lb_size.as_u64()),
+            table_length: Some(header.num_parts),
+            partitions,
+        })
+    }
+
+    /// Replaces the partitions with the ones of the script.
+    ///
+    /// Partitions keep the id of their device name, partitions without
+    /// one get the next free id. `label-id:` replaces the disk GUID and
+    /// `table-length:` the size of the partition array. The usable lbas
+    /// always come from the disk, `first-lba:` and `last-lba:` are not
+    /// used. Every partition is validated like `add_partition_at` does,
+    /// if one fails the disk is left unchanged.
+    ///
+    /// Fails with `GptError::LogicalBlockSizeMismatch` if the script was
+    /// dumped from a disk with another sector size.
+    ///
+    /// No changes are recorded to disk until `write()` is called.
+    pub fn load_sfdisk_script(&mut self, script: &SfdiskScript) -> Result<(), GptError> {
+        let lb_size = self.config.lb_size.as_u64();
+        if let Some(sector_size) = script.sector_size.filter(|s| *s != lb_size) {
+            return Err(GptError::LogicalBlockSizeMismatch {
+                expected: lb_size,
+                actual: sector_size,
+            });
+        }
+
+        let mut scratch = self.map_device(Ok)?;
+        scratch.partitions.clear();
+        if let Some(table_length) = script.table_length {
+            scratch.set_entry_capacity(table_length)?;
+        }
+        if let Some(label_id) = script.label_id {
+            scratch.update_guid(Some(label_id));
+        }
+
+        let mut previous_end = None;
+        for part in &script.partitions {
+            let id = match part.id {
+                Some(0) => return Err(GptError::InvalidPartitionId),
+                Some(id) if scratch.partitions.get(&id).map_or(false, |p| p.is_used()) => {
+                    return Err(GptError::PartitionIdAlreadyUsed(id))
+                }
+                Some(id) => id,
+                None => scratch.next_partition_id()?,
+            };
+            let size = part.size.unwrap_or(PartitionSize::Remaining);
+            let (first_lba, size_lba) = match part.start {
+                Some(start) => {
+                    // relative sizes refer to the free extent at `start`
+                    let free: Vec<(u64, u64)> = scratch
+                        .find_free_sectors()
+                        .into_iter()
+                        .filter(|(first, length)| *first <= start && start - first < *length)
+                        .map(|(first, length)| (start, length - (start - first)))
+                        .collect();
+                    (start, size.to_lba(lb_size, &free)?)
+                }
+                None => {
+                    let placement = match previous_end {
+                        Some(end) => Placement::Within {
+                            first_lba: end + 1,
+                            last_lba: u64::MAX,
+                        },
+                        None => scratch.config.placement,
+                    };
+                    scratch.locate(size, None, placement)?
+                }
+            };
+            let last_lba = first_lba
+                .checked_add(size_lba - 1)
+                .ok_or(GptError::Overflow("partition end"))?;
+
+            let partition = Partition {
+                part_type_guid: part.part_type.clone().unwrap_or(partition_types::LINUX_FS),
+                part_guid: part.uuid.unwrap_or_else(uuid::Uuid::new_v4),
+                first_lba,
+                last_lba,
+                flags: part.attrs.unwrap_or(0),
+                name: part.name.clone().unwrap_or_default(),
+                extra: vec![],
+            };
+            debug!("sfdisk: partition {}: {}", id, partition);
+            scratch.insert_partition(id, partition)?;
+            previous_end = Some(last_lba);
+        }
+
+        let guid = scratch.guid;
+        let primary_header = scratch.primary_header;
+        let backup_header = scratch.backup_header;
+        let partitions = scratch.partitions;
+        self.guid = guid;
+        self.primary_header = primary_header;
+        self.backup_header = backup_header;
+        self.partitions = partitions;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_script() {
+        let script: SfdiskScript = "label: gpt\n\
+            label-id: 5B2E5C9A-6C8D-4E0A-8F5E-2B1C0F3E9A11\n\
+            device: /dev/nvme0n1\n\
+            unit: sectors\n\
+            first-lba: 34\n\
+            last-lba: 1953525134\n\
+            sector-size: 512\n\
+            grain: 1048576\n\
+            \n\
+            /dev/nvme0n1p1 : start=2048, size=1048576, type=C12A7328-F81F-11D2-BA4B-00A0C93EC93B, \
+            uuid=0F8C4E5E-8B0B-4E3B-9C1A-6A2F1D4E7B21, name=\"EFI, \\\"System\\\"\", \
+            attrs=\"RequiredPartition LegacyBIOSBootable 10 GUID:59,63\"\n\
+            /dev/nvme0n1p3 : size=512MiB, type=S\n\
+            name=rest, size=+\n"
+            .parse()
+            .unwrap();
+
+        assert_eq!(script.device.as_deref(), Some("/dev/nvme0n1"));
+        assert_eq!(
+            (script.first_lba, script.last_lba),
+            (Some(34), Some(1953525134))
+        );
+        assert_eq!(script.sector_size, Some(512));
+        assert_eq!(script.partitions.len(), 3);
+        let esp = &script.partitions[0];
+        assert_eq!((esp.id, esp.start), (Some(1), Some(2048)));
+        assert_eq!(esp.size, Some(PartitionSize::Lba(1048576)));
+        assert_eq!(esp.part_type, Some(partition_types::EFI));
+        assert_eq!(esp.name.as_deref(), Some("EFI, \"System\""));
+        assert_eq!(esp.attrs, Some(1 | 1 << 2 | 1 << 10 | 1 << 59 | 1 << 63));
+        let swap = &script.partitions[1];
+        assert_eq!((swap.id, swap.start), (Some(3), None));
+        assert_eq!(swap.size, Some(PartitionSize::Bytes(512 << 20)));
+        assert_eq!(swap.part_type, Some(partition_types::LINUX_SWAP));
+        let rest = &script.partitions[2];
+        assert_eq!((rest.id, rest.name.as_deref()), (None, Some("rest")));
+        assert_eq!(rest.size, Some(PartitionSize::Remaining));
+
+        // written and parsed again
+        let text = script.to_string();
+        assert!(text.contains(
+            "/dev/nvme0n1p1 : start=        2048, size=     1048576, \
+            type=C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+        ));
+        assert!(text.contains("attrs=\"RequiredPartition LegacyBIOSBootable 10 GUID:59,63\"\n"));
+        assert!(text.contains("size=      512MiB"));
+        assert_eq!(text.parse::<SfdiskScript>().unwrap(), script);
+    }
+
+    #[test]
+    fn test_parse_script_errors() {
+        let parse = |s: &str| s.parse::<SfdiskScript>();
+        assert_eq!(
+            parse("label: dos\n"),
+            Err(SfdiskError::InvalidValue {
+                line: 1,
+                key: "label".into(),
+                value: "dos".into()
+            })
+        );
+        assert_eq!(
+            parse("label: gpt\nnonsense\n"),
+            Err(SfdiskError::InvalidLine(2))
+        );
+        assert_eq!(parse("start=1, 2048\n"), Err(SfdiskError::InvalidLine(1)));
+        assert!(parse("/dev/sda : start=1\n").is_err());
+        assert!(parse("start=1, size=1x\n").is_err());
+        assert!(parse("start=1, name=\"open\n").is_err());
+        assert!(parse("start=1, attrs=\"GUID:47\"\n").is_err());
+        assert!(parse("start=1, type=nonsense\n").is_err());
+    }
+}
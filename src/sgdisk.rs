@@ -0,0 +1,208 @@
+//! Backup files of partition tables in the format of `sgdisk --backup`.
+//!
+//! A backup file holds the protective MBR, the primary header, the backup
+//! header and the partition array, each starting at a 512 byte block and
+//! in this order, independent of the logical block size of the disk.
+//! `GptDisk::save_backup` writes such a file, `GptConfig::restore_backup`
+//! writes the table of one to a device like `sgdisk --load-backup`.
+//!
+//! ## Example
+//!
+//! ```rust,no_run
+//! let mut disk = gpt::GptConfig::new().open("/dev/sdz").unwrap();
+//! disk.save_backup("sdz.gpt").unwrap();
+//!
+//! let device = std::fs::OpenOptions::new()
+//!     .read(true)
+//!     .write(true)
+//!     .open("/dev/sdy")
+//!     .unwrap();
+//! let disk = gpt::GptConfig::new()
+//!     .writable(true)
+//!     .restore_backup(device, "sdz.gpt")
+//!     .unwrap();
+//! println!("restored {} partitions", disk.partitions().len());
+//! ```
+
+use std::io::{self, Read, Write};
+use std::{fs, path};
+
+use crate::disk::LogicalBlockSize;
+use crate::header::{self, HeaderBuilder};
+use crate::mbr::ProtectiveMBR;
+use crate::partition::{self, Partition, PartitionError};
+use crate::{geometry, DiskDevice, GptConfig, GptDisk, GptError, GptStructure};
+
+/// The size of the blocks of a backup file.
+const BLOCK: u64 = 512;
+
+/// The block of the partition array, after the MBR and both headers.
+const ENTRIES_BLOCK: u64 = 3;
+
+impl<D> GptDisk<D>
+where
+    D: DiskDevice,
+{
+    /// Saves the partition table to a backup file in the format of
+    /// `sgdisk --backup`.
+    pub fn save_backup(&mut self, path: impl AsRef<path::Path>) -> Result<(), GptError> {
+        debug!("saving backup to {}", path.as_ref().display());
+        let mut file = fs::File::create(path)?;
+        self.save_backup_to(&mut file)?;
+        file.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Writes the partition table in the format of `sgdisk --backup`.
+    ///
+    /// The MBR is read from the device, if it has none a new protective
+    /// MBR is saved. A missing header is rebuilt from the other one.
+    /// Fails with `PartitionError::ArrayBeyondDeviceEnd` if the header
+    /// describes a partition array which does not fit the device.
+    pub fn save_backup_to(&mut self, mut writer: impl Write) -> Result<(), GptError> {
+        let lb_size = self.config.lb_size;
+        let header = self.header()?.clone();
+        let mut primary = match &self.primary_header {
+            Ok(primary) => primary.clone(),
+            Err(_) => HeaderBuilder::from_header(&header)
+                .primary(true)
+                .build(lb_size)?,
+        };
+        let mut backup = match &self.backup_header {
+            Ok(backup) => backup.clone(),
+            Err(_) => HeaderBuilder::from_header(&header)
+                .primary(false)
+                .build(lb_size)?,
+        };
+
+        let mbr = match ProtectiveMBR::from_disk(&mut self.device, lb_size) {
+            Ok(mbr) => mbr,
+            Err(e) => {
+                debug!(
+                    "saving a new protective MBR instead of the invalid one: {}",
+                    e
+                );
+                let last_lba = primary.backup_lba.max(backup.backup_lba);
+                ProtectiveMBR::with_lb_size(u32::try_from(last_lba).unwrap_or(u32::MAX))
+            }
+        };
+
+        // don't trust the header to describe an array which fits the device
+        let device_size = self.device.seek(io::SeekFrom::End(0))?;
+        let end = u64::from(header.num_parts)
+            .checked_mul(u64::from(header.part_size))
+            .and_then(|len| len.checked_add(header.part_start.checked_mul(lb_size.as_u64())?))
+            .ok_or(GptError::Overflow("partition array end"))?;
+        if end > device_size {
+            return Err(PartitionError::ArrayBeyondDeviceEnd { end, device_size }.into());
+        }
+
+        let mut entries = vec![];
+        for id in 1..=header.num_parts {
+            let bytes = match self.partitions.get(&id) {
+                Some(part) => part.as_bytes(header.part_size)?,
+                None => Partition::zero().as_bytes(header.part_size)?,
+            };
+            entries.extend_from_slice(&bytes);
+        }
+        let crc32_parts = header::calculate_crc32(&entries);
+
+        let block = BLOCK as usize;
+        writer.write_all(&mbr.to_bytes())?;
+        writer.write_all(&primary.seal_block(crc32_parts, block))?;
+        writer.write_all(&backup.seal_block(crc32_parts, block))?;
+        writer.write_all(&entries)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+impl GptConfig {
+    /// Restores the partition table of a backup file written by
+    /// `GptDisk::save_backup` or `sgdisk --backup` to the device.
+    ///
+    /// See `restore_backup_from`.
+    pub fn restore_backup<D: DiskDevice>(
+        self,
+        device: D,
+        path: impl AsRef<path::Path>,
+    ) -> Result<GptDisk<D>, GptError> {
+        debug!("restoring backup from {}", path.as_ref().display());
+        let file = fs::File::open(path)?;
+        self.restore_backup_from(device, file)
+    }
+
+    /// Restores the partition table of a backup in the format of
+    /// `sgdisk --backup` to the device and returns the written disk.
+    ///
+    /// Both headers and the partition array need to have valid CRCs. If
+    /// the device has another size than the saved disk the backup header
+    /// and partition array are moved to its end and the protective MBR
+    /// partition is resized, which fails with `GptError::PartitionsDoNotFit`
+    /// if the device is too small for the partitions.
+    ///
+    /// The table and then the MBR are written right away, the config
+    /// needs to be writable.
+    pub fn restore_backup_from<D: DiskDevice>(
+        self,
+        device: D,
+        mut reader: impl Read,
+    ) -> Result<GptDisk<D>, GptError> {
+        if !self.writable {
+            return Err(GptError::ReadOnly);
+        }
+
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+        let mut file = io::Cursor::new(bytes);
+
+        let mut mbr_bytes = [0; BLOCK as usize];
+        file.read_exact(&mut mbr_bytes)?;
+        let mut mbr = ProtectiveMBR::from_bytes(&mbr_bytes, LogicalBlockSize::Lb512)?;
+
+        let invalid_header = |structure, error| GptError::InvalidHeader { structure, error };
+        let primary = header::file_read_header(&mut file, BLOCK)
+            .map_err(|e| invalid_header(GptStructure::PrimaryHeader, e))?;
+        let backup = header::file_read_header(&mut file, 2 * BLOCK)
+            .map_err(|e| invalid_header(GptStructure::BackupHeader, e))?;
+        // the array follows the headers instead of being where they say
+        let mut array = primary.clone();
+        array.part_start = ENTRIES_BLOCK;
+        let partitions =
+            partition::file_read_partitions(&mut file, &array, LogicalBlockSize::Lb512).map_err(
+                |error| GptError::InvalidPartitionArray {
+                    structure: GptStructure::PrimaryEntries,
+                    error,
+                },
+            )?;
+
+        let saved_backup_lba = primary.backup_lba;
+        let mut disk = GptDisk {
+            config: self,
+            device,
+            guid: primary.disk_guid,
+            primary_header: Ok(primary),
+            backup_header: Ok(backup),
+            partitions,
+            grown_to: None,
+            sync_all: None,
+        };
+
+        let last_lba = header::find_backup_lba(&mut disk.device, disk.config.lb_size)?;
+        if last_lba != saved_backup_lba {
+            debug!(
+                "device ends at lba {} instead of {}, moving the backup header",
+                last_lba, saved_backup_lba
+            );
+            disk.relocate_backup_to_end()?;
+            geometry::protect_up_to(&mut mbr, last_lba);
+        }
+
+        disk.write_inplace()?;
+        mbr.overwrite_lba0(&mut disk.device)?;
+
+        Ok(disk)
+    }
+}
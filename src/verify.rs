@@ -0,0 +1,671 @@
+//! Checking a GPT disk for problems, similar to the verify command of gdisk.
+//!
+//! `GptDisk::verify` reads the protective MBR, both headers and both
+//! partition arrays back from the device and returns a `VerifyReport`
+//! listing every problem found. Each `Finding` carries a `Severity`, so
+//! that for example misaligned partitions can be tolerated while a
+//! damaged table is not. Nothing is written to the device.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::header::{self, Header, HeaderError};
+use crate::mbr::ProtectiveMBR;
+use crate::partition::{self, Partition, PartitionError};
+use crate::{DiskDevice, GptDisk, GptError, GptStructure};
+
+/// The MBR partition type of the protective partition.
+const PROTECTIVE_OS_TYPE: u8 = 0xEE;
+
+/// How serious a problem is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub enum Severity {
+    /// The disk is usable, but something is unusual, for example a
+    /// partition which is not aligned.
+    Warning,
+    /// The table is damaged or violates the specification.
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let desc = match self {
+            Self::Warning => "warning",
+            Self::Error => "error",
+        };
+        write!(fmt, "{desc}")
+    }
+}
+
+/// A problem found by `GptDisk::verify`.
+#[non_exhaustive]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Problem {
+    /// The structure could not be read or is not valid
+    Unreadable {
+        /// The structure which could not be read
+        structure: GptStructure,
+        /// Why reading failed
+        reason: String,
+    },
+    /// The CRC32 stored for a header or partition array does not match
+    InvalidCRC32 {
+        /// The header or partition array with the wrong checksum
+        structure: GptStructure,
+        /// The checksum stored in the header
+        expected: u32,
+        /// The checksum of the data on the device
+        actual: u32,
+    },
+    /// The `current_lba` of a header is not the lba it was read from
+    WrongCurrentLba {
+        /// Either the primary or the backup header
+        structure: GptStructure,
+        /// The lba the header was read from
+        expected: u64,
+        /// The lba stored in the header
+        actual: u64,
+    },
+    /// The `backup_lba` of a header does not point to the other header
+    ///
+    /// For the primary header this is only a warning if the backup
+    /// header is located before the end of the device, which happens
+    /// when the device grew.
+    WrongBackupLba {
+        /// Either the primary or the backup header
+        structure: GptStructure,
+        /// The lba the other header is expected at
+        expected: u64,
+        /// The lba stored in the header
+        actual: u64,
+    },
+    /// The reserved field of a header is not zero
+    NonZeroReserved {
+        /// Either the primary or the backup header
+        structure: GptStructure,
+        /// The value of the reserved field
+        value: u32,
+    },
+    /// The primary and the backup header disagree on a field
+    HeadersDiffer {
+        /// The name of the field
+        field: &'static str,
+    },
+    /// The entry of a partition differs between the primary and the backup array
+    EntriesDiffer {
+        /// The id of the partition
+        id: u32,
+    },
+    /// The last lba of a partition is before its first lba
+    InvalidExtent {
+        /// The id of the partition
+        id: u32,
+        /// The first lba of the partition
+        first_lba: u64,
+        /// The last lba of the partition
+        last_lba: u64,
+    },
+    /// A partition is not inside the usable lbas of the disk
+    OutsideUsableRange {
+        /// The id of the partition
+        id: u32,
+        /// The first lba of the partition
+        first_lba: u64,
+        /// The last lba of the partition
+        last_lba: u64,
+    },
+    /// Two partitions overlap
+    Overlap {
+        /// The id of the partition which starts first
+        id: u32,
+        /// The id of the partition overlapping it
+        other: u32,
+    },
+    /// Two partitions have the same partition GUID
+    DuplicateGuid {
+        /// The id of the first partition with the GUID
+        id: u32,
+        /// The id of the other partition with the GUID
+        other: u32,
+        /// The duplicated GUID
+        guid: uuid::Uuid,
+    },
+    /// The MBR does not contain a protective partition
+    MissingProtectiveMbr,
+    /// The protective partition does not cover the disk
+    ProtectiveMbrSize {
+        /// The number of logical blocks after the MBR, at most `u32::MAX`
+        expected: u32,
+        /// The size of the protective partition
+        actual: u32,
+    },
+    /// A partition does not start at an lba aligned to the configured
+    /// alignment, see `GptConfig::alignment`
+    Misaligned {
+        /// The id of the partition
+        id: u32,
+        /// The first lba of the partition
+        first_lba: u64,
+        /// The expected alignment in lba
+        alignment: u64,
+    },
+    /// A partition does not start at a physical block, see
+    /// `GptConfig::physical_block_size`
+    PhysicalBlockMisaligned {
+        /// The id of the partition
+        id: u32,
+        /// The first lba of the partition
+        first_lba: u64,
+        /// The size of a physical block in lba
+        physical_block_lba: u64,
+    },
+}
+
+impl Problem {
+    /// Returns true if the problem is about the partition `id`.
+    fn concerns(&self, id: u32) -> bool {
+        use Problem::*;
+        match *self {
+            EntriesDiffer { id: i }
+            | InvalidExtent { id: i, .. }
+            | OutsideUsableRange { id: i, .. }
+            | Misaligned { id: i, .. }
+            | PhysicalBlockMisaligned { id: i, .. } => i == id,
+            Overlap { id: i, other, .. } | DuplicateGuid { id: i, other, .. } => {
+                i == id || other == id
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Problem::*;
+        match self {
+            Unreadable { structure, reason } => write!(fmt, "invalid {structure}: {reason}"),
+            InvalidCRC32 {
+                structure,
+                expected,
+                actual,
+            } => write!(
+                fmt,
+                "CRC32 mismatch in the {structure}, expected {expected:#010x} got {actual:#010x}"
+            ),
+            WrongCurrentLba {
+                structure,
+                expected,
+                actual,
+            } => write!(
+                fmt,
+                "the {structure} is at lba {expected} but claims to be at {actual}"
+            ),
+            WrongBackupLba {
+                structure,
+                expected,
+                actual,
+            } => write!(
+                fmt,
+                "the {structure} points to the other header at lba {actual} instead of {expected}"
+            ),
+            NonZeroReserved { structure, value } => {
+                write!(fmt, "the reserved field of the {structure} is {value:#x}")
+            }
+            HeadersDiffer { field } => {
+                write!(fmt, "the primary and backup header differ in {field}")
+            }
+            EntriesDiffer { id } => write!(
+                fmt,
+                "partition {id} differs between the primary and backup partition array"
+            ),
+            InvalidExtent {
+                id,
+                first_lba,
+                last_lba,
+            } => write!(
+                fmt,
+                "partition {id} ends at lba {last_lba} before it starts at {first_lba}"
+            ),
+            OutsideUsableRange {
+                id,
+                first_lba,
+                last_lba,
+            } => write!(
+                fmt,
+                "partition {id} ({first_lba}..={last_lba}) is outside the usable lbas"
+            ),
+            Overlap { id, other } => write!(fmt, "partitions {id} and {other} overlap"),
+            DuplicateGuid { id, other, guid } => {
+                write!(fmt, "partitions {id} and {other} have the same guid {guid}")
+            }
+            MissingProtectiveMbr => write!(fmt, "the MBR has no protective partition"),
+            ProtectiveMbrSize { expected, actual } => write!(
+                fmt,
+                "the protective partition is {actual} logical blocks large instead of {expected}"
+            ),
+            Misaligned {
+                id,
+                first_lba,
+                alignment,
+            } => write!(
+                fmt,
+                "partition {id} starts at lba {first_lba} which is not a multiple of {alignment}"
+            ),
+            PhysicalBlockMisaligned {
+                id,
+                first_lba,
+                physical_block_lba,
+            } => write!(
+                fmt,
+                "partition {id} starts at lba {first_lba} inside a physical block of \
+                {physical_block_lba} lba"
+            ),
+        }
+    }
+}
+
+/// A problem together with its severity.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Finding {
+    /// How serious the problem is.
+    pub severity: Severity,
+    /// What is wrong.
+    pub problem: Problem,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "{}: {}", self.severity, self.problem)
+    }
+}
+
+/// Every problem found by `GptDisk::verify`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct VerifyReport {
+    findings: Vec<Finding>,
+}
+
+impl VerifyReport {
+    /// All findings, in the order they were found.
+    pub fn findings(&self) -> &[Finding] {
+        &self.findings
+    }
+
+    /// Returns true if no problem was found.
+    pub fn is_empty(&self) -> bool {
+        self.findings.is_empty()
+    }
+
+    /// The severity of the most serious finding, None if there are none.
+    pub fn max_severity(&self) -> Option<Severity> {
+        self.findings.iter().map(|f| f.severity).max()
+    }
+
+    /// Returns the findings with the given severity.
+    pub fn severity(&self, severity: Severity) -> impl Iterator<Item = &Finding> {
+        self.findings.iter().filter(move |f| f.severity == severity)
+    }
+
+    fn push(&mut self, severity: Severity, problem: Problem) {
+        debug!("verify: {}: {}", severity, problem);
+        self.findings.push(Finding { severity, problem });
+    }
+
+    fn error(&mut self, problem: Problem) {
+        self.push(Severity::Error, problem);
+    }
+
+    fn warning(&mut self, problem: Problem) {
+        self.push(Severity::Warning, problem);
+    }
+}
+
+impl<D> GptDisk<D>
+where
+    D: DiskDevice,
+{
+    /// Checks the table on the device and reports every problem found.
+    ///
+    /// This checks the protective MBR, the location, CRCs and reserved
+    /// fields of both headers, that the primary and backup copies match
+    /// and that the partitions are inside the usable area, don't overlap,
+    /// have unique GUIDs and are aligned according to `GptConfig::alignment`
+    /// and `GptConfig::physical_block_size`.
+    ///
+    /// Changes which were not written yet are not checked. An error is
+    /// only returned if the device could not be accessed.
+    ///
+    /// ## Example
+    ///
+    /// ```rust,no_run
+    /// use gpt::verify::Severity;
+    ///
+    /// let mut disk = gpt::GptConfig::new().open("/dev/sdz").unwrap();
+    /// let report = disk.verify().unwrap();
+    /// for finding in report.findings() {
+    ///     println!("{finding}");
+    /// }
+    /// assert!(report.max_severity() < Some(Severity::Error));
+    /// ```
+    pub fn verify(&mut self) -> Result<VerifyReport, GptError> {
+        let lb_size = self.config.lb_size;
+        let mut report = VerifyReport::default();
+        let last_lba = header::find_backup_lba(&mut self.device, lb_size)?;
+
+        self.verify_mbr(&mut report, last_lba);
+
+        let primary = match header::read_header_at(&mut self.device, 1, lb_size) {
+            Ok(h) => Some(h),
+            Err(e) => {
+                report.header_error(GptStructure::PrimaryHeader, e);
+                None
+            }
+        };
+        let backup = self.read_backup(&mut report, primary.as_ref(), last_lba);
+
+        if let Some(primary) = &primary {
+            verify_header(
+                &mut report,
+                primary,
+                GptStructure::PrimaryHeader,
+                1,
+                last_lba,
+            );
+        }
+        if let Some((lba, backup)) = &backup {
+            verify_header(&mut report, backup, GptStructure::BackupHeader, *lba, 1);
+        }
+
+        let primary_entries = primary
+            .as_ref()
+            .and_then(|h| self.read_entries(&mut report, h, GptStructure::PrimaryEntries));
+        let backup_entries = backup
+            .as_ref()
+            .and_then(|(_, h)| self.read_entries(&mut report, h, GptStructure::BackupEntries));
+
+        if let (Some(primary), Some((_, backup))) = (&primary, &backup) {
+            compare_headers(&mut report, primary, backup);
+        }
+        if let (Some(primary), Some(backup)) = (&primary_entries, &backup_entries) {
+            compare_entries(&mut report, primary, backup);
+        }
+
+        let checked = match (&primary, primary_entries, &backup, backup_entries) {
+            (Some(h), Some(parts), _, _) | (_, _, Some((_, h)), Some(parts)) => Some((h, parts)),
+            _ => None,
+        };
+        if let Some((header, parts)) = checked {
+            let alignment = (self.alignment_lba(), self.physical_block_lba());
+            check_partitions(&mut report, header, &parts, Some(alignment));
+        }
+
+        Ok(report)
+    }
+
+    fn verify_mbr(&mut self, report: &mut VerifyReport, last_lba: u64) {
+        let mbr = match ProtectiveMBR::from_disk(&mut self.device, self.config.lb_size) {
+            Ok(mbr) => mbr,
+            Err(e) => {
+                return report.error(Problem::Unreadable {
+                    structure: GptStructure::ProtectiveMbr,
+                    reason: e.to_string(),
+                })
+            }
+        };
+
+        let protective = (0..4)
+            .filter_map(|i| mbr.partition(i))
+            .find(|p| p.os_type == PROTECTIVE_OS_TYPE);
+        let protective = match protective {
+            Some(p) => p,
+            None => return report.error(Problem::MissingProtectiveMbr),
+        };
+
+        let expected = u32::try_from(last_lba).unwrap_or(u32::MAX);
+        if protective.lb_start != 1 || protective.lb_size != expected {
+            report.warning(Problem::ProtectiveMbrSize {
+                expected,
+                actual: protective.lb_size,
+            });
+        }
+    }
+
+    /// Reads the backup header, first where the primary header points to
+    /// and then at the end of the device.
+    fn read_backup(
+        &mut self,
+        report: &mut VerifyReport,
+        primary: Option<&Header>,
+        last_lba: u64,
+    ) -> Option<(u64, Header)> {
+        let mut lbas = vec![];
+        if let Some(primary) = primary {
+            if primary.backup_lba > 1 && primary.backup_lba < last_lba {
+                lbas.push(primary.backup_lba);
+            }
+        }
+        lbas.push(last_lba);
+
+        let mut error = None;
+        for lba in lbas {
+            match header::read_header_at(&mut self.device, lba, self.config.lb_size) {
+                Ok(h) => return Some((lba, h)),
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(e) = error {
+            report.header_error(GptStructure::BackupHeader, e);
+        }
+
+        None
+    }
+
+    fn read_entries(
+        &mut self,
+        report: &mut VerifyReport,
+        header: &Header,
+        structure: GptStructure,
+    ) -> Option<BTreeMap<u32, Partition>> {
+        match partition::file_read_partitions(&mut self.device, header, self.config.lb_size) {
+            Ok(parts) => Some(parts),
+            Err(PartitionError::InvalidCRC32Checksum { expected, actual }) => {
+                report.error(Problem::InvalidCRC32 {
+                    structure,
+                    expected,
+                    actual,
+                });
+                None
+            }
+            Err(e) => {
+                report.error(Problem::Unreadable {
+                    structure,
+                    reason: e.to_string(),
+                });
+                None
+            }
+        }
+    }
+}
+
+impl VerifyReport {
+    fn header_error(&mut self, structure: GptStructure, error: HeaderError) {
+        match error {
+            HeaderError::InvalidCRC32Checksum { expected, actual } => {
+                self.error(Problem::InvalidCRC32 {
+                    structure,
+                    expected,
+                    actual,
+                })
+            }
+            e => self.error(Problem::Unreadable {
+                structure,
+                reason: e.to_string(),
+            }),
+        }
+    }
+}
+
+/// Checks the fields of a header which was read from `lba`, the other
+/// header is expected at `other_lba`.
+fn verify_header(
+    report: &mut VerifyReport,
+    header: &Header,
+    structure: GptStructure,
+    lba: u64,
+    other_lba: u64,
+) {
+    if header.current_lba != lba {
+        report.error(Problem::WrongCurrentLba {
+            structure,
+            expected: lba,
+            actual: header.current_lba,
+        });
+    }
+    if header.backup_lba != other_lba {
+        let problem = Problem::WrongBackupLba {
+            structure,
+            expected: other_lba,
+            actual: header.backup_lba,
+        };
+        // the device grew after the table was written
+        if structure == GptStructure::PrimaryHeader
+            && header.backup_lba > lba
+            && header.backup_lba < other_lba
+        {
+            report.warning(problem);
+        } else {
+            report.error(problem);
+        }
+    }
+    if header.reserved != 0 {
+        report.warning(Problem::NonZeroReserved {
+            structure,
+            value: header.reserved,
+        });
+    }
+}
+
+fn compare_headers(report: &mut VerifyReport, primary: &Header, backup: &Header) {
+    let fields = [
+        ("disk_guid", primary.disk_guid != backup.disk_guid),
+        ("first_usable", primary.first_usable != backup.first_usable),
+        ("last_usable", primary.last_usable != backup.last_usable),
+        ("num_parts", primary.num_parts != backup.num_parts),
+        ("part_size", primary.part_size != backup.part_size),
+    ];
+    for (field, _) in fields.iter().filter(|(_, differ)| *differ) {
+        report.error(Problem::HeadersDiffer { field });
+    }
+}
+
+fn compare_entries(
+    report: &mut VerifyReport,
+    primary: &BTreeMap<u32, Partition>,
+    backup: &BTreeMap<u32, Partition>,
+) {
+    let mut ids: Vec<u32> = primary.keys().chain(backup.keys()).copied().collect();
+    ids.sort_unstable();
+    ids.dedup();
+
+    for id in ids {
+        if primary.get(&id) != backup.get(&id) {
+            report.error(Problem::EntriesDiffer { id });
+        }
+    }
+}
+
+/// Returns the first problem of the partitions, without checking the
+/// alignment. This is what `GptDisk` validates every change with.
+///
+/// If an id is given only problems concerning that partition count.
+pub(crate) fn check_layout(
+    header: &Header,
+    partitions: &BTreeMap<u32, Partition>,
+    id: Option<u32>,
+) -> Result<(), Problem> {
+    let mut report = VerifyReport::default();
+    check_partitions(&mut report, header, partitions, None);
+    match report
+        .findings
+        .into_iter()
+        .find(|f| id.map_or(true, |id| f.problem.concerns(id)))
+    {
+        Some(finding) => Err(finding.problem),
+        None => Ok(()),
+    }
+}
+
+/// Checks the extents and GUIDs of the used partitions, and if given
+/// their alignment as `(alignment, physical block size)` in lba.
+fn check_partitions(
+    report: &mut VerifyReport,
+    header: &Header,
+    partitions: &BTreeMap<u32, Partition>,
+    alignment: Option<(u64, u64)>,
+) {
+    let mut guids = HashMap::new();
+    let mut extents = vec![];
+
+    for (id, p) in partitions.iter().filter(|(_, p)| p.is_used()) {
+        let (id, first_lba, last_lba) = (*id, p.first_lba, p.last_lba);
+        if last_lba < first_lba {
+            report.error(Problem::InvalidExtent {
+                id,
+                first_lba,
+                last_lba,
+            });
+        } else {
+            extents.push((first_lba, last_lba, id));
+            if first_lba < header.first_usable || last_lba > header.last_usable {
+                report.error(Problem::OutsideUsableRange {
+                    id,
+                    first_lba,
+                    last_lba,
+                });
+            }
+        }
+
+        if let Some(other) = guids.insert(p.part_guid, id) {
+            report.error(Problem::DuplicateGuid {
+                id: other,
+                other: id,
+                guid: p.part_guid,
+            });
+        }
+
+        match alignment {
+            Some((_, physical_block_lba)) if first_lba % physical_block_lba != 0 => {
+                report.warning(Problem::PhysicalBlockMisaligned {
+                    id,
+                    first_lba,
+                    physical_block_lba,
+                })
+            }
+            Some((alignment, _)) if first_lba % alignment != 0 => {
+                report.warning(Problem::Misaligned {
+                    id,
+                    first_lba,
+                    alignment,
+                })
+            }
+            _ => {}
+        }
+    }
+
+    // every partition is compared to the ones before it which end at
+    // or after its start
+    extents.sort_unstable();
+    let mut active: Vec<(u64, u32)> = vec![];
+    for (first_lba, last_lba, id) in extents {
+        active.retain(|(end, _)| *end >= first_lba);
+        for (_, other) in &active {
+            report.error(Problem::Overlap {
+                id: *other,
+                other: id,
+            });
+        }
+        active.push((last_lba, id));
+    }
+}
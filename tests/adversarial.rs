@@ -0,0 +1,412 @@
+//! Feeds corrupted and hostile tables to the public API.
+//!
+//! Every call has to return, errors are fine, panics are not.
+
+use gpt::layout::{Layout, PartitionSpec};
+use gpt::partition::Partition;
+use gpt::partition_move::PartitionMove;
+use gpt::partition_size::PartitionSize;
+use gpt::placement::Placement;
+use gpt::sfdisk::SfdiskScript;
+use gpt::{disk, header, journal, mbr, partition_types, GptConfig, GptDisk};
+
+use std::io::Cursor;
+
+const LB: usize = 512;
+const DISK_SIZE: usize = 1024 * 70;
+const BACKUP_LBA: usize = DISK_SIZE / LB - 1;
+
+// offsets of the header fields
+const CURRENT_LBA: usize = 24;
+const BACKUP: usize = 32;
+const FIRST_USABLE: usize = 40;
+const LAST_USABLE: usize = 48;
+const PART_START: usize = 72;
+const NUM_PARTS: usize = 80;
+const PART_SIZE: usize = 84;
+
+const CRC_32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+type Disk = GptDisk<Cursor<Vec<u8>>>;
+
+fn t_two_partition_disk() -> Vec<u8> {
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .alignment_fallback(true)
+        .create_from_device(Cursor::new(vec![0; DISK_SIZE]), None)
+        .unwrap();
+    gdisk
+        .add_partition("test1", 1024 * 12, partition_types::BASIC, 0, None)
+        .unwrap();
+    gdisk
+        .add_partition("test2", 1024 * 18, partition_types::LINUX_FS, 0, None)
+        .unwrap();
+    gdisk.write().unwrap().into_inner()
+}
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(data[pos..pos + 8].try_into().unwrap())
+}
+
+/// Sets a field of both headers and fixes up their CRCs.
+fn set_field(data: &mut [u8], field: usize, value: &[u8]) {
+    for lba in [1, BACKUP_LBA] {
+        let pos = lba * LB + field;
+        data[pos..pos + value.len()].copy_from_slice(value);
+        fix_crcs(data, lba);
+    }
+}
+
+/// Recomputes the partition array and header CRC of the header at `lba`,
+/// so corrupted fields get past the checksum checks.
+fn fix_crcs(data: &mut [u8], lba: usize) {
+    let h = lba * LB;
+    let part_start = read_u64(data, h + PART_START);
+    let len = u64::from(read_u32(data, h + NUM_PARTS)) * u64::from(read_u32(data, h + PART_SIZE));
+    let array = part_start
+        .checked_mul(LB as u64)
+        .and_then(|start| Some(start..start.checked_add(len)?))
+        .filter(|r| r.end <= data.len() as u64);
+    if let Some(r) = array {
+        let crc = CRC_32.checksum(&data[r.start as usize..r.end as usize]);
+        data[h + 88..h + 92].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    data[h + 16..h + 20].copy_from_slice(&[0; 4]);
+    let crc = CRC_32.checksum(&data[h..h + 92]);
+    data[h + 16..h + 20].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// A deterministic xorshift generator, so failures can be reproduced.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// Opens the image in every mode and calls every public function on it.
+fn exercise(data: &[u8]) {
+    let lb_size = disk::LogicalBlockSize::Lb512;
+    let _ = header::read_header_from_arbitrary_device(&mut Cursor::new(data), lb_size);
+    let _ = mbr::ProtectiveMBR::from_disk(&mut Cursor::new(data.to_vec()), lb_size);
+    let _ = GptConfig::new()
+        .only_valid_headers(true)
+        .open_from_device(Cursor::new(data.to_vec()));
+
+    for change_partition_count in [false, true] {
+        let gdisk = GptConfig::new()
+            .writable(true)
+            .change_partition_count(change_partition_count)
+            .open_from_device(Cursor::new(data.to_vec()));
+        if let Ok(gdisk) = gdisk {
+            exercise_disk(&gdisk);
+        }
+    }
+}
+
+fn exercise_disk(gdisk: &Disk) {
+    let _ = gdisk.header();
+    let _ = gdisk.find_free_sectors();
+    let _ = gdisk.find_next_partition_id();
+    let _ = gdisk.calculate_alignment();
+    let _ = gdisk.min_device_size();
+    let _ = gdisk.clone().verify();
+    let _ = gdisk.clone().plan_write();
+    let _ = gdisk.clone().write_inplace();
+
+    let last_usable = gdisk.header().map_or(u64::MAX, |h| h.last_usable);
+    let ids: Vec<u32> = gdisk.partitions().keys().copied().collect();
+
+    let mut d = gdisk.clone();
+    for size in [0, 1, 512 * 4, u64::MAX] {
+        for align in [None, Some(0), Some(1), Some(7), Some(u64::MAX)] {
+            let _ = d.add_partition("adv", size, partition_types::LINUX_FS, 0, align);
+        }
+    }
+    for id in [0, 1, 5, u32::MAX] {
+        for (first, len) in [(0, 0), (0, 1), (34, 1), (1, u64::MAX), (u64::MAX, 2)] {
+            let _ = d.add_partition_at("adv", id, first, len, partition_types::BASIC, 0);
+        }
+    }
+    for placement in [
+        Placement::BestFit,
+        Placement::LastFit,
+        Placement::Within {
+            first_lba: 0,
+            last_lba: u64::MAX,
+        },
+        Placement::Within {
+            first_lba: u64::MAX,
+            last_lba: 0,
+        },
+    ] {
+        for align in [None, Some(7), Some(u64::MAX)] {
+            let _ = d.add_partition_placed("adv", 512, partition_types::BASIC, 0, align, placement);
+        }
+    }
+    let _ = d.find_free_sectors_aligned(u64::MAX, u64::MAX);
+    let layout = Layout::new()
+        .partition(PartitionSpec::new("a", partition_types::BASIC, u64::MAX))
+        .partition(PartitionSpec::new("b", partition_types::BASIC, 1).max_size(u64::MAX))
+        .partition(
+            PartitionSpec::new("c", partition_types::BASIC, PartitionSize::Remaining)
+                .max_size(PartitionSize::Lba(u64::MAX)),
+        )
+        .allow_removal(true);
+    if let Ok(plan) = d.plan_layout(&layout) {
+        let _ = d.clone().apply_layout(&plan);
+    }
+    if let Ok(script) = d.sfdisk_script() {
+        let _ = script.to_string().parse::<SfdiskScript>();
+    }
+    for script in [
+        "table-length: 4294967295\nstart=18446744073709551615, size=18446744073709551615\n",
+        "table-length: 0\n/dev/sda4294967295 : size=0\nsize=+\nstart=0, size=1EiB\n",
+    ] {
+        let _ = d.clone().load_sfdisk_script(&script.parse().unwrap());
+    }
+    let mut backup = vec![];
+    if d.save_backup_to(&mut backup).is_ok() {
+        for size in [DISK_SIZE / 2, DISK_SIZE, DISK_SIZE * 2] {
+            let device = Cursor::new(vec![0; size]);
+            let _ = GptConfig::new()
+                .writable(true)
+                .restore_backup_from(device, &backup[..]);
+        }
+    }
+    let _ = d.write_inplace();
+
+    for id in ids.iter().copied().chain([0, u32::MAX]) {
+        let mut d = gdisk.clone();
+        for size in [0, 1, u64::MAX] {
+            for align in [None, Some(0), Some(u64::MAX)] {
+                let _ = d.resize_partition(id, size, align);
+            }
+        }
+        for align in [None, Some(0), Some(u64::MAX)] {
+            let _ = d.clone().grow_partition_to_fill(id, align);
+        }
+        for lba in [0, 34, last_usable, u64::MAX] {
+            let _ = d.clone().move_partition(id, lba);
+        }
+        let _ = d.remove_partition(id);
+        let _ = d.write_inplace();
+    }
+
+    let mut d = gdisk.clone();
+    for mv in [
+        PartitionMove {
+            id: 1,
+            from_lba: 34,
+            to_lba: 100,
+            length_lba: 24,
+            copied_lba: 0,
+            chunk_lba: 0,
+        },
+        PartitionMove {
+            id: 1,
+            from_lba: u64::MAX,
+            to_lba: u64::MAX - 1,
+            length_lba: u64::MAX,
+            copied_lba: 1,
+            chunk_lba: 1,
+        },
+        PartitionMove {
+            id: 1,
+            from_lba: 34,
+            to_lba: 1 << 40,
+            length_lba: 24,
+            copied_lba: 0,
+            chunk_lba: 24,
+        },
+    ] {
+        let _ = d.copy_partition_chunk(&mut mv.clone());
+        let _ = d.finish_partition_move(&mut mv.clone());
+    }
+
+    let mut d = gdisk.clone();
+    let _ = d.sort_partitions();
+    let _ = d.write_inplace();
+
+    for n in [0, 1, 128, u32::MAX] {
+        let mut d = gdisk.clone();
+        let _ = d.set_entry_capacity(n);
+        let _ = d.write_inplace();
+    }
+
+    let _ = gdisk.clone().disk_grown();
+    let _ = gdisk.clone().relocate_backup_to_end();
+    let _ = gdisk.clone().shrink_to_fit();
+    let _ = gdisk.clone().rebuild_primary_header();
+    let _ = gdisk.clone().rebuild_backup_header();
+    let _ = gdisk.clone().restore_primary_entries();
+    let _ = gdisk.clone().restore_backup_entries();
+    let _ = gdisk.clone().recompute_crcs();
+}
+
+/// Partitions which no sane table would contain.
+fn hostile_partitions() -> Vec<Partition> {
+    let part = |first_lba, last_lba| Partition {
+        part_type_guid: partition_types::LINUX_FS,
+        part_guid: uuid::Uuid::new_v4(),
+        first_lba,
+        last_lba,
+        flags: 0,
+        name: "hostile".to_string(),
+        extra: vec![],
+    };
+    vec![
+        part(50, 40),
+        part(0, 0),
+        part(0, u64::MAX),
+        part(u64::MAX, u64::MAX),
+        part(u64::MAX, 0),
+        part(40, 60),
+        part(1, 1),
+        part(BACKUP_LBA as u64, BACKUP_LBA as u64 + 10),
+    ]
+}
+
+#[test]
+fn test_invalid_arguments() {
+    let data = t_two_partition_disk();
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .open_from_device(Cursor::new(data))
+        .unwrap();
+
+    assert!(matches!(
+        gdisk.add_partition("a", 0, partition_types::BASIC, 0, None),
+        Err(gpt::GptError::InvalidPartitionSize)
+    ));
+    assert!(matches!(
+        gdisk.add_partition_at("a", 3, 100, 0, partition_types::BASIC, 0),
+        Err(gpt::GptError::InvalidPartitionSize)
+    ));
+    assert!(matches!(
+        gdisk.add_partition_at("a", 0, 100, 1, partition_types::BASIC, 0),
+        Err(gpt::GptError::InvalidPartitionId)
+    ));
+    let mut parts = gdisk.partitions().clone();
+    parts.insert(0, Partition::zero());
+    assert!(matches!(
+        gdisk.update_partitions(parts),
+        Err(gpt::GptError::InvalidPartitionId)
+    ));
+
+    // an alignment of 0 is treated like no alignment
+    let id = gdisk
+        .add_partition("a", 512, partition_types::BASIC, 0, Some(0))
+        .unwrap();
+    assert_eq!(gdisk.partitions()[&id].first_lba, 94);
+}
+
+#[test]
+fn test_hostile_headers() {
+    let data = t_two_partition_disk();
+    exercise(&data);
+
+    let values = [0, 1, 2, 33, 34, BACKUP_LBA as u64, 1 << 40, u64::MAX];
+    for field in [CURRENT_LBA, BACKUP, FIRST_USABLE, LAST_USABLE, PART_START] {
+        for value in values {
+            let mut data = data.clone();
+            set_field(&mut data, field, &value.to_le_bytes());
+            exercise(&data);
+        }
+    }
+    for field in [NUM_PARTS, PART_SIZE] {
+        for value in [0, 1, 4, 127, 128, 256, 1 << 20, u32::MAX - 127, u32::MAX] {
+            let mut data = data.clone();
+            set_field(&mut data, field, &value.to_le_bytes());
+            exercise(&data);
+        }
+    }
+}
+
+#[test]
+fn test_hostile_partitions() {
+    let data = t_two_partition_disk();
+    let gdisk = GptConfig::new()
+        .writable(true)
+        .change_partition_count(true)
+        .open_from_device(Cursor::new(data))
+        .unwrap();
+
+    for part in hostile_partitions() {
+        let mut d = gdisk.clone();
+        let mut parts = d.partitions().clone();
+        parts.insert(3, part.clone());
+        parts.insert(u32::MAX, part);
+        assert!(d.update_partitions(parts.clone()).is_err());
+        let _ = d.update_partitions_unchecked(parts);
+        exercise_disk(&d);
+
+        // and what ends up on disk
+        if d.write_inplace().is_ok() {
+            exercise(d.device_ref().get_ref());
+        }
+    }
+}
+
+#[test]
+fn test_fuzzed_tables() {
+    let data = t_two_partition_disk();
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+
+    for _ in 0..200 {
+        let mut data = data.clone();
+        // corrupt a few bytes of the mbr, the headers or the partition arrays
+        for _ in 0..1 + rng.below(8) {
+            let pos = match rng.below(4) {
+                0 => rng.below(LB),
+                1 => LB + rng.below(92),
+                2 => 2 * LB + rng.below(2 * 128),
+                _ => BACKUP_LBA * LB + rng.below(92),
+            };
+            data[pos] = rng.next() as u8;
+        }
+        fix_crcs(&mut data, 1);
+        fix_crcs(&mut data, BACKUP_LBA);
+        exercise(&data);
+    }
+}
+
+#[test]
+fn test_garbage_bytes() {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let lb_size = disk::LogicalBlockSize::Lb512;
+
+    for len in [0, 1, 16, 511, 512, 513, 4096] {
+        let buf: Vec<u8> = (0..len).map(|_| rng.next() as u8).collect();
+        let _ = mbr::ProtectiveMBR::from_bytes(&buf, lb_size);
+        let _ = mbr::PartRecord::from_bytes(&buf);
+        let _ = journal::Journal::from_bytes(&buf);
+        let _ = header::read_header_from_arbitrary_device(&mut Cursor::new(&buf), lb_size);
+        let _ = GptConfig::new().open_from_device(Cursor::new(buf.clone()));
+        let _ = GptConfig::new()
+            .writable(true)
+            .create_from_device(Cursor::new(buf), None)
+            .and_then(|d| d.write());
+    }
+
+    // a valid mbr signature in front of garbage
+    let mut buf: Vec<u8> = (0..DISK_SIZE).map(|_| rng.next() as u8).collect();
+    buf[510] = 0x55;
+    buf[511] = 0xaa;
+    buf[LB..LB + 8].copy_from_slice(b"EFI PART");
+    fix_crcs(&mut buf, 1);
+    exercise(&buf);
+}
@@ -1,4 +1,5 @@
-use gpt::{disk, GptConfig, GptError};
+use gpt::journal::{recover_from_journal, Journal, JournalMode, JournalState, RecoveryAction};
+use gpt::{disk, GptConfig, GptError, GptStructure};
 
 use std::io::{Cursor, Read, Seek, Write};
 use tempfile::NamedTempFile;
@@ -6,7 +7,7 @@ use tempfile::NamedTempFile;
 #[test]
 fn test_gptconfig_empty() {
     let mut tempdisk = NamedTempFile::new().expect("failed to create tempfile disk");
-    tempdisk.write(&[0; 1024 * 64]).unwrap();
+    tempdisk.write_all(&[0; 1024 * 64]).unwrap();
     let cfg = {
         let c1 = GptConfig::new();
         let c2 = GptConfig::default();
@@ -38,6 +39,7 @@ fn test_create_simple_on_device() {
 
     let mut gdisk = GptConfig::default()
         .writable(true)
+        .alignment_fallback(true)
         .logical_block_size(disk::LogicalBlockSize::Lb512)
         .create_from_device(mem_device, None)
         .unwrap();
@@ -71,6 +73,7 @@ fn test_only_valid_headers() {
     // write a valid disk
     let mut valid_disk = GptConfig::new()
         .writable(true)
+        .alignment_fallback(true)
         .create_from_device(Cursor::new(vec![0; 1024 * 70]), None)
         .unwrap();
 
@@ -114,6 +117,7 @@ fn test_readonly_backup() {
     // write a valid disk
     let mut valid_disk = GptConfig::new()
         .writable(true)
+        .alignment_fallback(true)
         .create_from_device(Cursor::new(vec![0; 1024 * 70]), None)
         .unwrap();
 
@@ -134,6 +138,7 @@ fn test_readonly_backup() {
 
     let mut test_disk = GptConfig::new()
         .writable(true)
+        .alignment_fallback(true)
         .readonly_backup(true)
         .open_from_device(test_disk_bytes)
         .unwrap();
@@ -166,6 +171,7 @@ fn test_change_partition_count() {
     // write a valid disk
     let mut valid_disk = GptConfig::new()
         .writable(true)
+        .alignment_fallback(true)
         .create_from_device(Cursor::new(vec![0; 512 * size]), None)
         .unwrap();
 
@@ -182,13 +188,7 @@ fn test_change_partition_count() {
             .unwrap();
     }
 
-    let failed = valid_disk.add_partition(
-        &format!("test129"),
-        512,
-        gpt::partition_types::BASIC,
-        0,
-        None,
-    );
+    let failed = valid_disk.add_partition("test129", 512, gpt::partition_types::BASIC, 0, None);
     assert!(matches!(failed, Err(GptError::PartitionCountWouldChange)));
 
     // now write to memory
@@ -198,18 +198,20 @@ fn test_change_partition_count() {
     let mut big_disk = GptConfig::new()
         .writable(true)
         .change_partition_count(true)
-        .create_from_device(Cursor::new(vec![0; 512 * size]), None)
+        .create_from_device(Cursor::new(vec![0; 512 * (size + 2)]), None)
         .unwrap();
 
-    // let's create 128 partitions
+    // let's create 129 partitions, leaving room for the partition arrays
+    // which grow by one lba each
     for i in 0..129 {
         big_disk
-            .add_partition(
+            .add_partition_at(
                 &format!("test{i}"),
-                512,
+                i + 1,
+                35 + u64::from(i),
+                1,
                 gpt::partition_types::BASIC,
                 0,
-                None,
             )
             .unwrap();
     }
@@ -218,6 +220,7 @@ fn test_change_partition_count() {
 
     let mut n_disk = GptConfig::new()
         .writable(true)
+        .alignment_fallback(true)
         .open_from_device(data.clone())
         .unwrap();
     n_disk.write_inplace().unwrap();
@@ -233,9 +236,29 @@ fn test_change_partition_count() {
 
     n_disk.write_inplace().unwrap();
 
-    assert_eq!(n_disk.header().num_parts, 129);
+    assert_eq!(n_disk.header().unwrap().num_parts, 129);
     assert_eq!(n_disk.partitions().len(), 128);
-    assert_ne!(valid_disk.header().num_parts, n_disk.header().num_parts);
+    assert_ne!(
+        valid_disk.header().unwrap().num_parts,
+        n_disk.header().unwrap().num_parts
+    );
+
+    // 129 entries need another lba, the partition would overlap the grown array
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .change_partition_count(true)
+        .create_from_device(Cursor::new(vec![0; 512 * size]), None)
+        .unwrap();
+    assert!(matches!(
+        gdisk.add_partition_at("test", 129, 34, 1, gpt::partition_types::BASIC, 0),
+        Err(GptError::InvalidLayout(_))
+    ));
+    assert_eq!(gdisk.header().unwrap().num_parts, 128);
+    assert!(gdisk.partitions().is_empty());
+    gdisk
+        .add_partition_at("test", 129, 35, 1, gpt::partition_types::BASIC, 0)
+        .unwrap();
+    assert_eq!(gdisk.header().unwrap().first_usable, 35);
 }
 
 fn test_helper_gptdisk_write_efi_unused_partition_entries(lb_size: disk::LogicalBlockSize) {
@@ -262,6 +285,7 @@ fn test_helper_gptdisk_write_efi_unused_partition_entries(lb_size: disk::Logical
     // Setup a new partition table and add a couple entries to it.
     let mut gdisk = GptConfig::default()
         .writable(true)
+        .alignment_fallback(true)
         .logical_block_size(lb_size)
         .create_from_device(mem_device, None)
         .unwrap();
@@ -361,6 +385,7 @@ fn test_non_overlapping_partition_edge_case() {
     // Create a new GPT disk using 512-byte sectors.
     let mut gpt_disk = GptConfig::default()
         .writable(true)
+        .alignment_fallback(true)
         .logical_block_size(disk::LogicalBlockSize::Lb512)
         .create_from_device(device, None)
         .expect("failed to create GPT disk in memory");
@@ -393,3 +418,2024 @@ fn test_non_overlapping_partition_edge_case() {
         part2.first_lba..part2.last_lba
     );
 }
+
+#[test]
+fn test_partition_ids_survive_write() {
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .create_from_device(Cursor::new(vec![0; 1024 * 70]), None)
+        .unwrap();
+
+    gdisk
+        .add_partition_at("test1", 1, 34, 2, gpt::partition_types::BASIC, 0)
+        .unwrap();
+    gdisk
+        .add_partition_at("test2", 2, 36, 2, gpt::partition_types::LINUX_FS, 0)
+        .unwrap();
+    gdisk
+        .add_partition_at("test5", 5, 38, 2, gpt::partition_types::BASIC, 0)
+        .unwrap();
+
+    let mut data = gdisk.write().unwrap();
+
+    // slots 3 and 4 need to be empty in both partition arrays
+    let backup_array = 1024 * 70 - 512 * (1 + 32);
+    for array_start in [512 * 2, backup_array as u64] {
+        let slot = |idx: u64| array_start + idx * 128;
+        assert_ne!(t_read_bytes(&mut data, slot(1), 128), vec![0; 128]);
+        assert_eq!(t_read_bytes(&mut data, slot(2), 128 * 2), vec![0; 128 * 2]);
+        assert_ne!(t_read_bytes(&mut data, slot(4), 128), vec![0; 128]);
+        assert_eq!(
+            t_read_bytes(&mut data, slot(5), 128 * 123),
+            vec![0; 128 * 123]
+        );
+    }
+
+    let reopened = GptConfig::new().open_from_device(data).unwrap();
+    let ids: Vec<_> = reopened.partitions().keys().copied().collect();
+    assert_eq!(ids, [1, 2, 5]);
+    assert_eq!(reopened.partitions()[&5].name, "test5");
+    assert_eq!(reopened.partitions()[&5].first_lba, 38);
+}
+
+/// A device which fails (or silently drops) writes to the given range,
+/// until it ran out of faults.
+#[derive(Debug, Clone)]
+struct FaultyDevice {
+    inner: Cursor<Vec<u8>>,
+    faulty: std::ops::Range<u64>,
+    drop_writes: bool,
+    faults: usize,
+}
+
+impl Read for FaultyDevice {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Seek for FaultyDevice {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl Write for FaultyDevice {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let pos = self.inner.position();
+        let end = pos + buf.len() as u64;
+        if self.faults > 0 && pos < self.faulty.end && self.faulty.start < end {
+            self.faults -= 1;
+            if !self.drop_writes {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "simulated power loss",
+                ));
+            }
+            self.inner.set_position(end);
+            return Ok(buf.len());
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn t_two_partition_disk() -> Cursor<Vec<u8>> {
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .alignment_fallback(true)
+        .create_from_device(Cursor::new(vec![0; 1024 * 70]), None)
+        .unwrap();
+    gdisk
+        .add_partition("test1", 1024 * 12, gpt::partition_types::BASIC, 0, None)
+        .unwrap();
+    gdisk
+        .add_partition("test2", 1024 * 18, gpt::partition_types::LINUX_FS, 0, None)
+        .unwrap();
+    gdisk.write().unwrap()
+}
+
+#[test]
+fn test_verify_writes() {
+    let data = t_two_partition_disk();
+
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .alignment_fallback(true)
+        .verify_writes(true)
+        .open_from_device(data.clone())
+        .unwrap();
+    gdisk
+        .add_partition("test3", 1024 * 4, gpt::partition_types::LINUX_FS, 0, None)
+        .unwrap();
+    gdisk.write_inplace().unwrap();
+
+    // a disk which drops every write to the backup header
+    let device = FaultyDevice {
+        inner: data,
+        faulty: (1024 * 70 - 512)..(1024 * 70),
+        drop_writes: true,
+        faults: usize::MAX,
+    };
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .alignment_fallback(true)
+        .verify_writes(true)
+        .open_from_device(device)
+        .unwrap();
+    gdisk
+        .add_partition("test3", 1024 * 4, gpt::partition_types::LINUX_FS, 0, None)
+        .unwrap();
+    let res = gdisk.write_inplace();
+    assert!(
+        matches!(
+            res,
+            Err(GptError::WriteVerificationFailed(
+                gpt::GptStructure::BackupHeader
+            ))
+        ),
+        "{res:?}"
+    );
+    // the primary table must not have been touched
+    let device = gdisk.take_device();
+    let gdisk = GptConfig::new()
+        .only_valid_headers(true)
+        .open_from_device(device.inner)
+        .unwrap();
+    assert_eq!(gdisk.partitions().len(), 2);
+}
+
+#[test]
+fn test_interrupted_primary_write() {
+    // the primary partition array gets written but the power is lost
+    // before the primary header is updated
+    let device = FaultyDevice {
+        inner: t_two_partition_disk(),
+        faulty: 512..1024,
+        drop_writes: false,
+        faults: usize::MAX,
+    };
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .alignment_fallback(true)
+        .open_from_device(device)
+        .unwrap();
+    gdisk
+        .add_partition("test3", 1024 * 4, gpt::partition_types::LINUX_FS, 0, None)
+        .unwrap();
+    assert!(matches!(gdisk.write_inplace(), Err(GptError::Header(_))));
+
+    // the old primary header no longer matches its array,
+    // so the new backup needs to be used
+    let device = gdisk.take_device();
+    let gdisk = GptConfig::new().open_from_device(device.inner).unwrap();
+    assert_eq!(gdisk.partitions().len(), 3);
+    assert_eq!(gdisk.partitions()[&3].name, "test3");
+}
+
+#[test]
+fn test_journal_rollback() {
+    let data = t_two_partition_disk();
+
+    // the primary header write fails once
+    let device = FaultyDevice {
+        inner: data.clone(),
+        faulty: 512..1024,
+        drop_writes: false,
+        faults: 1,
+    };
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .alignment_fallback(true)
+        .journal(JournalMode::Memory)
+        .open_from_device(device)
+        .unwrap();
+    let old_backup = gdisk.backup_header().unwrap().clone();
+    gdisk
+        .add_partition("test3", 1024 * 4, gpt::partition_types::LINUX_FS, 0, None)
+        .unwrap();
+    assert!(matches!(gdisk.write_inplace(), Err(GptError::Header(_))));
+
+    // everything was restored, on disk and in memory
+    assert_eq!(gdisk.device_ref().inner.get_ref(), data.get_ref());
+    assert_eq!(gdisk.backup_header().unwrap(), &old_backup);
+
+    // the fault is gone, so now the write succeeds
+    gdisk.write_inplace().unwrap();
+    let gdisk = GptConfig::new()
+        .only_valid_headers(true)
+        .open_from_device(gdisk.take_device().inner)
+        .unwrap();
+    assert_eq!(gdisk.partitions().len(), 3);
+}
+
+/// Deletes a file on the first write, so updating a journal fails.
+#[derive(Debug)]
+struct RemovingDevice {
+    inner: FaultyDevice,
+    path: std::path::PathBuf,
+}
+
+impl Read for RemovingDevice {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Seek for RemovingDevice {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl Write for RemovingDevice {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let _ = std::fs::remove_file(&self.path);
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn test_journal_state_failure() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("gpt.journal");
+    let open = |faults| {
+        let device = RemovingDevice {
+            inner: FaultyDevice {
+                inner: t_two_partition_disk(),
+                faulty: 512..1024,
+                drop_writes: false,
+                faults,
+            },
+            path: path.clone(),
+        };
+        let mut gdisk = GptConfig::new()
+            .writable(true)
+            .alignment_fallback(true)
+            .journal(JournalMode::File(path.clone()))
+            .open_from_device(device)
+            .unwrap();
+        gdisk
+            .add_partition("test3", 1024 * 4, gpt::partition_types::LINUX_FS, 0, None)
+            .unwrap();
+        gdisk
+    };
+
+    // the table is written even though the journal can't be committed
+    let mut gdisk = open(0);
+    gdisk.write_inplace().unwrap();
+    let gdisk = GptConfig::new()
+        .only_valid_headers(true)
+        .open_from_device(gdisk.take_device().inner.inner)
+        .unwrap();
+    assert_eq!(gdisk.partitions().len(), 3);
+
+    // the error of the write is kept
+    let mut gdisk = open(1);
+    let res = gdisk.write_inplace();
+    assert!(matches!(res, Err(GptError::Header(_))), "{res:?}");
+}
+
+#[test]
+fn test_journal_recover() {
+    let data = t_two_partition_disk();
+    let journal_file = NamedTempFile::new().unwrap();
+
+    let interrupted_write = || {
+        // the primary header can't be written and neither can it be restored
+        let device = FaultyDevice {
+            inner: data.clone(),
+            faulty: 512..1024,
+            drop_writes: false,
+            faults: usize::MAX,
+        };
+        let mut gdisk = GptConfig::new()
+            .writable(true)
+            .alignment_fallback(true)
+            .journal(JournalMode::File(journal_file.path().into()))
+            .open_from_device(device)
+            .unwrap();
+        gdisk
+            .add_partition("test3", 1024 * 4, gpt::partition_types::LINUX_FS, 0, None)
+            .unwrap();
+        let res = gdisk.write_inplace();
+        assert!(matches!(res, Err(GptError::RollbackFailed(_))), "{res:?}");
+        gdisk.take_device().inner
+    };
+
+    // undo
+    let mut device = interrupted_write();
+    assert_ne!(device.get_ref(), data.get_ref());
+    let journal = Journal::read_from(journal_file.path()).unwrap();
+    assert_eq!(journal.state(), JournalState::Pending);
+    assert_eq!(journal.entries()[0].offset, 0);
+
+    let state =
+        recover_from_journal(&mut device, journal_file.path(), RecoveryAction::Rollback).unwrap();
+    assert_eq!(state, JournalState::RolledBack);
+    assert_eq!(device.get_ref(), data.get_ref());
+    // recovering again does nothing
+    let state = recover_from_journal(
+        &mut device,
+        journal_file.path(),
+        RecoveryAction::RollForward,
+    )
+    .unwrap();
+    assert_eq!(state, JournalState::RolledBack);
+    assert_eq!(device.get_ref(), data.get_ref());
+
+    // finish
+    let mut device = interrupted_write();
+    let state = recover_from_journal(
+        &mut device,
+        journal_file.path(),
+        RecoveryAction::RollForward,
+    )
+    .unwrap();
+    assert_eq!(state, JournalState::Committed);
+    let gdisk = GptConfig::new()
+        .only_valid_headers(true)
+        .open_from_device(device)
+        .unwrap();
+    assert_eq!(gdisk.partitions().len(), 3);
+    assert_eq!(gdisk.partitions()[&3].name, "test3");
+}
+
+#[test]
+fn test_plan_write() {
+    let data = t_two_partition_disk();
+
+    let mut gdisk = GptConfig::new().open_from_device(data.clone()).unwrap();
+    // rewriting the same table changes nothing
+    assert!(gdisk.plan_write().unwrap().is_empty());
+
+    gdisk.remove_partition(2);
+    let plan = gdisk.plan_write().unwrap();
+    let structures: Vec<_> = plan.writes().iter().map(|w| w.structure).collect();
+    assert_eq!(
+        structures,
+        [
+            GptStructure::PrimaryHeader,
+            GptStructure::PrimaryEntries,
+            GptStructure::BackupEntries,
+            GptStructure::BackupHeader
+        ]
+    );
+
+    // only the second entry is cleared
+    let entries = plan.structure(GptStructure::PrimaryEntries).next().unwrap();
+    assert!(entries.offset >= 512 * 2 + 128);
+    assert!(entries.offset + (entries.new.len() as u64) <= 512 * 2 + 128 * 2);
+    assert!(entries.new.iter().all(|b| *b == 0));
+    assert!(entries.old.iter().any(|b| *b != 0));
+
+    // nothing was written
+    assert_eq!(gdisk.device_ref().get_ref(), data.get_ref());
+}
+
+#[test]
+fn test_repair() {
+    let data = t_two_partition_disk();
+    let len = data.get_ref().len();
+    let backup_lba = (len / 512 - 1) as u64;
+
+    // wipe the primary header
+    let mut damaged = data.clone();
+    damaged.get_mut()[512..1024].fill(0);
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .open_from_device(damaged)
+        .unwrap();
+    assert!(gdisk.primary_header().is_err());
+    let report = gdisk.rebuild_primary_header().unwrap();
+    assert_eq!(report.source, Some(GptStructure::BackupHeader));
+    assert_eq!(report.repaired.len(), 1);
+    assert_eq!(report.repaired[0].structure, GptStructure::PrimaryHeader);
+    assert_eq!(report.repaired[0].lba, 1);
+    assert_eq!(report.repaired[0].old_crc32, None);
+    assert_eq!(gdisk.device_ref().get_ref(), data.get_ref());
+
+    // corrupt the backup partition array
+    let mut damaged = data.clone();
+    let backup_entries = (backup_lba as usize - 32) * 512;
+    damaged.get_mut()[backup_entries..backup_entries + 128].fill(0xff);
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .open_from_device(damaged)
+        .unwrap();
+    let report = gdisk.restore_backup_entries().unwrap();
+    let structures: Vec<_> = report.repaired.iter().map(|r| r.structure).collect();
+    assert_eq!(
+        structures,
+        [GptStructure::BackupEntries, GptStructure::BackupHeader]
+    );
+    assert_eq!(report.repaired[0].lba, backup_lba - 32);
+    assert_ne!(
+        report.repaired[0].old_crc32,
+        Some(report.repaired[0].new_crc32)
+    );
+    // the array is copied unchanged so the header does not change
+    assert_eq!(
+        report.repaired[1].old_crc32,
+        Some(report.repaired[1].new_crc32)
+    );
+    assert_eq!(gdisk.device_ref().get_ref(), data.get_ref());
+
+    // make the crcs match a damaged primary array
+    let mut damaged = data.clone();
+    damaged.get_mut()[1024..1024 + 128].fill(0);
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .open_from_device(damaged)
+        .unwrap();
+    assert_eq!(gdisk.partitions().len(), 2);
+    let report = gdisk.recompute_crcs().unwrap();
+    let structures: Vec<_> = report.repaired.iter().map(|r| r.structure).collect();
+    assert_eq!(
+        structures,
+        [GptStructure::BackupHeader, GptStructure::PrimaryHeader]
+    );
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .only_valid_headers(true)
+        .open_from_device(gdisk.device_ref().clone())
+        .unwrap();
+    assert_eq!(gdisk.partitions().len(), 1);
+
+    // unwritten changes are not thrown away
+    let mut changed = gdisk.clone();
+    changed.remove_partition(2);
+    assert!(matches!(
+        changed.restore_primary_entries(),
+        Err(GptError::UnwrittenChanges)
+    ));
+
+    // restoring the primary array brings the first partition back
+    let report = gdisk.restore_primary_entries().unwrap();
+    assert_eq!(report.source, Some(GptStructure::BackupEntries));
+    assert_eq!(gdisk.partitions().len(), 2);
+    assert_eq!(gdisk.device_ref().get_ref(), data.get_ref());
+
+    // read only disks can not be repaired
+    let mut gdisk = GptConfig::new().open_from_device(data).unwrap();
+    assert!(matches!(
+        gdisk.rebuild_backup_header(),
+        Err(GptError::ReadOnly)
+    ));
+}
+
+#[test]
+fn test_repair_custom_partition_array_location() {
+    // lba 2..10 hold a boot loader payload, the arrays are at 10 and 100
+    let mut data = vec![0; 1024 * 70];
+    data[2 * 512..10 * 512].fill(0xaa);
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .primary_part_start(10)
+        .backup_part_start(100)
+        .create_from_device(Cursor::new(data), None)
+        .unwrap();
+    gdisk
+        .add_partition_at("test1", 1, 42, 8, gpt::partition_types::BASIC, 0)
+        .unwrap();
+    let data = gdisk.write().unwrap();
+    let payload_intact =
+        |data: &Cursor<Vec<u8>>| data.get_ref()[2 * 512..10 * 512].iter().all(|b| *b == 0xaa);
+
+    // wipe the primary header, the array location is only known if configured
+    let mut damaged = data.clone();
+    damaged.get_mut()[512..1024].fill(0);
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .open_from_device(damaged.clone())
+        .unwrap();
+    assert!(matches!(
+        gdisk.rebuild_primary_header(),
+        Err(GptError::UnknownPartitionArrayLocation(
+            GptStructure::PrimaryEntries
+        ))
+    ));
+    assert!(matches!(
+        gdisk.write_inplace(),
+        Err(GptError::UnknownPartitionArrayLocation(
+            GptStructure::PrimaryEntries
+        ))
+    ));
+    assert_eq!(gdisk.device_ref().get_ref(), damaged.get_ref());
+
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .primary_part_start(10)
+        .open_from_device(damaged)
+        .unwrap();
+    gdisk.rebuild_primary_header().unwrap();
+    assert_eq!(gdisk.primary_header().unwrap().part_start, 10);
+    let repaired = gdisk.write().unwrap();
+    assert_eq!(repaired.get_ref(), data.get_ref());
+    assert!(payload_intact(&repaired));
+
+    // the same for the backup header
+    let mut damaged = data.clone();
+    damaged.get_mut()[139 * 512..].fill(0);
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .open_from_device(damaged.clone())
+        .unwrap();
+    assert!(matches!(
+        gdisk.rebuild_backup_header(),
+        Err(GptError::UnknownPartitionArrayLocation(
+            GptStructure::BackupEntries
+        ))
+    ));
+
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .backup_part_start(100)
+        .open_from_device(damaged)
+        .unwrap();
+    gdisk.rebuild_backup_header().unwrap();
+    assert_eq!(gdisk.backup_header().unwrap().part_start, 100);
+    let repaired = gdisk.write().unwrap();
+    assert_eq!(repaired.get_ref(), data.get_ref());
+
+    // a valid header keeps its array, relocating only moves the backup
+    let mut grown = data;
+    grown.get_mut().resize(1024 * 80, 0);
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .open_from_device(grown)
+        .unwrap();
+    assert_eq!(gdisk.relocate_backup_to_end().unwrap(), 159);
+    let grown = gdisk.write().unwrap();
+    assert!(payload_intact(&grown));
+    let gdisk = GptConfig::new()
+        .only_valid_headers(true)
+        .open_from_device(grown)
+        .unwrap();
+    assert_eq!(gdisk.primary_header().unwrap().part_start, 10);
+    assert_eq!(gdisk.backup_header().unwrap().part_start, 159 - 32);
+    assert_eq!(gdisk.partitions().len(), 1);
+}
+
+#[test]
+fn test_relocate_backup_to_end() {
+    let mut data = t_two_partition_disk();
+    let old_len = data.get_ref().len() as u64;
+    let old_backup_lba = old_len / 512 - 1;
+    gpt::mbr::ProtectiveMBR::with_lb_size(old_backup_lba as u32)
+        .overwrite_lba0(&mut data)
+        .unwrap();
+    data.get_mut().resize(old_len as usize + 512 * 64, 0);
+    let new_backup_lba = old_backup_lba + 64;
+
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .only_valid_headers(true)
+        .open_from_device(data)
+        .unwrap();
+    assert_eq!(gdisk.backup_header().unwrap().current_lba, old_backup_lba);
+    // reported on open
+    assert_eq!(gdisk.grown_to(), Some(new_backup_lba));
+    assert_eq!(gdisk.disk_grown().unwrap(), Some(new_backup_lba));
+
+    let partitions = gdisk.partitions().clone();
+    assert_eq!(gdisk.relocate_backup_to_end().unwrap(), new_backup_lba);
+    assert_eq!(gdisk.disk_grown().unwrap(), None);
+    gdisk.write_inplace().unwrap();
+    gdisk.update_protective_mbr().unwrap();
+    let data = gdisk.take_device();
+
+    let mut gdisk = GptConfig::new()
+        .only_valid_headers(true)
+        .open_from_device(data)
+        .unwrap();
+    assert_eq!(gdisk.grown_to(), None);
+    let report = gdisk.verify().unwrap();
+    assert!(report
+        .findings()
+        .iter()
+        .all(|f| matches!(f.problem, gpt::verify::Problem::Misaligned { .. })));
+    let primary = gdisk.primary_header().unwrap();
+    assert_eq!(primary.backup_lba, new_backup_lba);
+    assert_eq!(primary.last_usable, new_backup_lba - 33);
+    let backup = gdisk.backup_header().unwrap();
+    assert_eq!(backup.current_lba, new_backup_lba);
+    assert_eq!(backup.part_start, new_backup_lba - 32);
+    assert_eq!(gdisk.partitions(), &partitions);
+    assert_eq!(gdisk.disk_grown().unwrap(), None);
+}
+
+#[test]
+fn test_shrink_to_fit() {
+    let data = t_two_partition_disk();
+    // test2 ends at lba 93, followed by the backup array and header
+    let min_size = (93 + 32 + 2) * 512;
+
+    let gdisk = GptConfig::new().open_from_device(data.clone()).unwrap();
+    assert_eq!(gdisk.min_device_size().unwrap(), min_size);
+
+    let mut tempdisk = NamedTempFile::new().expect("failed to create tempfile disk");
+    tempdisk.write_all(data.get_ref()).unwrap();
+    gpt::mbr::ProtectiveMBR::with_lb_size(139)
+        .overwrite_lba0(tempdisk.as_file_mut())
+        .unwrap();
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .open(tempdisk.path())
+        .unwrap();
+    let partitions = gdisk.partitions().clone();
+    assert_eq!(gdisk.shrink_file_to_fit().unwrap(), min_size);
+    drop(gdisk);
+    assert_eq!(tempdisk.as_file().metadata().unwrap().len(), min_size);
+
+    let mut gdisk = GptConfig::new()
+        .only_valid_headers(true)
+        .open(tempdisk.path())
+        .unwrap();
+    assert_eq!(gdisk.partitions(), &partitions);
+    assert_eq!(gdisk.primary_header().unwrap().last_usable, 93);
+    // the protective MBR ends at the new last lba
+    let report = gdisk.verify().unwrap();
+    assert!(report
+        .findings()
+        .iter()
+        .all(|f| matches!(f.problem, gpt::verify::Problem::Misaligned { .. })));
+}
+
+#[test]
+fn test_shrunken_device() {
+    let data = t_two_partition_disk();
+
+    // test2 does not fit anymore
+    let mut shrunken = data.clone();
+    shrunken.get_mut().truncate(100 * 512);
+    let err = GptConfig::new().open_from_device(shrunken).unwrap_err();
+    assert!(matches!(err, GptError::PartitionsDoNotFit(ids) if ids == [2]));
+
+    // only free space got lost, the backup can be moved to the new end
+    let mut shrunken = data;
+    shrunken.get_mut().truncate(130 * 512);
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .open_from_device(shrunken)
+        .unwrap();
+    assert!(gdisk.backup_header().is_err());
+    assert_eq!(gdisk.relocate_backup_to_end().unwrap(), 129);
+    let data = gdisk.write().unwrap();
+
+    let gdisk = GptConfig::new()
+        .only_valid_headers(true)
+        .open_from_device(data)
+        .unwrap();
+    assert_eq!(gdisk.partitions().len(), 2);
+
+    // the primary header is outdated and its partition array damaged, the
+    // backup at the new end is used
+    let old = t_two_partition_disk();
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .open_from_device(old.clone())
+        .unwrap();
+    let size = gdisk.shrink_to_fit().unwrap();
+    let mut shrunken = gdisk.write().unwrap();
+    shrunken.get_mut().truncate(size as usize);
+    shrunken.get_mut()[512..1024].copy_from_slice(&old.get_ref()[512..1024]);
+    shrunken.get_mut()[2 * 512] ^= 0xff;
+    let gdisk = GptConfig::new().open_from_device(shrunken).unwrap();
+    assert_eq!(gdisk.partitions().len(), 2);
+}
+
+#[test]
+fn test_resize_partition() {
+    // test1: 34..=57, test2: 58..=93, last usable: 106
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .open_from_device(t_two_partition_disk())
+        .unwrap();
+
+    // test2 is in the way
+    assert!(matches!(
+        gdisk.resize_partition(1, 1024 * 13, None),
+        Err(GptError::NotEnoughSpace {
+            requested: 26,
+            largest_free: 24
+        })
+    ));
+    assert!(matches!(
+        gdisk.resize_partition(3, 1024, None),
+        Err(GptError::PartitionNotFound(3))
+    ));
+    assert!(matches!(
+        gdisk.resize_partition(1, 0, None),
+        Err(GptError::InvalidPartitionSize)
+    ));
+
+    assert_eq!(gdisk.resize_partition(1, 1024 * 4, None).unwrap(), (34, 41));
+    // ends before lba 48
+    assert_eq!(gdisk.resize_partition(1, 1024, Some(16)).unwrap(), (34, 47));
+    assert_eq!(gdisk.grow_partition_to_fill(1, None).unwrap(), (34, 57));
+
+    assert_eq!(
+        gdisk.resize_partition(2, 1024 * 20, None).unwrap(),
+        (58, 97)
+    );
+    assert!(matches!(
+        gdisk.resize_partition(2, 1024 * 30, None),
+        Err(GptError::NotEnoughSpace {
+            requested: 60,
+            largest_free: 49
+        })
+    ));
+    assert_eq!(gdisk.grow_partition_to_fill(2, Some(8)).unwrap(), (58, 103));
+    assert_eq!(gdisk.grow_partition_to_fill(2, None).unwrap(), (58, 106));
+
+    let data = gdisk.write().unwrap();
+    let gdisk = GptConfig::new()
+        .only_valid_headers(true)
+        .open_from_device(data)
+        .unwrap();
+    assert_eq!(gdisk.partitions()[&1].last_lba, 57);
+    assert_eq!(gdisk.partitions()[&2].last_lba, 106);
+}
+
+#[test]
+fn test_move_partition() {
+    // test1: 34..=57, test2: 58..=93, last usable: 106
+    let mut data = t_two_partition_disk();
+    let pattern: Vec<u8> = (0..36 * 512).map(|i| (i / 512) as u8).collect();
+    data.get_mut()[58 * 512..94 * 512].copy_from_slice(&pattern);
+
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .open_from_device(data)
+        .unwrap();
+    assert!(matches!(
+        gdisk.move_partition(2, 50),
+        Err(GptError::InvalidLayout(gpt::verify::Problem::Overlap {
+            id: 1,
+            other: 2
+        }))
+    ));
+    gdisk.remove_partition(1);
+
+    // a resumable move by one lba copies one lba at a time
+    let mv = gdisk.prepare_partition_move(2, 59).unwrap();
+    assert_eq!(mv.chunk_lba, 1);
+
+    // overlapping move to the front
+    assert_eq!(gdisk.move_partition(2, 40).unwrap(), (40, 75));
+    assert_eq!(
+        t_read_bytes(gdisk.device_mut(), 40 * 512, 36 * 512),
+        pattern
+    );
+
+    // overlapping move to the back, interrupted after the first chunk
+    let mut mv = gdisk.prepare_partition_move(2, 60).unwrap();
+    assert_eq!(mv.chunk_lba, 20);
+    assert!(!gdisk.copy_partition_chunk(&mut mv).unwrap());
+    assert_eq!(gdisk.partitions()[&2].first_lba, 40);
+
+    // moves which don't match the partition or the disk anymore copy nothing
+    let before = gdisk.device_ref().clone();
+    let mut stale = mv.clone();
+    stale.from_lba = 41;
+    assert!(matches!(
+        gdisk.copy_partition_chunk(&mut stale),
+        Err(GptError::StalePartitionMove(2))
+    ));
+    let mut outside = mv.clone();
+    outside.to_lba = 1;
+    assert!(matches!(
+        gdisk.copy_partition_chunk(&mut outside),
+        Err(GptError::InvalidLayout(_))
+    ));
+    assert_eq!(gdisk.device_ref(), &before);
+    let mut resized = gdisk.clone();
+    resized.resize_partition(2, 512 * 10, Some(1)).unwrap();
+    assert!(matches!(
+        resized.finish_partition_move(&mut mv.clone()),
+        Err(GptError::StalePartitionMove(2))
+    ));
+
+    let data = gdisk.write().unwrap();
+
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .open_from_device(data)
+        .unwrap();
+    assert_eq!(gdisk.finish_partition_move(&mut mv).unwrap(), (60, 95));
+    assert_eq!(
+        t_read_bytes(gdisk.device_mut(), 60 * 512, 36 * 512),
+        pattern
+    );
+    let data = gdisk.write().unwrap();
+
+    let gdisk = GptConfig::new()
+        .only_valid_headers(true)
+        .open_from_device(data)
+        .unwrap();
+    assert_eq!(gdisk.partitions()[&2].first_lba, 60);
+}
+
+#[test]
+fn test_move_partition_short_distance() {
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .alignment(512)
+        .create_from_device(Cursor::new(vec![0; 4 * 1024 * 1024]), None)
+        .unwrap();
+    gdisk
+        .add_partition_at("test1", 1, 100, 5000, gpt::partition_types::LINUX_FS, 0)
+        .unwrap();
+    let pattern: Vec<u8> = (0..5000 * 512u32)
+        .map(|i| (i / 512 % 251 + i % 512 / 128) as u8)
+        .collect();
+    let device = gdisk.device_mut();
+    device.seek(std::io::SeekFrom::Start(100 * 512)).unwrap();
+    device.write_all(&pattern).unwrap();
+
+    // the 1MiB chunks overlap the data they were copied from
+    assert_eq!(gdisk.move_partition(1, 101).unwrap(), (101, 5100));
+    assert_eq!(
+        t_read_bytes(gdisk.device_mut(), 101 * 512, 5000 * 512),
+        pattern
+    );
+    assert_eq!(gdisk.move_partition(1, 99).unwrap(), (99, 5098));
+    assert_eq!(
+        t_read_bytes(gdisk.device_mut(), 99 * 512, 5000 * 512),
+        pattern
+    );
+}
+
+#[test]
+fn test_sort_partitions() {
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .open_from_device(t_two_partition_disk())
+        .unwrap();
+    gdisk.remove_partition(1);
+    gdisk
+        .add_partition_at("test3", 5, 34, 8, gpt::partition_types::LINUX_FS, 0)
+        .unwrap();
+    gdisk
+        .add_partition_at("test4", 3, 96, 8, gpt::partition_types::LINUX_FS, 0)
+        .unwrap();
+
+    let ids = gdisk.sort_partitions();
+    assert_eq!(
+        ids.into_iter().collect::<Vec<_>>(),
+        [(2, 2), (3, 3), (5, 1)]
+    );
+    let names: Vec<_> = gdisk
+        .partitions()
+        .iter()
+        .map(|(id, p)| (*id, p.name.as_str()))
+        .collect();
+    assert_eq!(names, [(1, "test3"), (2, "test2"), (3, "test4")]);
+
+    let data = gdisk.write().unwrap();
+    let gdisk = GptConfig::new()
+        .only_valid_headers(true)
+        .open_from_device(data)
+        .unwrap();
+    assert_eq!(gdisk.partitions()[&1].first_lba, 34);
+}
+
+#[test]
+fn test_set_entry_capacity() {
+    // test1: 34..=57, test2: 58..=93, backup header: 139
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .alignment_fallback(true)
+        .open_from_device(t_two_partition_disk())
+        .unwrap();
+
+    assert!(matches!(
+        gdisk.set_entry_capacity(1),
+        Err(GptError::OverflowPartitionCount)
+    ));
+    // the array would need 64 lba
+    let err = gdisk.set_entry_capacity(256).unwrap_err();
+    assert!(matches!(err, GptError::PartitionsDoNotFit(ids) if ids == [1, 2]));
+
+    gdisk.set_entry_capacity(56).unwrap();
+    let header = gdisk.primary_header().unwrap();
+    assert_eq!(header.num_parts, 56);
+    assert_eq!(header.first_usable, 2 + 14);
+    assert_eq!(header.last_usable, 139 - 14 - 1);
+    assert_eq!(gdisk.backup_header().unwrap().part_start, 139 - 14);
+
+    // new partitions still fit in the array
+    assert_eq!(
+        gdisk
+            .add_partition("test3", 512, gpt::partition_types::BASIC, 0, None)
+            .unwrap(),
+        3
+    );
+    let data = gdisk.write().unwrap();
+
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .only_valid_headers(true)
+        .open_from_device(data)
+        .unwrap();
+    assert_eq!(gdisk.header().unwrap().num_parts, 56);
+    assert_eq!(gdisk.partitions().len(), 3);
+
+    // test3 got placed in front of test1
+    let err = gdisk.set_entry_capacity(128).unwrap_err();
+    assert!(matches!(err, GptError::PartitionsDoNotFit(ids) if ids == [3]));
+    gdisk.remove_partition(3);
+    gdisk.set_entry_capacity(128).unwrap();
+    let data = gdisk.write().unwrap();
+    let gdisk = GptConfig::new()
+        .only_valid_headers(true)
+        .open_from_device(data)
+        .unwrap();
+    assert_eq!(gdisk.header().unwrap().num_parts, 128);
+    assert_eq!(gdisk.header().unwrap().first_usable, 34);
+
+    // custom array locations are kept
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .primary_part_start(10)
+        .backup_part_start(100)
+        .create_from_device(Cursor::new(vec![0; 1024 * 70]), None)
+        .unwrap();
+    let primary = gdisk.primary_header().unwrap().clone();
+    gdisk.set_entry_capacity(128).unwrap();
+    assert_eq!(gdisk.primary_header().unwrap(), &primary);
+    gdisk.set_entry_capacity(64).unwrap();
+    assert_eq!(gdisk.primary_header().unwrap().part_start, 10);
+    assert_eq!(gdisk.primary_header().unwrap().first_usable, 10 + 16);
+    assert_eq!(gdisk.backup_header().unwrap().part_start, 100);
+    assert_eq!(gdisk.primary_header().unwrap().last_usable, 99);
+    // also when loading a dump
+    let primary = gdisk.primary_header().unwrap().clone();
+    let script = gdisk.sfdisk_script().unwrap();
+    gdisk.load_sfdisk_script(&script).unwrap();
+    assert_eq!(gdisk.primary_header().unwrap(), &primary);
+    assert_eq!(gdisk.backup_header().unwrap().part_start, 100);
+
+    // 64 lba starting at lba 100 would overlap the backup header
+    let err = gdisk.set_entry_capacity(256).unwrap_err();
+    assert!(matches!(
+        err,
+        GptError::Header(gpt::header::HeaderError::InvalidPartitionArrayLocation)
+    ));
+}
+
+#[test]
+fn test_custom_partition_array_location() {
+    // lba 2..10 hold a boot loader payload
+    let mut data = vec![0; 1024 * 70];
+    data[2 * 512..10 * 512].fill(0xaa);
+
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .primary_part_start(10)
+        .backup_part_start(100)
+        .create_from_device(Cursor::new(data), None)
+        .unwrap();
+    let header = gdisk.primary_header().unwrap();
+    assert_eq!(header.part_start, 10);
+    assert_eq!(header.first_usable, 10 + 32);
+    assert_eq!(header.last_usable, 99);
+    assert_eq!(gdisk.backup_header().unwrap().part_start, 100);
+    assert_eq!(
+        gdisk
+            .add_partition_at("test1", 1, 42, 8, gpt::partition_types::BASIC, 0)
+            .unwrap(),
+        1
+    );
+    let data = gdisk.write().unwrap();
+    assert!(data.get_ref()[2 * 512..10 * 512].iter().all(|b| *b == 0xaa));
+
+    // rewriting keeps both locations
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .alignment_fallback(true)
+        .only_valid_headers(true)
+        .open_from_device(data)
+        .unwrap();
+    gdisk
+        .add_partition("test2", 1024, gpt::partition_types::BASIC, 0, None)
+        .unwrap();
+    let data = gdisk.write().unwrap();
+
+    let gdisk = GptConfig::new()
+        .only_valid_headers(true)
+        .open_from_device(data)
+        .unwrap();
+    assert_eq!(gdisk.primary_header().unwrap().part_start, 10);
+    assert_eq!(gdisk.backup_header().unwrap().part_start, 100);
+    assert_eq!(gdisk.partitions().len(), 2);
+    assert!(gdisk.device_ref().get_ref()[2 * 512..10 * 512]
+        .iter()
+        .all(|b| *b == 0xaa));
+
+    // the array would overlap the backup header
+    let err = GptConfig::new()
+        .writable(true)
+        .backup_part_start(120)
+        .create_from_device(Cursor::new(vec![0; 1024 * 70]), None)
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        GptError::Header(gpt::header::HeaderError::InvalidPartitionArrayLocation)
+    ));
+}
+
+#[test]
+fn test_large_partition_entries() {
+    let lb_size = disk::LogicalBlockSize::Lb512;
+    let mut device = Cursor::new(vec![0; 1024 * 70]);
+
+    // 64 entries of 256 bytes also fill 32 lba
+    let mut primary = gpt::header::HeaderBuilder::new()
+        .backup_lba(139)
+        .num_parts(64)
+        .part_size(256)
+        .build(lb_size)
+        .unwrap();
+    let mut backup = gpt::header::HeaderBuilder::from_header(&primary)
+        .primary(false)
+        .build(lb_size)
+        .unwrap();
+    let mut part = gpt::partition::Partition::zero();
+    part.part_type_guid = gpt::partition_types::LINUX_FS;
+    part.first_lba = 34;
+    part.last_lba = 41;
+    part.name = "test1".to_string();
+    part.extra = vec![0xaa; 128];
+    for header in [&primary, &backup] {
+        part.write_to_device(&mut device, 0, header.part_start, lb_size, 256)
+            .unwrap();
+    }
+    backup.write_backup(&mut device, lb_size).unwrap();
+    primary.write_primary(&mut device, lb_size).unwrap();
+
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .alignment_fallback(true)
+        .only_valid_headers(true)
+        .open_from_device(device)
+        .unwrap();
+    assert_eq!(gdisk.partitions()[&1], part);
+    gdisk
+        .add_partition("test2", 1024, gpt::partition_types::BASIC, 0, None)
+        .unwrap();
+    let device = gdisk.write().unwrap();
+
+    let gdisk = GptConfig::new()
+        .only_valid_headers(true)
+        .open_from_device(device)
+        .unwrap();
+    assert_eq!(gdisk.header().unwrap().part_size, 256);
+    assert_eq!(gdisk.partitions()[&1].extra, vec![0xaa; 128]);
+    assert!(gdisk.partitions()[&2].extra.is_empty());
+
+    // extra bytes are never cut off
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .open_from_device(gdisk.take_device())
+        .unwrap();
+    let mut partitions = gdisk.partitions().clone();
+    partitions.get_mut(&2).unwrap().extra = vec![0xbb; 129];
+    gdisk.update_partitions(partitions).unwrap();
+    let err = gdisk.write_inplace().unwrap_err();
+    assert!(matches!(
+        err,
+        GptError::Partition(gpt::partition::PartitionError::ExtraBytesDoNotFit {
+            len: 129,
+            entry_size: 256
+        })
+    ));
+
+    // not 128 * 2^n
+    for size in [0, 100, 384] {
+        let err = gpt::header::HeaderBuilder::new()
+            .backup_lba(139)
+            .part_size(size)
+            .build(lb_size)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            gpt::header::HeaderError::InvalidPartitionEntrySize(s) if s == size
+        ));
+    }
+}
+
+#[test]
+fn test_error_context() {
+    use gpt::header::HeaderError;
+    use gpt::partition::PartitionError;
+
+    // test1: 34..=57, test2: 58..=93, last usable: 106
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .open_from_device(t_two_partition_disk())
+        .unwrap();
+    assert!(matches!(
+        gdisk.add_partition("big", 1024 * 20, gpt::partition_types::BASIC, 0, None),
+        Err(GptError::NotEnoughSpace {
+            requested: 40,
+            largest_free: 13
+        })
+    ));
+    assert!(matches!(
+        gdisk.add_partition_at("used", 2, 100, 1, gpt::partition_types::BASIC, 0),
+        Err(GptError::PartitionIdAlreadyUsed(2))
+    ));
+
+    // a corrupted primary header
+    let mut data = t_two_partition_disk();
+    data.get_mut()[512 + 40] ^= 0xff;
+    let err = GptConfig::new()
+        .only_valid_headers(true)
+        .open_from_device(data)
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        GptError::InvalidHeader {
+            structure: GptStructure::PrimaryHeader,
+            error: HeaderError::InvalidCRC32Checksum { .. }
+        }
+    ));
+
+    // both partition arrays corrupted
+    let mut data = t_two_partition_disk();
+    data.get_mut()[2 * 512] ^= 0xff;
+    data.get_mut()[107 * 512] ^= 0xff;
+    let err = GptConfig::new().open_from_device(data).unwrap_err();
+    match err {
+        GptError::InvalidPartitionArray {
+            structure: GptStructure::BackupEntries,
+            error: PartitionError::InvalidCRC32Checksum { expected, actual },
+        } => assert_ne!(expected, actual),
+        e => panic!("unexpected error {e:?}"),
+    }
+}
+
+#[test]
+fn test_verify() {
+    use gpt::mbr::ProtectiveMBR;
+    use gpt::verify::{Problem, Severity};
+
+    // test1: 34..=57, test2: 58..=93, last usable: 106, backup header: 139
+    let mut data = t_two_partition_disk();
+    let mut gdisk = GptConfig::new().open_from_device(&mut data).unwrap();
+    let report = gdisk.verify().unwrap();
+    assert_eq!(report.max_severity(), Some(Severity::Error));
+    assert!(matches!(
+        report.severity(Severity::Error).next().unwrap().problem,
+        Problem::Unreadable {
+            structure: GptStructure::ProtectiveMbr,
+            ..
+        }
+    ));
+
+    ProtectiveMBR::with_lb_size(139)
+        .overwrite_lba0(&mut data)
+        .unwrap();
+    let mut gdisk = GptConfig::new().open_from_device(&mut data).unwrap();
+    let report = gdisk.verify().unwrap();
+    assert_eq!(report.max_severity(), Some(Severity::Warning));
+    let problems: Vec<_> = report.findings().iter().map(|f| &f.problem).collect();
+    assert_eq!(
+        problems,
+        [
+            &Problem::Misaligned {
+                id: 1,
+                first_lba: 34,
+                alignment: 2048
+            },
+            &Problem::Misaligned {
+                id: 2,
+                first_lba: 58,
+                alignment: 2048
+            },
+        ]
+    );
+
+    // overlapping partitions with the same guid, one beyond the usable lbas
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .open_from_device(&mut data)
+        .unwrap();
+    let mut partitions = gdisk.partitions().clone();
+    let first = partitions[&1].clone();
+    let second = partitions.get_mut(&2).unwrap();
+    second.part_guid = first.part_guid;
+    second.first_lba = 50;
+    second.last_lba = 110;
+    assert!(matches!(
+        gdisk.update_partitions(partitions.clone()),
+        Err(GptError::InvalidLayout(Problem::OutsideUsableRange {
+            id: 2,
+            ..
+        }))
+    ));
+    gdisk.update_partitions_unchecked(partitions).unwrap();
+    gdisk.write().unwrap();
+
+    let mut gdisk = GptConfig::new().open_from_device(&mut data).unwrap();
+    let errors: Vec<_> = gdisk
+        .verify()
+        .unwrap()
+        .severity(Severity::Error)
+        .map(|f| f.problem.clone())
+        .collect();
+    assert_eq!(
+        errors,
+        [
+            Problem::OutsideUsableRange {
+                id: 2,
+                first_lba: 50,
+                last_lba: 110
+            },
+            Problem::DuplicateGuid {
+                id: 1,
+                other: 2,
+                guid: first.part_guid
+            },
+            Problem::Overlap { id: 1, other: 2 },
+        ]
+    );
+
+    // a damaged primary array and a protective partition which is too small
+    data.get_mut()[1024] ^= 0xFF;
+    ProtectiveMBR::with_lb_size(100)
+        .overwrite_lba0(&mut data)
+        .unwrap();
+    let mut gdisk = GptConfig::new().open_from_device(&mut data).unwrap();
+    let report = gdisk.verify().unwrap();
+    let findings = report.findings();
+    assert_eq!(
+        findings[0].problem,
+        Problem::ProtectiveMbrSize {
+            expected: 139,
+            actual: 100
+        }
+    );
+    assert_eq!(findings[0].severity, Severity::Warning);
+    assert!(matches!(
+        findings[1].problem,
+        Problem::InvalidCRC32 {
+            structure: GptStructure::PrimaryEntries,
+            ..
+        }
+    ));
+    assert_eq!(
+        findings[1].to_string(),
+        format!("error: {}", findings[1].problem)
+    );
+    // the partitions are still checked using the backup array
+    assert!(report
+        .severity(Severity::Error)
+        .any(|f| f.problem == Problem::Overlap { id: 1, other: 2 }));
+}
+
+#[test]
+fn test_layout_validation() {
+    use gpt::verify::Problem;
+
+    // test1: 34..=57, test2: 58..=93, last usable: 106
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .open_from_device(t_two_partition_disk())
+        .unwrap();
+
+    // starts in the free space but would run into the backup array
+    assert!(matches!(
+        gdisk.add_partition_at("end", 3, 100, 10, gpt::partition_types::BASIC, 0),
+        Err(GptError::NotEnoughSpace {
+            requested: 10,
+            largest_free: 13
+        })
+    ));
+    assert_eq!(
+        gdisk
+            .add_partition_at("end", 3, 100, 7, gpt::partition_types::BASIC, 0)
+            .unwrap(),
+        3
+    );
+    assert_eq!(gdisk.partitions()[&3].last_lba, 106);
+
+    let mut partitions = gdisk.partitions().clone();
+    partitions.get_mut(&2).unwrap().first_lba = 57;
+    assert!(matches!(
+        gdisk.update_partitions(partitions),
+        Err(GptError::InvalidLayout(Problem::Overlap {
+            id: 1,
+            other: 2
+        }))
+    ));
+
+    let mut partitions = gdisk.partitions().clone();
+    let guid = partitions[&1].part_guid;
+    partitions.get_mut(&3).unwrap().part_guid = guid;
+    match gdisk.update_partitions(partitions) {
+        Err(
+            e @ GptError::InvalidLayout(Problem::DuplicateGuid {
+                id: 1, other: 3, ..
+            }),
+        ) => {
+            assert_eq!(
+                e.to_string(),
+                format!("invalid partition layout: partitions 1 and 3 have the same guid {guid}")
+            )
+        }
+        r => panic!("unexpected result {r:?}"),
+    }
+
+    let mut partitions = gdisk.partitions().clone();
+    let part = partitions.get_mut(&3).unwrap();
+    part.first_lba = 106;
+    part.last_lba = 105;
+    assert!(matches!(
+        gdisk.update_partitions(partitions.clone()),
+        Err(GptError::InvalidLayout(Problem::InvalidExtent {
+            id: 3,
+            ..
+        }))
+    ));
+
+    // the unchecked variant takes whatever it gets
+    gdisk.update_partitions_unchecked(partitions).unwrap();
+    assert_eq!(gdisk.partitions()[&3].last_lba, 105);
+}
+
+#[test]
+fn test_alignment_policy() {
+    use gpt::verify::{Problem, Severity};
+
+    let new_disk = |config: GptConfig| {
+        config
+            .writable(true)
+            .create_from_device(Cursor::new(vec![0; 8 * 1024 * 1024]), None)
+            .unwrap()
+    };
+
+    // 1MiB by default
+    let mut gdisk = new_disk(GptConfig::new());
+    assert_eq!(gdisk.alignment_lba(), 2048);
+    assert_eq!(gdisk.physical_block_lba(), 1);
+    for first_lba in [2048, 4096] {
+        let id = gdisk
+            .add_partition("a", 1024, gpt::partition_types::BASIC, 0, None)
+            .unwrap();
+        assert_eq!(gdisk.partitions()[&id].first_lba, first_lba);
+    }
+    // an explicit alignment wins
+    let id = gdisk
+        .add_partition("b", 1024, gpt::partition_types::BASIC, 0, Some(1))
+        .unwrap();
+    assert_eq!(gdisk.partitions()[&id].first_lba, 34);
+
+    // the alignment is rounded up to whole lba and physical blocks
+    let gdisk = new_disk(GptConfig::new().alignment(1000));
+    assert_eq!(gdisk.alignment_lba(), 2);
+    let gdisk = new_disk(GptConfig::new().alignment(6000).physical_block_size(4096));
+    assert_eq!((gdisk.alignment_lba(), gdisk.physical_block_lba()), (16, 8));
+
+    // 512e on a 4K drive without any further alignment
+    let mut gdisk = new_disk(GptConfig::new().alignment(0).physical_block_size(4096));
+    let id = gdisk
+        .add_partition("c", 1024, gpt::partition_types::BASIC, 0, None)
+        .unwrap();
+    assert_eq!(gdisk.partitions()[&id].first_lba, 40);
+    assert_eq!(gdisk.resize_partition(id, 512, None).unwrap(), (40, 47));
+
+    // without room for 1MiB alignment partitions don't fit
+    // test1: 34..=57, test2: 58..=93, last usable: 106
+    let mut data = t_two_partition_disk();
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .physical_block_size(4096)
+        .open_from_device(&mut data)
+        .unwrap();
+    assert!(matches!(
+        gdisk.add_partition("d", 1024, gpt::partition_types::BASIC, 0, None),
+        Err(GptError::NotEnoughSpace {
+            requested: 2,
+            largest_free: 13
+        })
+    ));
+
+    // unless they may only be physically aligned
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .physical_block_size(4096)
+        .alignment_fallback(true)
+        .open_from_device(&mut data)
+        .unwrap();
+    let id = gdisk
+        .add_partition("d", 1024, gpt::partition_types::BASIC, 0, None)
+        .unwrap();
+    assert_eq!(gdisk.partitions()[&id].first_lba, 96);
+    assert_eq!(gdisk.grow_partition_to_fill(id, None).unwrap(), (96, 103));
+    gdisk.write().unwrap();
+
+    let mut gdisk = GptConfig::new()
+        .physical_block_size(4096)
+        .open_from_device(&mut data)
+        .unwrap();
+    let warnings: Vec<_> = gdisk
+        .verify()
+        .unwrap()
+        .severity(Severity::Warning)
+        .map(|f| f.problem.clone())
+        .collect();
+    assert_eq!(
+        warnings,
+        [
+            Problem::PhysicalBlockMisaligned {
+                id: 1,
+                first_lba: 34,
+                physical_block_lba: 8
+            },
+            Problem::PhysicalBlockMisaligned {
+                id: 2,
+                first_lba: 58,
+                physical_block_lba: 8
+            },
+            Problem::Misaligned {
+                id: 3,
+                first_lba: 96,
+                alignment: 2048
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_placement() {
+    use gpt::placement::Placement;
+
+    // test2: 58..=93, free: 34..=57 and 94..=106
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .open_from_device(t_two_partition_disk())
+        .unwrap();
+    gdisk.remove_partition(1);
+
+    assert_eq!(gdisk.find_free_sectors(), [(34, 24), (94, 13)]);
+    assert_eq!(gdisk.find_free_sectors_aligned(8, 0), [(40, 18), (96, 11)]);
+    assert_eq!(gdisk.find_free_sectors_aligned(8, 12), [(40, 18)]);
+    assert!(gdisk.find_free_sectors_aligned(64, 0).is_empty());
+
+    let place = |placement, alignment| {
+        let mut d = gdisk.clone();
+        let id = d.add_partition_placed(
+            "p",
+            512 * 4,
+            gpt::partition_types::BASIC,
+            0,
+            Some(alignment),
+            placement,
+        )?;
+        Ok::<_, GptError>(d.partitions()[&id].first_lba)
+    };
+    assert_eq!(place(Placement::FirstFit, 1).unwrap(), 34);
+    assert_eq!(place(Placement::BestFit, 1).unwrap(), 94);
+    assert_eq!(place(Placement::LastFit, 1).unwrap(), 103);
+    assert_eq!(place(Placement::LastFit, 8).unwrap(), 96);
+    let within = |first_lba, last_lba| Placement::Within {
+        first_lba,
+        last_lba,
+    };
+    assert_eq!(place(within(50, 200), 1).unwrap(), 50);
+    assert_eq!(place(within(50, 200), 8).unwrap(), 96);
+    assert!(matches!(
+        place(within(55, 60), 1),
+        Err(GptError::NotEnoughSpace {
+            requested: 4,
+            largest_free: 24
+        })
+    ));
+
+    // the configured strategy
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .alignment_fallback(true)
+        .placement(Placement::LastFit)
+        .open_from_device(t_two_partition_disk())
+        .unwrap();
+    let id = gdisk
+        .add_partition("last", 512 * 4, gpt::partition_types::BASIC, 0, None)
+        .unwrap();
+    assert_eq!(gdisk.partitions()[&id].first_lba, 103);
+}
+
+#[test]
+fn test_partition_size() {
+    use gpt::partition_size::{PartitionSize, ResolvedSize};
+
+    // test1: 34..=57, test2: 58..=93, free: 94..=106
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .alignment_fallback(true)
+        .open_from_device(t_two_partition_disk())
+        .unwrap();
+
+    let size: PartitionSize = "1.5K".parse().unwrap();
+    assert_eq!(size, PartitionSize::Bytes(1536));
+    assert_eq!(
+        gdisk.resolve_size(size, None).unwrap(),
+        ResolvedSize {
+            bytes: 1536,
+            lba: 3
+        }
+    );
+    assert_eq!(gdisk.resolve_size(1000, None).unwrap().lba, 2);
+    assert_eq!(
+        gdisk
+            .resolve_size(PartitionSize::Lba(4), None)
+            .unwrap()
+            .bytes,
+        2048
+    );
+
+    let id = gdisk
+        .add_partition(
+            "half",
+            "50%".parse::<PartitionSize>().unwrap(),
+            gpt::partition_types::BASIC,
+            0,
+            None,
+        )
+        .unwrap();
+    assert_eq!(gdisk.partitions()[&id].first_lba, 94);
+    assert_eq!(gdisk.partitions()[&id].last_lba, 99);
+    assert_eq!(
+        gdisk.resolve_size(PartitionSize::Remaining, None).unwrap(),
+        ResolvedSize {
+            bytes: 7 * 512,
+            lba: 7
+        }
+    );
+    let rest = gdisk
+        .add_partition(
+            "rest",
+            PartitionSize::Remaining,
+            gpt::partition_types::BASIC,
+            0,
+            None,
+        )
+        .unwrap();
+    assert_eq!(gdisk.partitions()[&rest].first_lba, 100);
+    assert_eq!(gdisk.partitions()[&rest].last_lba, 106);
+    assert!(matches!(
+        gdisk.resolve_size(PartitionSize::Remaining, None),
+        Err(GptError::NotEnoughSpace {
+            requested: 1,
+            largest_free: 0
+        })
+    ));
+    assert!(matches!(
+        gdisk.add_partition(
+            "zero",
+            PartitionSize::Lba(0),
+            gpt::partition_types::BASIC,
+            0,
+            None
+        ),
+        Err(GptError::InvalidPartitionSize)
+    ));
+
+    // resizing refers to the space up to the next partition
+    gdisk.remove_partition(rest);
+    assert_eq!(
+        gdisk
+            .resize_partition(id, PartitionSize::PercentOfFree(50), None)
+            .unwrap(),
+        (94, 99)
+    );
+    assert_eq!(
+        gdisk
+            .resize_partition(id, PartitionSize::Remaining, Some(8))
+            .unwrap(),
+        (94, 103)
+    );
+    assert_eq!(
+        gdisk
+            .resize_partition(id, "1K".parse::<PartitionSize>().unwrap(), None)
+            .unwrap(),
+        (94, 95)
+    );
+}
+
+#[test]
+fn test_layout() {
+    use gpt::layout::{Layout, LayoutAction, PartitionSpec};
+    use gpt::partition_size::PartitionSize;
+    use gpt::partition_types::{BASIC, LINUX_FS};
+
+    // test1: 34..=57, test2: 58..=93, free: 94..=106
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .alignment_fallback(true)
+        .open_from_device(t_two_partition_disk())
+        .unwrap();
+    let partitions = gdisk.partitions().clone();
+
+    let layout = Layout::new()
+        .partition(PartitionSpec::new("test1", BASIC, 1024 * 12).flags(1))
+        .partition(PartitionSpec::new("test2", LINUX_FS, 1024).max_size(PartitionSize::Remaining))
+        .partition(
+            PartitionSpec::new("new", LINUX_FS, PartitionSize::Lba(2))
+                .max_size(PartitionSize::Remaining),
+        );
+    let plan = gdisk.plan_layout(&layout).unwrap();
+    // planning does not change anything
+    assert_eq!(gdisk.partitions(), &partitions);
+    assert_eq!(plan.ids(), [1, 2, 3]);
+    let actions = plan.actions();
+    assert_eq!(actions.len(), 3);
+    assert_eq!(
+        actions[0],
+        LayoutAction::Update {
+            id: 1,
+            name: "test1".into(),
+            flags: 1
+        }
+    );
+    match &actions[1] {
+        LayoutAction::Create { id: 3, partition } => {
+            assert_eq!((partition.first_lba, partition.last_lba), (94, 95));
+            assert_eq!(partition.name, "new");
+        }
+        a => panic!("unexpected action {a:?}"),
+    }
+    assert_eq!(
+        actions[2],
+        LayoutAction::Grow {
+            id: 3,
+            old_last_lba: 95,
+            last_lba: 106
+        }
+    );
+
+    gdisk.apply_layout(&plan).unwrap();
+    assert_eq!(gdisk.partitions()[&1].flags, 1);
+    assert_eq!(gdisk.partitions()[&3].last_lba, 106);
+    assert!(gdisk.plan_layout(&layout).unwrap().is_empty());
+    // the plan does not fit anymore
+    assert!(matches!(
+        gdisk.apply_layout(&plan),
+        Err(GptError::PartitionIdAlreadyUsed(3))
+    ));
+
+    // a failing plan changes nothing
+    let mut other = GptConfig::new()
+        .writable(true)
+        .alignment_fallback(true)
+        .open_from_device(t_two_partition_disk())
+        .unwrap();
+    other
+        .add_partition("other", 1024, LINUX_FS, 0, None)
+        .unwrap();
+    let before = other.partitions().clone();
+    assert!(matches!(
+        other.apply_layout(&plan),
+        Err(GptError::PartitionIdAlreadyUsed(3))
+    ));
+    assert_eq!(other.partitions(), &before);
+
+    // a partition grown by the plan was changed in between
+    other.remove_partition(3);
+    let grow = Layout::new()
+        .partition(PartitionSpec::new("test1", BASIC, 1024 * 12))
+        .partition(PartitionSpec::new("test2", LINUX_FS, 1024).max_size(PartitionSize::Remaining));
+    let grow_plan = other.plan_layout(&grow).unwrap();
+    assert_eq!(
+        grow_plan.actions(),
+        [LayoutAction::Grow {
+            id: 2,
+            old_last_lba: 93,
+            last_lba: 106
+        }]
+    );
+    other.resize_partition(2, 1024 * 4, None).unwrap();
+    assert!(matches!(
+        other.apply_layout(&grow_plan),
+        Err(GptError::StaleLayoutPlan(2))
+    ));
+
+    // creating a partition in a full table grows the partition array
+    let mut full = GptConfig::new()
+        .writable(true)
+        .alignment(2048)
+        .change_partition_count(true)
+        .create_from_device(Cursor::new(vec![0; 512 * 600]), None)
+        .unwrap();
+    for i in 0..128 {
+        full.add_partition(&format!("test{i}"), 512, BASIC, 0, None)
+            .unwrap();
+    }
+    let plan = full
+        .plan_layout(&Layout::new().partition(PartitionSpec::new("new", LINUX_FS, 512)))
+        .unwrap();
+    assert!(matches!(
+        plan.actions(),
+        [LayoutAction::Create { id: 129, .. }]
+    ));
+    full.apply_layout(&plan).unwrap();
+    assert_eq!(full.header().unwrap().num_parts, 129);
+    let data = full.write().unwrap();
+    let full = GptConfig::new().open_from_device(data).unwrap();
+    assert_eq!(full.header().unwrap().num_parts, 129);
+    assert_eq!(full.partitions()[&129].name, "new");
+
+    // partitions not in the layout are only removed if allowed
+    let layout = Layout::new().partition(PartitionSpec::new("new", LINUX_FS, 512));
+    assert!(gdisk.plan_layout(&layout).unwrap().is_empty());
+    let plan = gdisk.plan_layout(&layout.allow_removal(true)).unwrap();
+    assert_eq!(
+        plan.actions(),
+        [
+            LayoutAction::Remove { id: 1 },
+            LayoutAction::Remove { id: 2 }
+        ]
+    );
+
+    let guid = gdisk.partitions()[&1].part_guid;
+    let layout = Layout::new().partition(PartitionSpec::new("x", LINUX_FS, 512).guid(guid));
+    assert!(matches!(
+        gdisk.plan_layout(&layout),
+        Err(GptError::PartitionTypeMismatch(1))
+    ));
+    let layout = Layout::new().partition(PartitionSpec::new("big", LINUX_FS, 1024 * 1024));
+    assert!(matches!(
+        gdisk.plan_layout(&layout),
+        Err(GptError::NotEnoughSpace { .. })
+    ));
+}
+
+#[test]
+fn test_repart() {
+    use gpt::repart::{self, RepartDefinition};
+
+    // test1: 34..=57, test2: 58..=93, free: 94..=106
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .alignment_fallback(true)
+        .open_from_device(t_two_partition_disk())
+        .unwrap();
+
+    let definitions = gdisk.repart_definitions();
+    assert_eq!(definitions.len(), 2);
+    let test2 = definitions[1].to_string();
+    assert!(test2.starts_with("[Partition]\nType=linux-generic\nLabel=test2\nUUID="));
+    assert!(test2.ends_with("SizeMinBytes=18432\nSizeMaxBytes=18432\n"));
+
+    // the exported definitions describe the disk as it is
+    let parsed: Vec<RepartDefinition> = definitions
+        .iter()
+        .map(|d| d.to_string().parse().unwrap())
+        .collect();
+    assert_eq!(parsed, definitions);
+    assert!(gdisk
+        .plan_layout(&repart::layout(&parsed).unwrap())
+        .unwrap()
+        .is_empty());
+
+    let swap: RepartDefinition = "[Partition]\nType=swap\nSizeMinBytes=1K\nGrowFileSystem=on\n"
+        .parse()
+        .unwrap();
+    let mut definitions = parsed;
+    definitions.push(swap);
+    let plan = gdisk
+        .plan_layout(&repart::layout(&definitions).unwrap())
+        .unwrap();
+    gdisk.apply_layout(&plan).unwrap();
+    let part = &gdisk.partitions()[&3];
+    assert_eq!(part.part_type_guid, gpt::partition_types::LINUX_SWAP);
+    assert_eq!(part.name, "swap");
+    assert_eq!((part.first_lba, part.last_lba), (94, 106));
+    assert_eq!(part.flags, repart::GROW_FILE_SYSTEM_FLAG);
+
+    // a minimum size which does not fit
+    let home: RepartDefinition = "[Partition]\nType=home\n".parse().unwrap();
+    definitions.push(home);
+    assert!(matches!(
+        gdisk.plan_layout(&repart::layout(&definitions).unwrap()),
+        Err(GptError::NotEnoughSpace { .. })
+    ));
+
+    // settings which can't be reconciled are refused unless they are the default
+    definitions.pop();
+    for (setting, key) in [
+        ("Priority=0", None),
+        ("Weight=1000", None),
+        ("PaddingMinBytes=0", None),
+        ("Priority=1", Some("Priority")),
+        ("Weight=500", Some("Weight")),
+        ("PaddingMinBytes=1K", Some("PaddingMinBytes")),
+    ] {
+        definitions[2] = format!("[Partition]\nType=swap\n{setting}\n")
+            .parse()
+            .unwrap();
+        let res = repart::layout(&definitions);
+        match key {
+            Some(key) => assert_eq!(res.unwrap_err(), repart::RepartError::Unsupported(key)),
+            None => assert!(res.is_ok()),
+        }
+    }
+}
+
+#[test]
+fn test_sfdisk_script() {
+    use gpt::partition_size::PartitionSize;
+    use gpt::sfdisk::SfdiskScript;
+
+    // test1: 34..=57, test2: 58..=93, free: 94..=106
+    let gdisk = GptConfig::new()
+        .writable(true)
+        .open_from_device(t_two_partition_disk())
+        .unwrap();
+    let mut script = gdisk.sfdisk_script().unwrap();
+    script.device = Some("/dev/sda".into());
+    let text = script.to_string();
+    assert!(text.starts_with(&format!(
+        "label: gpt\nlabel-id: {}\ndevice: /dev/sda\nunit: sectors\nfirst-lba: 34\n\
+        last-lba: 106\ntable-length: 128\nsector-size: 512\n\n/dev/sda1 : start=          34, \
+        size=          24, type=EBD0A0A2-B9E5-4433-87C0-68B6B72699C7",
+        gdisk.guid().to_string().to_uppercase()
+    )));
+    assert!(text.ends_with(", name=\"test2\"\n"));
+    let parsed: SfdiskScript = text.parse().unwrap();
+    assert_eq!(parsed, script);
+
+    // recreate the table on an empty disk of the same size
+    let mut new_disk = GptConfig::new()
+        .writable(true)
+        .create_from_device(Cursor::new(vec![0; 1024 * 70]), None)
+        .unwrap();
+    new_disk.load_sfdisk_script(&parsed).unwrap();
+    assert_eq!(new_disk.guid(), gdisk.guid());
+    assert_eq!(new_disk.partitions(), gdisk.partitions());
+    let mut device = new_disk.write().unwrap();
+    let reopened = GptConfig::new().open_from_device(&mut device).unwrap();
+    assert_eq!(reopened.partitions(), gdisk.partitions());
+
+    // partitions without start follow the previous one
+    let mut script = parsed;
+    script.partitions.truncate(1);
+    script.partitions[0].attrs = Some(1 << 2);
+    script.partitions.push(gpt::sfdisk::SfdiskPartition {
+        id: Some(4),
+        size: Some(PartitionSize::Bytes(4096)),
+        ..Default::default()
+    });
+    script.partitions.push(Default::default());
+    let mut new_disk = GptConfig::new()
+        .writable(true)
+        .alignment_fallback(true)
+        .create_from_device(Cursor::new(vec![0; 1024 * 70]), None)
+        .unwrap();
+    new_disk.load_sfdisk_script(&script).unwrap();
+    let partitions = new_disk.partitions();
+    assert_eq!(partitions[&1].flags, 1 << 2);
+    assert_eq!(
+        (partitions[&4].first_lba, partitions[&4].last_lba),
+        (58, 65)
+    );
+    assert_eq!(
+        partitions[&4].part_type_guid,
+        gpt::partition_types::LINUX_FS
+    );
+    assert_eq!(
+        (partitions[&2].first_lba, partitions[&2].last_lba),
+        (66, 106)
+    );
+
+    // invalid scripts leave the disk unchanged
+    let before = new_disk.partitions().clone();
+    script.partitions[1].start = Some(40);
+    assert!(matches!(
+        new_disk.load_sfdisk_script(&script),
+        Err(GptError::InvalidLayout(_))
+    ));
+    script.sector_size = Some(4096);
+    assert!(matches!(
+        new_disk.load_sfdisk_script(&script),
+        Err(GptError::LogicalBlockSizeMismatch {
+            expected: 512,
+            actual: 4096
+        })
+    ));
+    assert_eq!(new_disk.partitions(), &before);
+}
+
+#[test]
+fn test_sgdisk_backup() {
+    // test1: 34..=57, test2: 58..=93, backup header: 139
+    let mut gdisk = GptConfig::new()
+        .writable(true)
+        .open_from_device(t_two_partition_disk())
+        .unwrap();
+    let mut backup = vec![];
+    gdisk.save_backup_to(&mut backup).unwrap();
+    // MBR, both headers and 128 entries
+    assert_eq!(backup.len(), 512 * 3 + 128 * 128);
+    assert_eq!(backup[510..512], [0x55, 0xAA]);
+    assert_eq!(&backup[512..520], b"EFI PART");
+    assert_eq!(&backup[1024..1032], b"EFI PART");
+    // the current lba of the backup header
+    assert_eq!(backup[1024 + 24], 139);
+
+    // a device of the same size
+    let disk = GptConfig::new()
+        .writable(true)
+        .restore_backup_from(Cursor::new(vec![0; 1024 * 70]), &backup[..])
+        .unwrap();
+    assert_eq!(disk.guid(), gdisk.guid());
+    assert_eq!(disk.partitions(), gdisk.partitions());
+    let mut device = disk.write().unwrap();
+    let reopened = GptConfig::new()
+        .only_valid_headers(true)
+        .open_from_device(&mut device)
+        .unwrap();
+    assert_eq!(reopened.partitions(), gdisk.partitions());
+    assert_eq!(reopened.backup_header().unwrap().current_lba, 139);
+    let mbr = gpt::mbr::ProtectiveMBR::from_disk(&mut device, disk::DEFAULT_SECTOR_SIZE).unwrap();
+    assert_eq!(mbr.partition(0).unwrap().lb_size, 139);
+
+    // a larger device gets the backup structures at its end
+    let disk = GptConfig::new()
+        .writable(true)
+        .restore_backup_from(Cursor::new(vec![0; 1024 * 100]), &backup[..])
+        .unwrap();
+    assert_eq!(disk.partitions(), gdisk.partitions());
+    assert_eq!(disk.backup_header().unwrap().current_lba, 199);
+    assert_eq!(disk.primary_header().unwrap().last_usable, 166);
+    let mut device = disk.write().unwrap();
+    let reopened = GptConfig::new()
+        .only_valid_headers(true)
+        .open_from_device(&mut device)
+        .unwrap();
+    assert_eq!(reopened.partitions(), gdisk.partitions());
+    let mbr = gpt::mbr::ProtectiveMBR::from_disk(&mut device, disk::DEFAULT_SECTOR_SIZE).unwrap();
+    assert_eq!(mbr.partition(0).unwrap().lb_size, 199);
+
+    // a device which is too small
+    assert!(matches!(
+        GptConfig::new()
+            .writable(true)
+            .restore_backup_from(Cursor::new(vec![0; 1024 * 40]), &backup[..]),
+        Err(GptError::PartitionsDoNotFit(ids)) if ids == [1, 2]
+    ));
+    assert!(matches!(
+        GptConfig::new().restore_backup_from(Cursor::new(vec![0; 1024 * 70]), &backup[..]),
+        Err(GptError::ReadOnly)
+    ));
+
+    // corrupted backups
+    let mut corrupted = backup.clone();
+    corrupted[1024 + 40] ^= 1;
+    assert!(matches!(
+        GptConfig::new()
+            .writable(true)
+            .restore_backup_from(Cursor::new(vec![0; 1024 * 70]), &corrupted[..]),
+        Err(GptError::InvalidHeader {
+            structure: GptStructure::BackupHeader,
+            ..
+        })
+    ));
+    let mut corrupted = backup.clone();
+    corrupted[1536 + 60] ^= 1;
+    assert!(matches!(
+        GptConfig::new()
+            .writable(true)
+            .restore_backup_from(Cursor::new(vec![0; 1024 * 70]), &corrupted[..]),
+        Err(GptError::InvalidPartitionArray {
+            structure: GptStructure::PrimaryEntries,
+            ..
+        })
+    ));
+    assert!(matches!(
+        GptConfig::new()
+            .writable(true)
+            .restore_backup_from(Cursor::new(vec![0; 1024 * 70]), &backup[..300]),
+        Err(GptError::Io(_))
+    ));
+
+    // through files
+    let file = NamedTempFile::new().unwrap();
+    gdisk.save_backup(file.path()).unwrap();
+    assert_eq!(std::fs::read(file.path()).unwrap(), backup);
+    let disk = GptConfig::new()
+        .writable(true)
+        .restore_backup(Cursor::new(vec![0; 1024 * 70]), file.path())
+        .unwrap();
+    assert_eq!(disk.partitions(), gdisk.partitions());
+}
@@ -101,3 +101,20 @@ fn test_mbr_disksig() {
     let s2 = mbr::read_disk_signature(&mut tempdisk).unwrap();
     assert_eq!(s1.to_vec(), s2.to_vec());
 }
+
+#[test]
+fn test_mbr_errors() {
+    let lb_size = disk::LogicalBlockSize::Lb512;
+    let err = mbr::ProtectiveMBR::from_bytes(&[0; 16], lb_size).unwrap_err();
+    assert!(matches!(
+        err,
+        mbr::MBRError::InvalidMBRLength {
+            expected: 512,
+            actual: 16
+        }
+    ));
+    let err = mbr::ProtectiveMBR::from_bytes(&[0; 512], lb_size).unwrap_err();
+    assert!(matches!(err, mbr::MBRError::InvalidMBRSignature([0, 0])));
+    let err = mbr::PartRecord::from_bytes(&[0; 15]).unwrap_err();
+    assert!(matches!(err, mbr::MBRError::InvalidPartitionLength(15)));
+}
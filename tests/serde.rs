@@ -0,0 +1,123 @@
+#![cfg(feature = "serde")]
+
+use gpt::header::HeaderBuilder;
+use gpt::partition::{Partition, PartitionAttributes};
+use gpt::{disk, journal, mbr, partition_types, placement, GptConfig};
+
+#[test]
+fn test_serde_partition() {
+    let part = Partition {
+        part_type_guid: partition_types::LINUX_FS,
+        part_guid: "0f8c4e5e-8b0b-4e3b-9c1a-6a2f1d4e7b21".parse().unwrap(),
+        first_lba: 2048,
+        last_lba: 4095,
+        flags: 1 << 60,
+        name: "root".into(),
+        extra: vec![],
+    };
+    let json = serde_json::to_string(&part).unwrap();
+    assert_eq!(
+        json,
+        r#"{"part_type_guid":{"guid":"0fc63daf-8483-4772-8e79-3d69d8477de4","os":"Linux"},"part_guid":"0f8c4e5e-8b0b-4e3b-9c1a-6a2f1d4e7b21","first_lba":2048,"last_lba":4095,"flags":1152921504606846976,"name":"root","extra":""}"#
+    );
+    assert_eq!(serde_json::from_str::<Partition>(&json).unwrap(), part);
+
+    // extra bytes are optional
+    let json = r#"{"part_type_guid":{"guid":"0FC63DAF-8483-4772-8E79-3D69D8477DE4","os":{"Custom":"x"}},
+        "part_guid":"0f8c4e5e-8b0b-4e3b-9c1a-6a2f1d4e7b21","first_lba":1,"last_lba":2,"flags":0,"name":""}"#;
+    let parsed: Partition = serde_json::from_str(json).unwrap();
+    assert_eq!(parsed.part_type_guid.guid, partition_types::LINUX_FS.guid);
+    assert_eq!(
+        parsed.part_type_guid.os,
+        partition_types::OperatingSystem::Custom("x".into())
+    );
+    assert!(parsed.extra.is_empty());
+
+    let mut part = part;
+    part.extra = vec![0xde, 0xad, 0, 1];
+    let json = serde_json::to_string(&part).unwrap();
+    assert!(json.ends_with(r#""extra":"dead0001"}"#));
+    assert_eq!(serde_json::from_str::<Partition>(&json).unwrap(), part);
+    let invalid = json.replace("dead0001", "dead001");
+    assert!(serde_json::from_str::<Partition>(&invalid).is_err());
+    let invalid = json.replace("dead0001", "+dead001");
+    assert!(serde_json::from_str::<Partition>(&invalid).is_err());
+
+    let flags = PartitionAttributes::PLATFORM | PartitionAttributes::BOOTABLE;
+    let json = serde_json::to_string(&flags).unwrap();
+    assert_eq!(json, r#""PLATFORM | BOOTABLE""#);
+    assert_eq!(
+        serde_json::from_str::<PartitionAttributes>(&json)
+            .unwrap()
+            .bits(),
+        flags.bits()
+    );
+}
+
+#[test]
+fn test_serde_header() {
+    let header = HeaderBuilder::new()
+        .backup_lba(139)
+        .build(disk::LogicalBlockSize::Lb512)
+        .unwrap();
+    let json = serde_json::to_value(&header).unwrap();
+    assert_eq!(json["disk_guid"], header.disk_guid.to_string());
+    assert_eq!(json["backup_lba"], 139);
+    assert_eq!(
+        serde_json::from_value::<gpt::header::Header>(json).unwrap(),
+        header
+    );
+}
+
+#[test]
+fn test_serde_config() {
+    let config = GptConfig::new()
+        .writable(true)
+        .logical_block_size(disk::LogicalBlockSize::Lb4096)
+        .journal(journal::JournalMode::File("/tmp/journal".into()))
+        .placement(placement::Placement::Within {
+            first_lba: 34,
+            last_lba: 99,
+        });
+    let json = serde_json::to_value(&config).unwrap();
+    assert_eq!(json["lb_size"], 4096);
+    assert_eq!(serde_json::from_value::<GptConfig>(json).unwrap(), config);
+
+    // missing options keep their defaults
+    let config: GptConfig =
+        serde_json::from_str(r#"{"writable": true, "lb_size": 4096, "placement": "LastFit"}"#)
+            .unwrap();
+    assert_eq!(
+        config,
+        GptConfig::new()
+            .writable(true)
+            .logical_block_size(disk::LogicalBlockSize::Lb4096)
+            .placement(placement::Placement::LastFit)
+    );
+    assert!(serde_json::from_str::<GptConfig>(r#"{"lb_size": 1024}"#).is_err());
+}
+
+#[test]
+fn test_serde_mbr() {
+    let mut mbr = mbr::ProtectiveMBR::with_lb_size(139);
+    let mut bootcode = [0; 440];
+    bootcode[0] = 0xeb;
+    bootcode[439] = 0x90;
+    mbr.set_bootcode(bootcode);
+    mbr.set_disk_signature([0x12, 0x34, 0x56, 0x78]);
+
+    let json = serde_json::to_value(&mbr).unwrap();
+    let hex = json["bootcode"].as_str().unwrap();
+    assert_eq!(hex.len(), 880);
+    assert!(hex.starts_with("eb00") && hex.ends_with("0090"));
+    assert_eq!(json["disk_signature"], "12345678");
+    assert_eq!(json["partitions"][0]["os_type"], 0xee);
+    assert_eq!(json["partitions"][0]["lb_size"], 139);
+
+    let parsed: mbr::ProtectiveMBR = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(parsed.to_bytes(), mbr.to_bytes());
+
+    let mut invalid = json;
+    invalid["bootcode"] = "eb90".into();
+    assert!(serde_json::from_value::<mbr::ProtectiveMBR>(invalid).is_err());
+}